@@ -0,0 +1,45 @@
+// Bare bones `--name value` / `--name=value` option lookup for the lab binaries, so each one
+// doesn't need its own copy of the loop.
+use std::str::FromStr;
+
+// value of option `name` (given without the leading dashes), the last one wins if it's repeated.
+// A trailing `--name` with nothing after it gives an empty string.
+pub fn option_value<'a>(args: &'a [String], name: &str) -> Option<&'a str> {
+    let flag = format!("--{}", name);
+    let mut value = None;
+    for (i, arg) in args.iter().enumerate() {
+        if *arg == flag {
+            value = Some(args.get(i + 1).map_or("", String::as_str));
+        } else if let Some(rest) = arg.strip_prefix(&flag).and_then(|r| r.strip_prefix('=')) {
+            value = Some(rest);
+        }
+    }
+    value
+}
+
+// option `name` parsed as a T, or `default` if it isn't there
+pub fn parse_option<T: FromStr>(args: &[String], name: &str, default: T) -> Result<T, String> {
+    match option_value(args, name) {
+        None => Ok(default),
+        Some(value) => value
+            .parse()
+            .map_err(|_| format!("invalid value '{}' for --{}", value, name)),
+    }
+}
+
+// Everything that isn't an option or an option's value, in order (the program name in args[0]
+// is skipped). Every option takes a value, so a bare `--name` always eats the argument after it.
+pub fn positional(args: &[String]) -> Vec<&str> {
+    let mut positional = Vec::new();
+    let mut rest = args.iter().skip(1);
+    while let Some(arg) = rest.next() {
+        if arg.starts_with("--") {
+            if !arg.contains('=') {
+                rest.next();
+            }
+        } else {
+            positional.push(arg.as_str());
+        }
+    }
+    positional
+}
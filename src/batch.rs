@@ -0,0 +1,336 @@
+// Batch mode: every image in a directory (or matching a glob) through the pipeline, written to an
+// output directory with the same layout. Files run in parallel on the rayon pool, and a file that
+// fails gets reported at the end instead of stopping the rest.
+use std::path::{Path, PathBuf};
+
+// what counts as an image when walking a directory, anything imgcodecs can read in practice
+pub const IMAGE_EXTENSIONS: [&str; 6] = ["jpg", "jpeg", "png", "tif", "tiff", "bmp"];
+
+// one input image and where its result goes
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BatchJob {
+    pub input: PathBuf,
+    pub output: PathBuf,
+}
+
+// what went wrong with which file
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct BatchSummary {
+    pub processed: usize,
+    pub failures: Vec<(PathBuf, String)>,
+}
+
+impl BatchSummary {
+    pub fn print(&self) {
+        println!(
+            "Processed {} images, {} failed",
+            self.processed + self.failures.len(),
+            self.failures.len()
+        );
+        for (path, message) in &self.failures {
+            println!("  {}: {}", path.display(), message);
+        }
+    }
+}
+
+pub fn is_image(path: &Path) -> bool {
+    path.extension()
+        .and_then(|extension| extension.to_str())
+        .is_some_and(|extension| {
+            IMAGE_EXTENSIONS.contains(&extension.to_ascii_lowercase().as_str())
+        })
+}
+
+// Every image under `input` (a directory, searched recursively, or a glob like
+// `frames/**/*.png`), paired with the same relative path under `output_dir`. Sorted, so runs
+// process files in the same order.
+pub fn find_jobs(input: &str, output_dir: &Path) -> Result<Vec<BatchJob>, String> {
+    let input_path = Path::new(input);
+    let (base, mut files) = if input_path.is_dir() {
+        let mut files = Vec::new();
+        walk(input_path, output_dir, &mut files)
+            .map_err(|err| format!("couldn't read '{}': {}", input, err))?;
+        (input_path.to_path_buf(), files)
+    } else {
+        let paths = glob::glob(input).map_err(|err| format!("bad glob '{}': {}", input, err))?;
+        let files = paths
+            .filter_map(Result::ok)
+            .filter(|path| path.is_file() && is_image(path))
+            .collect();
+        (glob_base(input), files)
+    };
+    if files.is_empty() {
+        return Err(format!("no images found in '{}'", input));
+    }
+    files.sort();
+
+    Ok(files
+        .into_iter()
+        .map(|file| {
+            let relative = file.strip_prefix(&base).unwrap_or(&file).to_path_buf();
+            BatchJob {
+                output: output_dir.join(relative),
+                input: file,
+            }
+        })
+        .collect())
+}
+
+// images under `dir`, skipping the output directory in case it's inside the input one
+fn walk(dir: &Path, output_dir: &Path, files: &mut Vec<PathBuf>) -> std::io::Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            if !same_path(&path, output_dir) {
+                walk(&path, output_dir, files)?;
+            }
+        } else if is_image(&path) {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+fn same_path(a: &Path, b: &Path) -> bool {
+    match (a.canonicalize(), b.canonicalize()) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => a == b,
+    }
+}
+
+// the directories of a glob before the first wildcard, which is what the output layout is
+// relative to
+fn glob_base(pattern: &str) -> PathBuf {
+    Path::new(pattern)
+        .components()
+        .take_while(|component| {
+            !component
+                .as_os_str()
+                .to_string_lossy()
+                .contains(['*', '?', '['])
+        })
+        .collect()
+}
+
+#[cfg(feature = "opencv-io")]
+mod run {
+    use super::{BatchJob, BatchSummary};
+    use crate::image::ImageView;
+    use crate::luma::Luma;
+    use crate::my_arm_neon;
+    use crate::pipeline::Pipeline;
+    use opencv::{
+        core::{Vector, CV_16U, CV_8U},
+        imgcodecs,
+        prelude::*,
+    };
+    use rayon::prelude::*;
+
+    // Every job through `pipeline`, in parallel. `luma` is the grayscale 16 bit images get
+    // before a chain built from the command line options, None for a --pipeline file, which
+    // does its own grayscale (like the worker).
+    pub fn run_batch(jobs: &[BatchJob], pipeline: &Pipeline, luma: Option<Luma>) -> BatchSummary {
+        let results: Vec<Result<(), String>> = jobs
+            .par_iter()
+            .map(|job| run_job(job, pipeline, luma))
+            .collect();
+
+        let mut summary = BatchSummary::default();
+        for (job, result) in jobs.iter().zip(results) {
+            match result {
+                Ok(()) => summary.processed += 1,
+                Err(message) => summary.failures.push((job.input.clone(), message)),
+            }
+        }
+        summary
+    }
+
+    fn run_job(job: &BatchJob, pipeline: &Pipeline, luma: Option<Luma>) -> Result<(), String> {
+        let input = job.input.to_string_lossy();
+        let output = job.output.to_string_lossy();
+
+        // the file as it is (grey, BGR or BGRA, 8 or 16 bit), the chain's grayscale stage takes
+        // any of those channel counts and the 16 bit ones get brought down to 8 here first
+        let image = imgcodecs::imread(&input, imgcodecs::IMREAD_UNCHANGED)
+            .map_err(|err| err.to_string())?;
+        if image.empty() {
+            return Err("couldn't decode the image".to_string());
+        }
+        if !matches!(image.channels(), 1 | 3 | 4) {
+            return Err(format!(
+                "{} channel images aren't supported, expected grey, BGR or BGRA",
+                image.channels()
+            ));
+        }
+        let edges = match image.depth() {
+            CV_8U => pipeline.run(&ImageView::from_mat(&image).map_err(|err| err.to_string())?),
+            CV_16U => {
+                let frame = ImageView::<u16>::from_mat(&image).map_err(|err| err.to_string())?;
+                let frame = match luma {
+                    Some(luma) => my_arm_neon::to442_grayscale16(&frame, luma),
+                    None => my_arm_neon::to442_narrow16(&frame),
+                };
+                pipeline.run(&frame.view())
+            }
+            _ => return Err("only 8 and 16 bit images are supported".to_string()),
+        };
+
+        if let Some(parent) = job.output.parent() {
+            std::fs::create_dir_all(parent)
+                .map_err(|err| format!("couldn't create '{}': {}", parent.display(), err))?;
+        }
+        let mat = edges.as_mat().map_err(|err| err.to_string())?;
+        match imgcodecs::imwrite(&output, &mat, &Vector::new()) {
+            Ok(true) => Ok(()),
+            Ok(false) => Err(format!("couldn't write '{}'", output)),
+            Err(err) => Err(err.to_string()),
+        }
+    }
+}
+
+#[cfg(feature = "opencv-io")]
+pub use run::run_batch;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a scratch directory of empty files (directories for names ending in /), removed again when
+    // it's dropped
+    struct Scratch(PathBuf);
+
+    impl Scratch {
+        fn new(name: &str, files: &[&str]) -> Scratch {
+            let root =
+                std::env::temp_dir().join(format!("cpe442-batch-{}-{}", name, std::process::id()));
+            let _ = std::fs::remove_dir_all(&root);
+            for file in files {
+                let path = root.join(file);
+                if file.ends_with('/') {
+                    std::fs::create_dir_all(path).unwrap();
+                } else {
+                    std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+                    std::fs::write(path, b"").unwrap();
+                }
+            }
+            Scratch(root)
+        }
+
+        fn path(&self, relative: &str) -> PathBuf {
+            self.0.join(relative)
+        }
+    }
+
+    impl Drop for Scratch {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    // (input, output) relative to the scratch directory
+    fn relative(scratch: &Scratch, jobs: &[BatchJob]) -> Vec<(String, String)> {
+        let strip = |path: &Path| {
+            path.strip_prefix(&scratch.0)
+                .unwrap()
+                .to_string_lossy()
+                .replace('\\', "/")
+        };
+        jobs.iter()
+            .map(|job| (strip(&job.input), strip(&job.output)))
+            .collect()
+    }
+
+    #[test]
+    fn image_extensions() {
+        for name in [
+            "a.png",
+            "b.JPG",
+            "c.jpeg",
+            "d.tif",
+            "e.TIFF",
+            "f.bmp",
+            "dir/g.png",
+        ] {
+            assert!(is_image(Path::new(name)), "{}", name);
+        }
+        for name in [
+            "notes.txt",
+            "png",
+            "archive.png.gz",
+            "video.mp4",
+            "noextension",
+        ] {
+            assert!(!is_image(Path::new(name)), "{}", name);
+        }
+    }
+
+    #[test]
+    fn output_mirrors_nested_directories() {
+        let scratch = Scratch::new(
+            "nested",
+            &[
+                "in/a.png",
+                "in/notes.txt",
+                "in/sub/b.jpg",
+                "in/sub/README",
+                "in/sub/deeper/c.TIFF",
+            ],
+        );
+        let input = scratch.path("in");
+        let jobs = find_jobs(input.to_str().unwrap(), &scratch.path("out")).unwrap();
+        assert_eq!(
+            relative(&scratch, &jobs),
+            [
+                ("in/a.png", "out/a.png"),
+                ("in/sub/b.jpg", "out/sub/b.jpg"),
+                ("in/sub/deeper/c.TIFF", "out/sub/deeper/c.TIFF"),
+            ]
+            .map(|(input, output)| (input.to_string(), output.to_string()))
+        );
+    }
+
+    #[test]
+    fn globs_are_relative_to_their_base() {
+        let scratch = Scratch::new(
+            "glob",
+            // a directory that looks like an image too
+            &["in/a.png", "in/b.jpg", "in/folder.png/", "in/sub/d.png"],
+        );
+        assert_eq!(glob_base("frames/run1/*.png"), PathBuf::from("frames/run1"));
+        assert_eq!(glob_base("frames/**/f?.png"), PathBuf::from("frames"));
+
+        let pattern = scratch.path("in/*.png");
+        let jobs = find_jobs(pattern.to_str().unwrap(), &scratch.path("out")).unwrap();
+        assert_eq!(
+            relative(&scratch, &jobs),
+            [("in/a.png".to_string(), "out/a.png".to_string())]
+        );
+
+        let pattern = scratch.path("in/**/*.png");
+        let jobs = find_jobs(pattern.to_str().unwrap(), &scratch.path("out")).unwrap();
+        assert_eq!(
+            relative(&scratch, &jobs),
+            [("in/a.png", "out/a.png"), ("in/sub/d.png", "out/sub/d.png")]
+                .map(|(input, output)| (input.to_string(), output.to_string()))
+        );
+
+        let pattern = scratch.path("in/*.gif");
+        assert!(find_jobs(pattern.to_str().unwrap(), &scratch.path("out")).is_err());
+    }
+
+    #[test]
+    fn output_inside_the_input_is_skipped() {
+        // results of an earlier run into in/edges
+        let scratch = Scratch::new("inside", &["in/a.png", "in/edges/a.png", "in/sub/b.png"]);
+        let input = scratch.path("in");
+        let jobs = find_jobs(input.to_str().unwrap(), &scratch.path("in/edges")).unwrap();
+        assert_eq!(
+            relative(&scratch, &jobs),
+            [
+                ("in/a.png", "in/edges/a.png"),
+                ("in/sub/b.png", "in/edges/sub/b.png")
+            ]
+            .map(|(input, output)| (input.to_string(), output.to_string()))
+        );
+    }
+}
@@ -0,0 +1,12 @@
+// every lab in one binary, see cli.rs (or run it with no arguments) for the subcommands
+use std::env;
+
+use lib::cli;
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    if let Err(message) = cli::run(&args) {
+        eprintln!("{}", message);
+        std::process::exit(1);
+    }
+}
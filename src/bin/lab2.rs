@@ -1,3 +1,5 @@
+// Lab 2 only opens a photo and shows it, no grayscale or edges and no frame loop, so there's no
+// cpe442 subcommand for it to wrap and it stays the opencv hello world it always was.
 use opencv::{
     core::MatTraitConst,
     highgui::{self, WINDOW_AUTOSIZE},
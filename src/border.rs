@@ -0,0 +1,73 @@
+// What neighbourhood kernels read when they reach past the edge of the image.
+//
+// Every mode defines every output pixel, so filtered frames keep the input's dimensions
+// instead of losing (or leaving garbage in) the outer ring.
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum BorderMode {
+    // pixels outside the image read as 0 (opencv BORDER_CONSTANT)
+    Zero,
+    // repeat the edge pixel: aaa|abcd|ddd (opencv BORDER_REPLICATE)
+    Replicate,
+    // mirror around the edge pixel without repeating it: cb|abcd|cb (opencv BORDER_REFLECT_101,
+    // which is also what cv::Sobel uses by default)
+    #[default]
+    Reflect,
+    // continue from the opposite edge: cd|abcd|ab (opencv BORDER_WRAP)
+    Wrap,
+}
+
+impl BorderMode {
+    pub const ALL: [BorderMode; 4] = [
+        BorderMode::Zero,
+        BorderMode::Replicate,
+        BorderMode::Reflect,
+        BorderMode::Wrap,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            BorderMode::Zero => "zero",
+            BorderMode::Replicate => "replicate",
+            BorderMode::Reflect => "reflect",
+            BorderMode::Wrap => "wrap",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<BorderMode> {
+        match name.to_ascii_lowercase().as_str() {
+            "zero" | "constant" => Some(BorderMode::Zero),
+            "replicate" | "clamp" => Some(BorderMode::Replicate),
+            "reflect" | "mirror" => Some(BorderMode::Reflect),
+            "wrap" => Some(BorderMode::Wrap),
+            _ => None,
+        }
+    }
+
+    // Map coordinate `i` (possibly outside 0..len) to the one to read instead.
+    // None means the pixel reads as zero.
+    #[inline]
+    pub fn resolve(self, i: isize, len: usize) -> Option<usize> {
+        let n = len as isize;
+        if (0..n).contains(&i) {
+            return Some(i as usize);
+        }
+        if len == 0 {
+            return None;
+        }
+
+        match self {
+            BorderMode::Zero => None,
+            BorderMode::Replicate => Some(i.clamp(0, n - 1) as usize),
+            BorderMode::Reflect => {
+                if n == 1 {
+                    return Some(0);
+                }
+                let period = 2 * (n - 1);
+                let m = i.rem_euclid(period);
+                Some(if m < n { m } else { period - m } as usize)
+            }
+            BorderMode::Wrap => Some(i.rem_euclid(n) as usize),
+        }
+    }
+}
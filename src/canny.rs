@@ -0,0 +1,417 @@
+// Canny edge detector built on the grayscale and gradient stages: gaussian blur, gradient,
+// non-maximum suppression along the gradient direction, then double threshold hysteresis.
+//
+// Everything up to the thresholds runs strip parallel like do_frame, just with a bigger halo.
+// Hysteresis follows edges across the whole frame so it runs once over the stitched result.
+// The two halves are the CannyEdges and Hysteresis pipeline stages.
+use crate::args;
+use crate::border::BorderMode;
+use crate::edge::EdgeOperator;
+use crate::gradient::{self, MagnitudeNorm};
+use crate::image::{Image, ImageView};
+use crate::my_arm_neon::{FrameOptions, Mode};
+use crate::pipeline::{FrameFilter, Pipeline};
+use crate::prefilter::{self, PreFilter};
+
+// canny's own blur, the same gaussian --prefilter uses (radius 3 at sigma 1)
+const BLUR: PreFilter = PreFilter::Gaussian { sigma: 1.0 };
+
+// pixel classes after thresholding, before hysteresis
+const WEAK: u8 = 1;
+const STRONG: u8 = 2;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CannyOptions {
+    // magnitudes below `low` are never edges, at or above `high` always are, and the ones in
+    // between only if they connect to a strong edge
+    pub low: u16,
+    pub high: u16,
+    pub norm: MagnitudeNorm,
+}
+
+impl Default for CannyOptions {
+    fn default() -> Self {
+        CannyOptions {
+            low: 50,
+            high: 150,
+            // same default as cv::Canny
+            norm: MagnitudeNorm::L1,
+        }
+    }
+}
+
+impl CannyOptions {
+    // `--low N --high N --norm l1|l2`, defaults for anything not given
+    pub fn from_args(args: &[String]) -> Result<CannyOptions, String> {
+        let default = CannyOptions::default();
+        let norm = match args::option_value(args, "norm") {
+            None => default.norm,
+            Some(name) => MagnitudeNorm::from_name(name)
+                .ok_or_else(|| format!("unknown norm '{}', expected l1 or l2", name))?,
+        };
+        let options = CannyOptions {
+            low: args::parse_option(args, "low", default.low)?,
+            high: args::parse_option(args, "high", default.high)?,
+            norm,
+        };
+        if options.low > options.high {
+            return Err(format!(
+                "--low ({}) must not be above --high ({})",
+                options.low, options.high
+            ));
+        }
+        Ok(options)
+    }
+}
+
+// canny of a BGR frame, 255 on edges and 0 everywhere else. The pre-filter in `options` runs
+// before canny's own blur.
+pub fn canny(frame: &ImageView<u8>, options: &FrameOptions, canny: &CannyOptions) -> Image<u8> {
+    let options = FrameOptions {
+        mode: Mode::Canny(*canny),
+        ..*options
+    };
+    Pipeline::from_options(&options).run(frame)
+}
+
+// same thing for an image that's already grey
+pub fn canny_grey(grey: &ImageView<u8>, options: &FrameOptions, canny: &CannyOptions) -> Image<u8> {
+    assert_eq!(
+        grey.channels(),
+        1,
+        "canny_grey expects a single channel image"
+    );
+    let mut pipeline = Pipeline::new()
+        .strips(options.strips)
+        .border(options.border);
+    if options.prefilter != PreFilter::None {
+        pipeline = pipeline.then(options.prefilter);
+    }
+    pipeline
+        .then(CannyEdges {
+            operator: options.operator,
+            options: *canny,
+        })
+        .then(Hysteresis)
+        .run(grey)
+}
+
+// The strip part of canny as a pipeline stage: grey in, pixel classes out (WEAK / STRONG for
+// edge candidates, 0 for the rest). Needs Hysteresis after it to get actual edges.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CannyEdges {
+    pub operator: EdgeOperator,
+    pub options: CannyOptions,
+}
+
+impl FrameFilter for CannyEdges {
+    fn name(&self) -> &'static str {
+        "canny"
+    }
+
+    // rows of context each strip needs: the blur's, 1 for the gradient and 1 for the neighbours
+    // non-max suppression compares against
+    fn halo(&self) -> usize {
+        BLUR.halo() + 2
+    }
+
+    fn apply(&self, input: &ImageView<u8>, border: BorderMode) -> Image<u8> {
+        classify(input, border, self.operator, &self.options)
+    }
+}
+
+// and the whole frame part: classes in, 255 on edges out
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Hysteresis;
+
+impl FrameFilter for Hysteresis {
+    fn name(&self) -> &'static str {
+        "hysteresis"
+    }
+
+    fn halo(&self) -> usize {
+        0
+    }
+
+    // edges can run across any number of strips
+    fn whole_frame(&self) -> bool {
+        true
+    }
+
+    fn apply(&self, input: &ImageView<u8>, _border: BorderMode) -> Image<u8> {
+        hysteresis(input)
+    }
+}
+
+// blur, gradient, non-max suppression and thresholds for one strip
+fn classify(
+    grey: &ImageView<u8>,
+    border: BorderMode,
+    operator: EdgeOperator,
+    canny: &CannyOptions,
+) -> Image<u8> {
+    let (rows, cols) = (grey.height(), grey.width());
+    let blurred = prefilter::apply(grey, border, BLUR);
+    let planes = gradient::gradient_planes(&blurred.view(), border, operator);
+
+    let mut magnitude = Image::new(cols, rows, 1);
+    let mut direction = Image::new(cols, rows, 1);
+    for y in 0..rows {
+        let gradient_row = planes.row(y);
+        for (x, g) in gradient_row.chunks_exact(2).enumerate() {
+            magnitude.row_mut(y)[x] = gradient::magnitude(g[0], g[1], canny.norm);
+            direction.row_mut(y)[x] = gradient::orientation_bin(g[0], g[1], 4);
+        }
+    }
+
+    // neighbour magnitude, reading past the edge the same way the other stages do
+    let magnitude_at = |y: isize, x: isize| -> u16 {
+        match (border.resolve(y, rows), border.resolve(x, cols)) {
+            (Some(y), Some(x)) => magnitude.row(y)[x],
+            _ => 0,
+        }
+    };
+
+    let mut classes = Image::new(cols, rows, 1);
+    for y in 0..rows {
+        for x in 0..cols {
+            let m = magnitude.row(y)[x];
+            if m < canny.low || m == 0 {
+                continue;
+            }
+
+            // step towards the neighbour along the gradient (gy is positive upwards, so a 45
+            // degree gradient points right and up)
+            let (dx, dy) = match direction.row(y)[x] {
+                0 => (1, 0),
+                1 => (1, -1),
+                2 => (0, -1),
+                _ => (-1, -1),
+            };
+            let (y, x) = (y as isize, x as isize);
+            let ahead = magnitude_at(y + dy, x + dx);
+            let behind = magnitude_at(y - dy, x - dx);
+            // strict on one side so a plateau two pixels wide still keeps one of them
+            if m > ahead && m >= behind {
+                classes.row_mut(y as usize)[x as usize] =
+                    if m >= canny.high { STRONG } else { WEAK };
+            }
+        }
+    }
+
+    classes
+}
+
+// keep the strong pixels and every weak pixel 8-connected to one, as 255
+fn hysteresis(classes: &ImageView<u8>) -> Image<u8> {
+    let (rows, cols) = (classes.height(), classes.width());
+    let mut output = Image::new(cols, rows, 1);
+
+    let mut stack = Vec::new();
+    for y in 0..rows {
+        for x in 0..cols {
+            if classes.row(y)[x] == STRONG {
+                output.row_mut(y)[x] = 255;
+                stack.push((y, x));
+            }
+        }
+    }
+
+    while let Some((y, x)) = stack.pop() {
+        for ny in y.saturating_sub(1)..(y + 2).min(rows) {
+            for nx in x.saturating_sub(1)..(x + 2).min(cols) {
+                if classes.row(ny)[nx] == WEAK && output.row(ny)[nx] == 0 {
+                    output.row_mut(ny)[nx] = 255;
+                    stack.push((ny, nx));
+                }
+            }
+        }
+    }
+
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // blobs with soft edges plus some noise, so there are strong, weak and rejected pixels
+    fn frame(width: usize, height: usize) -> Image<u8> {
+        let grey = (0..width * height)
+            .map(|i| {
+                let (x, y) = ((i % width) as i32, (i / width) as i32);
+                let blob = if (x - 20).pow(2) + (y - 15).pow(2) < 120 {
+                    180
+                } else {
+                    40
+                };
+                let ramp = if x > 40 { (x - 40) * 6 } else { 0 };
+                (blob + ramp.min(60) + (i * 7919 % 23) as i32) as u8
+            })
+            .collect();
+        Image::from_vec(width, height, 1, grey)
+    }
+
+    #[test]
+    fn strips_match_the_whole_frame() {
+        let grey = frame(61, 47);
+        for border in [BorderMode::Replicate, BorderMode::Zero] {
+            let options = FrameOptions {
+                mode: Mode::Canny(CannyOptions::default()),
+                border,
+                strips: 1,
+                ..Default::default()
+            };
+            let whole = canny_grey(&grey.view(), &options, &CannyOptions::default());
+            assert!(whole.data().contains(&255) && whole.data().contains(&0));
+            for strips in [2, 5, 16] {
+                let options = FrameOptions { strips, ..options };
+                assert_eq!(
+                    canny_grey(&grey.view(), &options, &CannyOptions::default()),
+                    whole,
+                    "{} strips",
+                    strips
+                );
+            }
+        }
+    }
+
+    const SIZE: usize = 21;
+    const STEP: CannyOptions = CannyOptions {
+        low: 20,
+        high: 100,
+        norm: MagnitudeNorm::L1,
+    };
+
+    // 0 on one side of a straight edge and 200 on the other
+    fn step(bright: impl Fn(usize, usize) -> bool) -> Image<u8> {
+        let grey = (0..SIZE * SIZE)
+            .map(|i| if bright(i % SIZE, i / SIZE) { 200 } else { 0 })
+            .collect();
+        Image::from_vec(SIZE, SIZE, 1, grey)
+    }
+
+    fn classes(grey: &Image<u8>, options: &CannyOptions) -> Image<u8> {
+        classify(
+            &grey.view(),
+            BorderMode::Replicate,
+            EdgeOperator::Sobel,
+            options,
+        )
+    }
+
+    // the direction bin non-max suppression used at (x, y)
+    fn bin_at(grey: &Image<u8>, x: usize, y: usize) -> u8 {
+        let blurred = prefilter::apply(&grey.view(), BorderMode::Replicate, BLUR);
+        let planes =
+            gradient::gradient_planes(&blurred.view(), BorderMode::Replicate, EdgeOperator::Sobel);
+        let g = &planes.row(y)[x * 2..x * 2 + 2];
+        gradient::orientation_bin(g[0], g[1], 4)
+    }
+
+    // Edge pixels on each line across the edge, from `starts` stepping by (dx, dy) until it leaves
+    // the frame: every one of them should have exactly one
+    fn edges_across(classes: &Image<u8>, starts: &[(usize, usize)], (dx, dy): (isize, isize)) {
+        for &start in starts {
+            let (mut x, mut y, mut count) = (start.0 as isize, start.1 as isize, 0);
+            while (0..SIZE as isize).contains(&x) && (0..SIZE as isize).contains(&y) {
+                count += (classes.row(y as usize)[x as usize] != 0) as usize;
+                x += dx;
+                y += dy;
+            }
+            assert_eq!(count, 1, "line from {:?}", start);
+        }
+    }
+
+    #[test]
+    fn step_edges_thin_to_one_pixel_in_every_direction() {
+        let middle = SIZE / 2;
+        let inner = 4..SIZE - 4;
+
+        // vertical step, horizontal gradient: one pixel per row
+        let grey = step(|x, _| x > middle);
+        assert_eq!(bin_at(&grey, middle, middle), 0);
+        let starts: Vec<_> = inner.clone().map(|y| (0, y)).collect();
+        edges_across(&classes(&grey, &STEP), &starts, (1, 0));
+
+        // horizontal step, vertical gradient: one per column
+        let grey = step(|_, y| y > middle);
+        assert_eq!(bin_at(&grey, middle, middle), 2);
+        let starts: Vec<_> = inner.clone().map(|x| (x, 0)).collect();
+        edges_across(&classes(&grey, &STEP), &starts, (0, 1));
+
+        // bright above the x = y diagonal, the gradient points right and up: one per
+        // anti-diagonal (left edge to top edge)
+        let grey = step(|x, y| x > y);
+        assert_eq!(bin_at(&grey, middle, middle), 1);
+        let starts: Vec<_> = (inner.start..SIZE).map(|y| (0, y)).collect();
+        edges_across(&classes(&grey, &STEP), &starts, (1, -1));
+
+        // bright past the other diagonal, the gradient points right and down: one per diagonal
+        // (left edge to bottom edge)
+        let grey = step(|x, y| x + y > SIZE);
+        assert_eq!(bin_at(&grey, middle, middle), 3);
+        let starts: Vec<_> = (0..inner.end).map(|y| (0, y)).collect();
+        edges_across(&classes(&grey, &STEP), &starts, (1, 1));
+    }
+
+    #[test]
+    fn hysteresis_follows_weak_pixels_from_strong_ones() {
+        // a strong pixel with a weak chain running off it diagonally and sideways, and a weak
+        // pair on its own
+        let mut classes = Image::new(8, 6, 1);
+        let mut set = |x: usize, y: usize, class: u8| classes.row_mut(y)[x] = class;
+        set(1, 1, STRONG);
+        for (x, y) in [(2, 2), (3, 3), (4, 3), (5, 4)] {
+            set(x, y, WEAK);
+        }
+        for (x, y) in [(7, 0), (7, 1)] {
+            set(x, y, WEAK);
+        }
+
+        let edges = hysteresis(&classes.view());
+        let kept: Vec<_> = (0..6)
+            .flat_map(|y| (0..8).map(move |x| (x, y)))
+            .filter(|&(x, y)| edges.row(y)[x] == 255)
+            .collect();
+        assert_eq!(kept, [(1, 1), (2, 2), (3, 3), (4, 3), (5, 4)]);
+        assert!(edges.data().iter().all(|&v| v == 0 || v == 255));
+    }
+
+    #[test]
+    fn hysteresis_drops_weak_pixels_on_their_own() {
+        let mut classes = Image::new(5, 5, 1);
+        classes.row_mut(2)[2] = WEAK;
+        classes.row_mut(0)[4] = WEAK;
+        assert!(hysteresis(&classes.view()).data().iter().all(|&v| v == 0));
+    }
+
+    #[test]
+    fn nothing_below_the_low_threshold_survives() {
+        let grey = step(|x, _| x > SIZE / 2);
+        let everything = CannyOptions {
+            low: 1,
+            high: 1,
+            ..STEP
+        };
+        let strips = FrameOptions {
+            strips: 1,
+            ..Default::default()
+        };
+        assert!(canny_grey(&grey.view(), &strips, &everything)
+            .data()
+            .contains(&255));
+
+        // the strongest gradient this step has is well under 8160, the L1 maximum
+        let nothing = CannyOptions {
+            low: 8000,
+            high: 8100,
+            ..STEP
+        };
+        assert!(classes(&grey, &nothing).data().iter().all(|&v| v == 0));
+        assert!(canny_grey(&grey.view(), &strips, &nothing)
+            .data()
+            .iter()
+            .all(|&v| v == 0));
+    }
+}
@@ -0,0 +1,546 @@
+// The cpe442 command line: one binary with subcommands instead of a main per lab.
+//
+//     cpe442 view    VIDEO   show the edges next to the original
+//     cpe442 process VIDEO   no windows, --output writes the edge video (headless over SSH)
+//     cpe442 bench   VIDEO   no windows, prints the timing at the end
+//     cpe442 batch   DIR     every image in DIR (or a quoted glob) into the --output directory
+//     cpe442 host    VIDEO   lab 6 host (same as --backend distributed)
+//     cpe442 worker          lab 6 compute node
+//
+// `-` (or a .y4m file) as the input reads a y4m stream instead, and `--output -` writes one, so
+// process and bench can sit between two ffmpegs, with or without opencv.
+//
+// Every option takes a value (`--display off`, not `--no-display`), which is what lets the input
+// be given without `--input`. The old lab binaries call run_lab with their subcommand and backend.
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+
+use crate::args;
+use crate::engine::Engine;
+use crate::mat_packet;
+use crate::my_arm_neon::FrameOptions;
+use crate::pipeline::{Pipeline, RunStats};
+use crate::pipeline_config::PipelineConfig;
+use crate::playback::{FrameRange, Pacing};
+use crate::timing;
+use crate::worker;
+use crate::y4m;
+
+pub const USAGE: &str = "\
+Usage: cpe442 <view|process|bench|batch|host|worker> [VIDEO] [options]
+
+  --input PATH              video to read (or give it after the subcommand), for batch a
+                            directory of images or a quoted glob like 'frames/*.png', - or a
+                            .y4m file for a y4m stream (process and bench)
+  --output PATH             write the edge video (view, process and host), for batch the
+                            directory the results go in, - or a .y4m file for y4m
+  --codec FOURCC            codec for --output, mp4v by default (MJPG, XVID, avc1, ...)
+  --fps N                   frame rate for --output, the source's by default
+  --backend NAME            scalar, threaded, neon (default) or distributed
+  --strips N                strips per frame, one per core by default
+  --display on|off          highgui windows, on for view and host
+  --start N --end N         play frames N up to (not including) N of the video
+  --every N                 only every Nth frame
+  --loop N                  play it N times, 0 to loop until killed
+  --pace off|drop|slow      play at the source's frame rate, dropping late frames or slowing
+                            down for them (slow for view and host, off otherwise)
+  --host ADDR               where the workers find the host
+  --report FILE             per stage timings (count, mean, p50/p95/p99, max) at the end,
+                            CSV if FILE ends in .csv, JSON otherwise
+  --trace FILE              every stage of every frame on a timeline, Chrome trace JSON for
+                            ui.perfetto.dev or chrome://tracing
+  --pipeline FILE           TOML/JSON stage list instead of the options below
+  --operator NAME --mode sobel|canny --low N --high N --norm l1|l2
+  --luma PROFILE --rounding truncate|round
+  --prefilter none|gaussian:SIGMA|box:RADIUS|median3|median5";
+
+pub const DEFAULT_CODEC: &str = "mp4v";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Command {
+    View,
+    Process,
+    Bench,
+    Batch,
+    Host,
+    Worker,
+}
+
+impl Command {
+    pub fn name(self) -> &'static str {
+        match self {
+            Command::View => "view",
+            Command::Process => "process",
+            Command::Bench => "bench",
+            Command::Batch => "batch",
+            Command::Host => "host",
+            Command::Worker => "worker",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Command> {
+        match name.to_ascii_lowercase().as_str() {
+            "view" => Some(Command::View),
+            "process" => Some(Command::Process),
+            "bench" => Some(Command::Bench),
+            "batch" => Some(Command::Batch),
+            "host" => Some(Command::Host),
+            "worker" => Some(Command::Worker),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct CliOptions {
+    pub command: Command,
+    pub engine: Engine,
+    pub input: Option<String>,
+    pub output: Option<String>,
+    // fourcc for the output video, always four characters
+    pub codec: String,
+    // output frame rate, None to copy the source's
+    pub fps: Option<f64>,
+    pub display: bool,
+    pub host: String,
+    // --strips if it was given, it overrides the one in a --pipeline file
+    pub strips: Option<usize>,
+    pub frame: FrameOptions,
+    pub pipeline: Option<PipelineConfig>,
+    pub range: FrameRange,
+    pub pacing: Pacing,
+    // write the per stage timings here at the end, .csv or .json
+    pub report: Option<String>,
+    // and/or every span as a Chrome trace
+    pub trace: Option<String>,
+}
+
+impl CliOptions {
+    // `args[1]` is the subcommand, the next positional argument is the input
+    pub fn from_args(args: &[String]) -> Result<CliOptions, String> {
+        let positional = args::positional(args);
+        let command = match positional.first() {
+            None => return Err(USAGE.to_string()),
+            Some(name) => Command::from_name(name).ok_or_else(|| {
+                format!(
+                    "unknown command '{}', expected view, process, bench, batch, host or worker",
+                    name
+                )
+            })?,
+        };
+        if positional.len() > 2 {
+            // most likely the shell expanded a glob meant for batch
+            if command == Command::Batch {
+                return Err(format!(
+                    "unexpected argument '{}', quote the glob so batch gets to expand it",
+                    positional[2]
+                ));
+            }
+            return Err(format!("unexpected argument '{}'", positional[2]));
+        }
+
+        let engine = match (command, Engine::from_args(args)?) {
+            (Command::Host, Engine::Distributed) => Engine::Distributed,
+            // without --backend it's just the default, which the host ignores
+            (Command::Host, engine) if args::option_value(args, "backend").is_some() => {
+                return Err(format!(
+                    "the host sends frames to the workers, it can't use the {} backend",
+                    engine.name()
+                ))
+            }
+            (Command::Host, _) => Engine::Distributed,
+            (Command::Worker, Engine::Distributed) => {
+                return Err("a worker can't use the distributed backend".to_string())
+            }
+            (Command::Batch, Engine::Distributed) => {
+                return Err("batch runs locally, pick another backend".to_string())
+            }
+            (_, engine) => engine,
+        };
+
+        let input = args::option_value(args, "input")
+            .or(positional.get(1).copied())
+            .map(str::to_string);
+        if input.is_none() && command != Command::Worker {
+            return Err(format!(
+                "{} needs {} to read\n\n{}",
+                command.name(),
+                match command {
+                    Command::Batch => "a directory or glob of images",
+                    _ => "a video",
+                },
+                USAGE
+            ));
+        }
+
+        let output = args::option_value(args, "output").map(str::to_string);
+        if output.is_some() && matches!(command, Command::Bench | Command::Worker) {
+            return Err(format!(
+                "{} doesn't write output, use process",
+                command.name()
+            ));
+        }
+        let codec = args::option_value(args, "codec").unwrap_or(DEFAULT_CODEC);
+        if codec.len() != 4 || !codec.is_ascii() {
+            return Err(format!(
+                "invalid codec '{}', expected a four character code like mp4v, MJPG or XVID",
+                codec
+            ));
+        }
+        let fps = match args::option_value(args, "fps") {
+            None => None,
+            Some(value) => match value.parse::<f64>() {
+                Ok(fps) if fps > 0.0 && fps.is_finite() => Some(fps),
+                _ => return Err(format!("invalid value '{}' for --fps", value)),
+            },
+        };
+        if output.is_none() && (codec != DEFAULT_CODEC || fps.is_some()) {
+            return Err("--codec and --fps are for --output".to_string());
+        }
+        if command == Command::Batch {
+            if output.is_none() {
+                return Err("batch needs an --output directory".to_string());
+            }
+            // images keep their own format, there's no video to encode
+            if codec != DEFAULT_CODEC || fps.is_some() {
+                return Err("--codec and --fps are for video output, not batch".to_string());
+            }
+            if args::option_value(args, "display").is_some() {
+                return Err("batch doesn't display anything".to_string());
+            }
+        }
+
+        let display = match args::option_value(args, "display") {
+            None => matches!(command, Command::View | Command::Host),
+            Some(value) => match value.to_ascii_lowercase().as_str() {
+                "on" | "true" | "yes" => true,
+                "off" | "false" | "no" => false,
+                _ => {
+                    return Err(format!(
+                        "invalid value '{}' for --display, expected on or off",
+                        value
+                    ))
+                }
+            },
+        };
+
+        let y4m_input = input.as_deref().is_some_and(y4m::is_y4m_path);
+        let y4m_output = output.as_deref().is_some_and(y4m::is_y4m_path);
+        if y4m_input || y4m_output {
+            if !matches!(command, Command::Process | Command::Bench) {
+                return Err("y4m streams only work with process and bench".to_string());
+            }
+            if engine == Engine::Distributed {
+                return Err("y4m streams run locally, pick another backend".to_string());
+            }
+            if !y4m_input {
+                return Err(
+                    "y4m output needs y4m input, pipe the video in with ffmpeg -f yuv4mpegpipe"
+                        .to_string(),
+                );
+            }
+            if output.is_some() && !y4m_output {
+                return Err("y4m input only goes back out as y4m, - or a .y4m file".to_string());
+            }
+            if codec != DEFAULT_CODEC || fps.is_some() {
+                return Err(
+                    "--codec and --fps don't apply to y4m, it keeps the input's".to_string()
+                );
+            }
+            if display {
+                return Err("y4m streams run headless, use --display off".to_string());
+            }
+        }
+
+        // Real time only matters when somebody is watching, the rest default to flat out. Both
+        // need an actual video file to seek around in and a frame rate to keep.
+        let range = FrameRange::from_args(args)?;
+        let pacing = Pacing::from_args(
+            args,
+            match display {
+                true => Pacing::Slow,
+                false => Pacing::Off,
+            },
+        )?;
+        if (!range.is_everything() || pacing != Pacing::Off)
+            && (y4m_input || matches!(command, Command::Batch | Command::Worker))
+        {
+            return Err(format!(
+                "--start, --end, --every, --loop and --pace only work on video files, not {}",
+                match command {
+                    Command::Batch => "batch",
+                    Command::Worker => "a worker",
+                    _ => "y4m streams",
+                }
+            ));
+        }
+
+        let report = args::option_value(args, "report").map(str::to_string);
+        let trace = args::option_value(args, "trace").map(str::to_string);
+        if (report.is_some() || trace.is_some())
+            && matches!(command, Command::Batch | Command::Worker)
+        {
+            return Err(format!(
+                "--report and --trace are for videos, {} doesn't time its frames",
+                command.name()
+            ));
+        }
+
+        let strips = match args::option_value(args, "strips") {
+            None => None,
+            Some(_) => match args::parse_option(args, "strips", 0)? {
+                0 => return Err("--strips must be at least 1".to_string()),
+                strips => Some(strips),
+            },
+        };
+        let mut frame = FrameOptions::from_args(args)?;
+        if let Some(strips) = strips {
+            frame.strips = strips;
+        }
+
+        Ok(CliOptions {
+            command,
+            engine,
+            input,
+            output,
+            codec: codec.to_string(),
+            fps,
+            display,
+            host: args::option_value(args, "host")
+                .unwrap_or(mat_packet::HOST_IP)
+                .to_string(),
+            strips,
+            frame,
+            pipeline: PipelineConfig::from_args(args)?,
+            range,
+            pacing,
+            report,
+            trace,
+        })
+    }
+
+    // the --report for a finished run, timings from whatever got recorded
+    pub fn report(&self, stats: &RunStats) -> Result<timing::Report, String> {
+        // the host's own pipeline never runs, the workers have theirs
+        let (strips, stages) = match self.engine {
+            Engine::Distributed => (0, Vec::new()),
+            _ => {
+                let pipeline = self.pipeline()?;
+                let stages = pipeline.stages().map(|stage| stage.name().to_string());
+                (pipeline.strip_count(), stages.collect())
+            }
+        };
+        let config = timing::RunConfig {
+            command: self.command.name().to_string(),
+            backend: self.engine.name().to_string(),
+            input: self.input.clone().unwrap_or_default(),
+            strips,
+            pipeline: stages,
+            pacing: self.pacing.name().to_string(),
+        };
+        Ok(timing::Report::new(
+            config,
+            stats.frames,
+            stats.dropped,
+            stats.elapsed,
+        ))
+    }
+
+    // reading a y4m stream rather than going through opencv
+    pub fn is_y4m(&self) -> bool {
+        self.input.as_deref().is_some_and(y4m::is_y4m_path)
+    }
+
+    // the chain to run locally, from --pipeline if there is one
+    pub fn pipeline(&self) -> Result<Pipeline, String> {
+        let mut pipeline = match &self.pipeline {
+            None => return Ok(self.engine.pipeline(&self.frame)),
+            Some(config) => config.build()?,
+        };
+        if let Some(strips) = self.strips {
+            pipeline = pipeline.strips(strips);
+        }
+        if self.engine == Engine::Scalar {
+            pipeline = pipeline.strips(1);
+        }
+        Ok(pipeline)
+    }
+
+    #[cfg(feature = "opencv-io")]
+    pub fn video_settings(&self) -> crate::video::VideoSettings {
+        crate::video::VideoSettings {
+            display: self.display,
+            output: self.output.clone(),
+            codec: self.codec.clone(),
+            fps: self.fps,
+            range: self.range,
+            pacing: self.pacing,
+        }
+    }
+}
+
+// parse `args` and run the subcommand
+pub fn run(args: &[String]) -> Result<(), String> {
+    let options = CliOptions::from_args(args)?;
+    if options.report.is_some() || options.trace.is_some() {
+        timing::enable(options.trace.is_some());
+    }
+
+    let stats = match (options.command, options.engine) {
+        (Command::Worker, _) => {
+            worker::run_worker(&options.host, options.pipeline()?, options.frame.luma);
+            return Ok(());
+        }
+        _ if options.is_y4m() => run_y4m(&options)?,
+        #[cfg(feature = "opencv-io")]
+        (Command::Batch, _) => return run_batch(&options),
+        #[cfg(feature = "opencv-io")]
+        (_, Engine::Distributed) => run_host(&options)?,
+        #[cfg(feature = "opencv-io")]
+        _ => run_local(&options)?,
+        #[cfg(not(feature = "opencv-io"))]
+        (command, _) => {
+            return Err(format!(
+                "{} needs opencv, rebuild with the opencv-io feature",
+                command.name()
+            ))
+        }
+    };
+
+    // the host has no pipeline time of its own to show
+    if options.command == Command::Bench && options.engine != Engine::Distributed {
+        println!(
+            "{} backend: {} frames, {:?} per frame in the pipeline, {:.1} fps overall",
+            options.engine.name(),
+            stats.frames,
+            stats.average(),
+            stats.fps()
+        );
+    }
+    if let Some(path) = &options.report {
+        options.report(&stats)?.write(path)?;
+        // stderr, stdout might be a y4m stream
+        eprintln!("Wrote the timing report to {}", path);
+    }
+    if let Some(path) = &options.trace {
+        timing::write_trace(path, &format!("cpe442 {}", options.command.name()))?;
+        eprintln!("Wrote the trace to {}, open it in ui.perfetto.dev", path);
+    }
+    Ok(())
+}
+
+// What the old lab binaries run: `program VIDEO [options]` as `cpe442 COMMAND --backend ENGINE
+// VIDEO [options]`
+pub fn run_lab(args: &[String], command: Command, engine: Engine) -> Result<(), String> {
+    let mut lab_args = vec![
+        args.first().cloned().unwrap_or_default(),
+        command.name().to_string(),
+        "--backend".to_string(),
+        engine.name().to_string(),
+    ];
+    lab_args.extend(args.iter().skip(1).cloned());
+    run(&lab_args)
+}
+
+#[cfg(feature = "opencv-io")]
+fn run_host(options: &CliOptions) -> Result<RunStats, String> {
+    let input = options.input.as_deref().unwrap_or_default();
+    // Without a --pipeline file the workers still get the command line's chain, so --operator,
+    // --luma and the rest apply to them. Checked here so a bad file fails on the host rather than
+    // on every worker.
+    let mut config = match &options.pipeline {
+        Some(config) => config.clone(),
+        None => PipelineConfig::from_options(&options.frame, options.strips),
+    };
+    if options.strips.is_some() {
+        config.strips = options.strips;
+    }
+    config.build()?;
+    let pipeline = Some(config.to_json());
+    let runtime = tokio::runtime::Runtime::new().map_err(|err| err.to_string())?;
+    runtime
+        .block_on(crate::host::run_host(
+            input,
+            pipeline,
+            &options.video_settings(),
+        ))
+        .map_err(|err| err.to_string())
+}
+
+#[cfg(feature = "opencv-io")]
+fn run_batch(options: &CliOptions) -> Result<(), String> {
+    let input = options.input.as_deref().unwrap_or_default();
+    let output = std::path::Path::new(options.output.as_deref().unwrap_or_default());
+    let jobs = crate::batch::find_jobs(input, output)?;
+    let pipeline = options.pipeline()?;
+    // a --pipeline file has its own grayscale stage, the options' chain gets 16 bit images as grey
+    let luma = options.pipeline.is_none().then_some(options.frame.luma);
+
+    let summary = crate::batch::run_batch(&jobs, &pipeline, luma);
+    summary.print();
+    match summary.failures.len() {
+        0 => Ok(()),
+        failed => Err(format!("{} of {} images failed", failed, jobs.len())),
+    }
+}
+
+#[cfg(feature = "opencv-io")]
+fn run_local(options: &CliOptions) -> Result<RunStats, String> {
+    let input = options.input.as_deref().unwrap_or_default();
+    let pipeline = options.pipeline()?;
+    crate::video::run(input, &pipeline, &options.video_settings()).map_err(|err| err.to_string())
+}
+
+// stdin/stdout for `-`, otherwise .y4m files
+fn run_y4m(options: &CliOptions) -> Result<RunStats, String> {
+    let pipeline = options.pipeline()?;
+    let input: Box<dyn BufRead> = match options.input.as_deref().unwrap_or_default() {
+        "-" => Box::new(io::stdin().lock()),
+        path => Box::new(BufReader::new(
+            File::open(path).map_err(|err| format!("couldn't open '{}': {}", path, err))?,
+        )),
+    };
+    let output: Option<Box<dyn Write>> = match options.output.as_deref() {
+        None => None,
+        Some("-") => Some(Box::new(BufWriter::new(io::stdout().lock()))),
+        Some(path) => Some(Box::new(BufWriter::new(
+            File::create(path).map_err(|err| format!("couldn't create '{}': {}", path, err))?,
+        ))),
+    };
+
+    y4m::run(input, output, &pipeline).map_err(|err| err.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(command_line: &str) -> Result<CliOptions, String> {
+        let args: Vec<String> = std::iter::once("cpe442")
+            .chain(command_line.split_whitespace())
+            .map(str::to_string)
+            .collect();
+        CliOptions::from_args(&args)
+    }
+
+    #[test]
+    fn host_is_always_distributed() {
+        assert_eq!(parse("host in.mp4").unwrap().engine, Engine::Distributed);
+        assert_eq!(
+            parse("host in.mp4 --backend distributed").unwrap().engine,
+            Engine::Distributed
+        );
+    }
+
+    #[test]
+    fn conflicting_backends_are_rejected() {
+        for command_line in [
+            "host in.mp4 --backend neon",
+            "host in.mp4 --backend scalar",
+            "worker --backend distributed",
+            "batch frames --output out --backend distributed",
+        ] {
+            assert!(parse(command_line).is_err(), "{}", command_line);
+        }
+    }
+}
@@ -0,0 +1,80 @@
+// Runtime selection of the SIMD kernel set.
+//
+// The kernels in my_arm_neon / my_x86_simd / my_scalar all produce identical output, this just
+// picks the fastest one the current CPU supports. Set CPE442_SIMD=scalar|sse2|avx2|neon to force a
+// specific level (handy for benchmarking); unsupported requests fall back to the detected level.
+use std::sync::OnceLock;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SimdLevel {
+    Scalar,
+    Sse2,
+    Avx2,
+    Neon,
+}
+
+impl SimdLevel {
+    pub fn name(self) -> &'static str {
+        match self {
+            SimdLevel::Scalar => "scalar",
+            SimdLevel::Sse2 => "sse2",
+            SimdLevel::Avx2 => "avx2",
+            SimdLevel::Neon => "neon",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<SimdLevel> {
+        match name.to_ascii_lowercase().as_str() {
+            "scalar" => Some(SimdLevel::Scalar),
+            "sse2" => Some(SimdLevel::Sse2),
+            "avx2" => Some(SimdLevel::Avx2),
+            "neon" => Some(SimdLevel::Neon),
+            _ => None,
+        }
+    }
+
+    // can this cpu actually run the kernels for this level?
+    pub fn is_supported(self) -> bool {
+        match self {
+            SimdLevel::Scalar => true,
+            #[cfg(target_arch = "x86_64")]
+            SimdLevel::Sse2 => is_x86_feature_detected!("sse2"),
+            #[cfg(target_arch = "x86_64")]
+            SimdLevel::Avx2 => is_x86_feature_detected!("avx2"),
+            #[cfg(target_arch = "aarch64")]
+            SimdLevel::Neon => std::arch::is_aarch64_feature_detected!("neon"),
+            #[allow(unreachable_patterns)]
+            _ => false,
+        }
+    }
+}
+
+static LEVEL: OnceLock<SimdLevel> = OnceLock::new();
+
+// Best level for this machine, detected once and then cached
+pub fn detect() -> SimdLevel {
+    *LEVEL.get_or_init(|| {
+        let best = best_supported();
+        match std::env::var("CPE442_SIMD") {
+            Ok(name) => match SimdLevel::from_name(&name) {
+                Some(level) if level.is_supported() => level,
+                _ => {
+                    eprintln!(
+                        "CPE442_SIMD={} is not available on this cpu, using {}",
+                        name,
+                        best.name()
+                    );
+                    best
+                }
+            },
+            Err(_) => best,
+        }
+    })
+}
+
+fn best_supported() -> SimdLevel {
+    [SimdLevel::Neon, SimdLevel::Avx2, SimdLevel::Sse2]
+        .into_iter()
+        .find(|level| level.is_supported())
+        .unwrap_or(SimdLevel::Scalar)
+}
@@ -0,0 +1,97 @@
+// The edge operators the "sobel" stage can run. The rest of the code still calls that stage sobel
+// since that's what it started out as.
+//
+// Every operator is written as a pair of 3x3 kernels and the output is |gx| + |gy| clamped to
+// 255, so they all share the same halo and border handling. Single kernel operators (laplacian)
+// just have an all zero gy, and roberts cross (really 2x2) sits in the bottom right corner.
+use crate::args;
+
+pub type Kernel3 = [[i32; 3]; 3];
+
+const ZERO: Kernel3 = [[0; 3]; 3];
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum EdgeOperator {
+    #[default]
+    Sobel,
+    Scharr,
+    Prewitt,
+    Roberts,
+    // 4 neighbour laplacian (no diagonals)
+    Laplacian4,
+    // 8 neighbour laplacian
+    Laplacian8,
+}
+
+impl EdgeOperator {
+    pub const ALL: [EdgeOperator; 6] = [
+        EdgeOperator::Sobel,
+        EdgeOperator::Scharr,
+        EdgeOperator::Prewitt,
+        EdgeOperator::Roberts,
+        EdgeOperator::Laplacian4,
+        EdgeOperator::Laplacian8,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            EdgeOperator::Sobel => "sobel",
+            EdgeOperator::Scharr => "scharr",
+            EdgeOperator::Prewitt => "prewitt",
+            EdgeOperator::Roberts => "roberts",
+            EdgeOperator::Laplacian4 => "laplacian4",
+            EdgeOperator::Laplacian8 => "laplacian8",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<EdgeOperator> {
+        match name.to_ascii_lowercase().as_str() {
+            "sobel" => Some(EdgeOperator::Sobel),
+            "scharr" => Some(EdgeOperator::Scharr),
+            "prewitt" => Some(EdgeOperator::Prewitt),
+            "roberts" => Some(EdgeOperator::Roberts),
+            "laplacian4" | "laplacian" => Some(EdgeOperator::Laplacian4),
+            "laplacian8" => Some(EdgeOperator::Laplacian8),
+            _ => None,
+        }
+    }
+
+    // [gx, gy]
+    pub fn kernels(self) -> [Kernel3; 2] {
+        match self {
+            EdgeOperator::Sobel => [
+                [[-1, 0, 1], [-2, 0, 2], [-1, 0, 1]],
+                [[1, 2, 1], [0, 0, 0], [-1, -2, -1]],
+            ],
+            EdgeOperator::Scharr => [
+                [[-3, 0, 3], [-10, 0, 10], [-3, 0, 3]],
+                [[3, 10, 3], [0, 0, 0], [-3, -10, -3]],
+            ],
+            EdgeOperator::Prewitt => [
+                [[-1, 0, 1], [-1, 0, 1], [-1, 0, 1]],
+                [[1, 1, 1], [0, 0, 0], [-1, -1, -1]],
+            ],
+            EdgeOperator::Roberts => [
+                [[0, 0, 0], [0, 1, 0], [0, 0, -1]],
+                [[0, 0, 0], [0, 0, 1], [0, -1, 0]],
+            ],
+            EdgeOperator::Laplacian4 => [[[0, 1, 0], [1, -4, 1], [0, 1, 0]], ZERO],
+            EdgeOperator::Laplacian8 => [[[1, 1, 1], [1, -8, 1], [1, 1, 1]], ZERO],
+        }
+    }
+
+    // `--operator <name>` (or `--operator=<name>`) from the command line, sobel if it's not given
+    pub fn from_args(args: &[String]) -> Result<EdgeOperator, String> {
+        match args::option_value(args, "operator") {
+            None => Ok(EdgeOperator::default()),
+            Some(name) => EdgeOperator::from_name(name).ok_or_else(|| {
+                let names: Vec<&str> = EdgeOperator::ALL.iter().map(|op| op.name()).collect();
+                format!(
+                    "unknown edge operator '{}', expected one of {}",
+                    name,
+                    names.join(", ")
+                )
+            }),
+        }
+    }
+}
@@ -0,0 +1,248 @@
+// Which implementation the frames go through: the pixel-at-a-time grayscale and sobel from the
+// early labs on one thread (lab 3) or in strips (lab 4), the SIMD kernels (lab 5), or the Pi
+// cluster (lab 6). Not to be confused with my_arm_neon::Backend, which picks between two ways of
+// running the SIMD kernels.
+//
+// That code used to be pasted into each lab binary as float math, it lives here now as pipeline
+// stages on the same fixed point grey as everything else.
+use crate::args;
+use crate::border::BorderMode;
+use crate::edge::EdgeOperator;
+use crate::image::{Image, ImageView};
+use crate::luma::Luma;
+use crate::my_arm_neon::{assert_grayscale_input, FrameOptions, Mode};
+use crate::my_scalar;
+use crate::pipeline::{FrameFilter, Pipeline};
+use crate::prefilter::PreFilter;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Engine {
+    // scalar stages, single threaded (lab3_slow)
+    Scalar,
+    // scalar stages, one strip per rayon worker (lab4_threaded)
+    Threaded,
+    // the SIMD kernels, NEON on the pi and SSE2/AVX2 on x86 (lab5_simd)
+    #[default]
+    Neon,
+    // frames go out to lab6_compute workers over zeromq (lab6_host)
+    Distributed,
+}
+
+impl Engine {
+    pub fn name(self) -> &'static str {
+        match self {
+            Engine::Scalar => "scalar",
+            Engine::Threaded => "threaded",
+            Engine::Neon => "neon",
+            Engine::Distributed => "distributed",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Engine> {
+        match name.to_ascii_lowercase().as_str() {
+            "scalar" | "slow" => Some(Engine::Scalar),
+            "threaded" => Some(Engine::Threaded),
+            "neon" | "simd" => Some(Engine::Neon),
+            "distributed" | "cluster" => Some(Engine::Distributed),
+            _ => None,
+        }
+    }
+
+    // `--backend scalar|threaded|neon|distributed`, neon if it's not given
+    pub fn from_args(args: &[String]) -> Result<Engine, String> {
+        match args::option_value(args, "backend") {
+            None => Ok(Engine::default()),
+            Some(name) => Engine::from_name(name).ok_or_else(|| {
+                format!(
+                    "unknown backend '{}', expected scalar, threaded, neon or distributed",
+                    name
+                )
+            }),
+        }
+    }
+
+    // The chain this engine runs for `options`. Canny only exists in the library, so it runs
+    // the SIMD stages whatever the engine (single threaded for scalar). Distributed gives what
+    // each worker runs.
+    pub fn pipeline(self, options: &FrameOptions) -> Pipeline {
+        match (self, options.mode) {
+            (Engine::Scalar, _) => Engine::Threaded.pipeline(options).strips(1),
+            (Engine::Threaded, Mode::Sobel) => {
+                let mut pipeline = Pipeline::new()
+                    .strips(options.strips)
+                    .border(options.border)
+                    .then(ScalarGrayscale { luma: options.luma });
+                if options.prefilter != PreFilter::None {
+                    pipeline = pipeline.then(options.prefilter);
+                }
+                pipeline.then(ScalarSobel {
+                    operator: options.operator,
+                })
+            }
+            _ => Pipeline::from_options(options),
+        }
+    }
+}
+
+// grey/BGR/BGRA to grey one pixel at a time, no SIMD
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ScalarGrayscale {
+    pub luma: Luma,
+}
+
+impl FrameFilter for ScalarGrayscale {
+    fn name(&self) -> &'static str {
+        "scalar-grayscale"
+    }
+
+    fn halo(&self) -> usize {
+        0
+    }
+
+    fn apply(&self, input: &ImageView<u8>, _border: BorderMode) -> Image<u8> {
+        to442_grayscale(input, self.luma)
+    }
+}
+
+// the edge operator one pixel at a time, no SIMD
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ScalarSobel {
+    pub operator: EdgeOperator,
+}
+
+impl FrameFilter for ScalarSobel {
+    fn name(&self) -> &'static str {
+        "scalar-sobel"
+    }
+
+    fn halo(&self) -> usize {
+        1
+    }
+
+    fn apply(&self, input: &ImageView<u8>, border: BorderMode) -> Image<u8> {
+        to442_sobel(input, border, self.operator)
+    }
+}
+
+// Same fixed point math as the SIMD kernels (my_scalar::grey_pixel), so every engine gives the
+// same grey for the same --luma/--rounding. Takes grey, BGR or BGRA like to442_grayscale_simd,
+// grey just gets copied.
+pub fn to442_grayscale(frame: &ImageView<u8>, luma: Luma) -> Image<u8> {
+    let channels = frame.channels();
+    assert_grayscale_input(channels);
+
+    let mut output = Image::new(frame.width(), frame.height(), 1);
+    for (in_row, out_row) in frame.rows().zip(output.rows_mut()) {
+        match channels {
+            1 => out_row.copy_from_slice(in_row),
+            _ => my_scalar::grayscale_interleaved(in_row, channels, out_row, luma),
+        }
+    }
+
+    output
+}
+
+pub fn to442_sobel(frame: &ImageView<u8>, border: BorderMode, operator: EdgeOperator) -> Image<u8> {
+    let (rows, cols) = (frame.height(), frame.width());
+
+    let mut output = Image::new(cols, rows, 1);
+
+    let [gx, gy] = operator.kernels();
+
+    for y in 0..rows {
+        for x in 0..cols {
+            let (sum_x, sum_y) = (0..3)
+                .flat_map(|ky| {
+                    (0..3).map(move |kx| {
+                        // neighbours past the edge come from the border mode (None reads as 0)
+                        let src_y = border.resolve(y as isize + ky as isize - 1, rows);
+                        let src_x = border.resolve(x as isize + kx as isize - 1, cols);
+                        let pixel: i32 = match (src_y, src_x) {
+                            (Some(src_y), Some(src_x)) => frame.row(src_y)[src_x].into(),
+                            _ => 0,
+                        };
+                        (pixel * gx[ky][kx], pixel * gy[ky][kx])
+                    })
+                })
+                .fold((0i32, 0i32), |(acc_x, acc_y), (dx, dy)| {
+                    (acc_x + dx, acc_y + dy)
+                });
+
+            let magnitude = (sum_x.abs() + sum_y.abs()).min(255) as u8;
+
+            output.row_mut(y)[x] = magnitude;
+        }
+    }
+
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::my_arm_neon::Backend;
+
+    const ENGINES: [Engine; 4] = [
+        Engine::Scalar,
+        Engine::Threaded,
+        Engine::Neon,
+        Engine::Distributed,
+    ];
+
+    // the same grey picture as grey, BGR and BGRA (grey pixels come out of grayscale unchanged
+    // whatever the luma profile, so all three should give the same edges)
+    fn frames(width: usize, height: usize) -> [Image<u8>; 3] {
+        let grey: Vec<u8> = (0..width * height)
+            .map(|i| ((i % width) * 23 + (i / width) * 71 % 256) as u8)
+            .collect();
+        let bgr = grey.iter().flat_map(|&v| [v, v, v]).collect();
+        let bgra = grey.iter().flat_map(|&v| [v, v, v, 255 - v]).collect();
+        [
+            Image::from_vec(width, height, 1, grey),
+            Image::from_vec(width, height, 3, bgr),
+            Image::from_vec(width, height, 4, bgra),
+        ]
+    }
+
+    #[test]
+    fn grayscale_takes_grey_bgr_and_bgra() {
+        let [grey, bgr, bgra] = frames(19, 5);
+        let luma = Luma::default();
+        assert_eq!(to442_grayscale(&grey.view(), luma), grey);
+        assert_eq!(to442_grayscale(&bgr.view(), luma), grey);
+        assert_eq!(to442_grayscale(&bgra.view(), luma), grey);
+    }
+
+    #[test]
+    fn every_engine_takes_every_channel_count() {
+        let [grey, bgr, bgra] = frames(37, 23);
+        for backend in [Backend::TwoStage, Backend::Fused] {
+            let options = FrameOptions {
+                strips: 3,
+                backend,
+                ..Default::default()
+            };
+            let expected = Engine::Scalar.pipeline(&options).run(&bgr.view());
+            for engine in ENGINES {
+                let pipeline = engine.pipeline(&options);
+                for frame in [&grey, &bgr, &bgra] {
+                    assert_eq!(
+                        pipeline.run(&frame.view()),
+                        expected,
+                        "{} with {} channels ({})",
+                        engine.name(),
+                        frame.channels(),
+                        backend.name()
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "grayscale expects grey, BGR or BGRA input")]
+    fn two_channels_are_rejected() {
+        let frame = Image::<u8>::new(4, 4, 2);
+        to442_grayscale(&frame.view(), Luma::default());
+    }
+}
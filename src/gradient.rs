@@ -0,0 +1,386 @@
+// Gradient outputs beyond the clamped |gx| + |gy| the sobel stage produces: the signed gx/gy
+// planes, true L2 or unclamped magnitude, quantized orientation and an HSV false colour view.
+//
+// Things like non-max suppression and HOG need the direction, and analysis wants magnitudes
+// that aren't clipped at 255, so this keeps everything in 16 bits until the caller picks.
+use crate::border::BorderMode;
+use crate::edge::EdgeOperator;
+use crate::image::{Image, ImageView};
+use crate::my_arm_neon::{self, FrameOptions, SOBEL_HALO};
+use crate::my_scalar;
+use crate::prefilter::{self, PreFilter};
+use crate::strips;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum MagnitudeNorm {
+    // |gx| + |gy|, what the sobel stage uses
+    #[default]
+    L1,
+    // sqrt(gx^2 + gy^2), rounded
+    L2,
+}
+
+impl MagnitudeNorm {
+    pub fn name(self) -> &'static str {
+        match self {
+            MagnitudeNorm::L1 => "l1",
+            MagnitudeNorm::L2 => "l2",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<MagnitudeNorm> {
+        match name.to_ascii_lowercase().as_str() {
+            "l1" => Some(MagnitudeNorm::L1),
+            "l2" => Some(MagnitudeNorm::L2),
+            _ => None,
+        }
+    }
+}
+
+// Signed gx and gy for every pixel, as one 2 channel image (gx, gy interleaved). Fits in an i16
+// for every operator, scharr is the biggest at 16 * 255.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Gradient {
+    planes: Image<i16>,
+}
+
+impl Gradient {
+    // gradient of a single channel grey image (after the pre-filter, if any), strip parallel
+    // like do_frame
+    pub fn from_grey(grey: &ImageView<u8>, options: &FrameOptions) -> Gradient {
+        assert_eq!(
+            grey.channels(),
+            1,
+            "gradient expects a single channel image"
+        );
+        let halo = SOBEL_HALO + options.prefilter.halo();
+        let planes =
+            strips::run_strips(
+                grey,
+                options.strips,
+                halo,
+                options.border,
+                |strip| match options.prefilter {
+                    PreFilter::None => gradient_planes(strip, options.border, options.operator),
+                    filter => {
+                        let filtered = prefilter::apply(strip, options.border, filter);
+                        gradient_planes(&filtered.view(), options.border, options.operator)
+                    }
+                },
+            );
+        Gradient { planes }
+    }
+
+    // grayscale (+ pre-filter) + gradient of a BGR frame
+    pub fn from_bgr(frame: &ImageView<u8>, options: &FrameOptions) -> Gradient {
+        let halo = SOBEL_HALO + options.prefilter.halo();
+        let planes = strips::run_strips(frame, options.strips, halo, options.border, |strip| {
+            let grey = my_arm_neon::to442_grey_filtered(strip, options);
+            gradient_planes(&grey.view(), options.border, options.operator)
+        });
+        Gradient { planes }
+    }
+
+    pub fn width(&self) -> usize {
+        self.planes.width()
+    }
+
+    pub fn height(&self) -> usize {
+        self.planes.height()
+    }
+
+    // the interleaved (gx, gy) image
+    pub fn planes(&self) -> &Image<i16> {
+        &self.planes
+    }
+
+    pub fn gx(&self) -> Image<i16> {
+        self.map(|gx, _| gx)
+    }
+
+    pub fn gy(&self) -> Image<i16> {
+        self.map(|_, gy| gy)
+    }
+
+    // magnitude without clamping (at most 8160 for L1, 5770 for L2)
+    pub fn magnitude(&self, norm: MagnitudeNorm) -> Image<u16> {
+        self.map(|gx, gy| magnitude(gx, gy, norm))
+    }
+
+    // magnitude clamped to 255, for display. L1 gives exactly what the sobel stage outputs
+    pub fn magnitude_u8(&self, norm: MagnitudeNorm) -> Image<u8> {
+        self.map(|gx, gy| magnitude(gx, gy, norm).min(255) as u8)
+    }
+
+    // Gradient direction folded onto 0..180 degrees (a gradient and its opposite land in the same
+    // bin) and split into `bins` equal bins, bin 0 centred on a horizontal gradient. So 4 bins
+    // gives the 0/45/90/135 directions non-max suppression wants, 9 the usual HOG bins.
+    // Pixels with no gradient get bin 0.
+    pub fn orientation(&self, bins: usize) -> Image<u8> {
+        assert!((1..=256).contains(&bins), "orientation needs 1 to 256 bins");
+        self.map(|gx, gy| orientation_bin(gx, gy, bins))
+    }
+
+    // False colour BGR image: hue is the direction over the full circle, brightness the L2
+    // magnitude (clamped to 255), saturation always full.
+    pub fn hsv_visualization(&self) -> Image<u8> {
+        let mut output = Image::new(self.width(), self.height(), 3);
+        for (in_row, out_row) in self.planes.view().rows().zip(output.rows_mut()) {
+            for (g, bgr) in in_row.chunks_exact(2).zip(out_row.chunks_exact_mut(3)) {
+                let value = magnitude(g[0], g[1], MagnitudeNorm::L2).min(255) as u8;
+                bgr.copy_from_slice(&hsv_to_bgr(angle_degrees(g[0], g[1]), value));
+            }
+        }
+        output
+    }
+
+    // one output plane from each (gx, gy) pair
+    fn map<T: Copy + Default>(&self, f: impl Fn(i16, i16) -> T) -> Image<T> {
+        let mut output = Image::new(self.width(), self.height(), 1);
+        for (in_row, out_row) in self.planes.view().rows().zip(output.rows_mut()) {
+            for (g, out) in in_row.chunks_exact(2).zip(out_row.iter_mut()) {
+                *out = f(g[0], g[1]);
+            }
+        }
+        output
+    }
+}
+
+// (gx, gy) for every pixel of a strip, edges handled with `border` the same way as sobel_border
+pub(crate) fn gradient_planes(
+    grey: &ImageView<u8>,
+    border: BorderMode,
+    operator: EdgeOperator,
+) -> Image<i16> {
+    let (rows, cols) = (grey.height(), grey.width());
+    let mut planes = Image::new(cols, rows, 2);
+    for (y, out_row) in planes.rows_mut().enumerate() {
+        for (x, out) in out_row.chunks_exact_mut(2).enumerate() {
+            let (gx, gy) = if y == 0 || x == 0 || y + 1 == rows || x + 1 == cols {
+                my_scalar::gradient_pixel_border(grey, y, x, border, operator)
+            } else {
+                let window = [grey.row(y - 1), grey.row(y), grey.row(y + 1)];
+                my_scalar::gradient_pixel_rows(window, x, operator)
+            };
+            out[0] = gx as i16;
+            out[1] = gy as i16;
+        }
+    }
+    planes
+}
+
+pub(crate) fn magnitude(gx: i16, gy: i16, norm: MagnitudeNorm) -> u16 {
+    let (gx, gy) = (gx as i32, gy as i32);
+    match norm {
+        MagnitudeNorm::L1 => (gx.abs() + gy.abs()) as u16,
+        MagnitudeNorm::L2 => ((gx * gx + gy * gy) as f32).sqrt().round() as u16,
+    }
+}
+
+// see Gradient::orientation
+pub(crate) fn orientation_bin(gx: i16, gy: i16, bins: usize) -> u8 {
+    let bin_width = 180.0 / bins as f32;
+    let angle = angle_degrees(gx, gy) % 180.0;
+    (((angle + bin_width / 2.0) / bin_width) as usize % bins) as u8
+}
+
+// direction of (gx, gy) in 0..360 degrees
+fn angle_degrees(gx: i16, gy: i16) -> f32 {
+    (gy as f32).atan2(gx as f32).to_degrees().rem_euclid(360.0)
+}
+
+// hue in degrees, full saturation
+fn hsv_to_bgr(hue: f32, value: u8) -> [u8; 3] {
+    let v = value as f32;
+    let sector = (hue / 60.0) as u32 % 6;
+    let fraction = hue / 60.0 - (hue / 60.0).floor();
+    let falling = (v * (1.0 - fraction)).round() as u8;
+    let rising = (v * fraction).round() as u8;
+
+    // [b, g, r]
+    match sector {
+        0 => [0, rising, value],
+        1 => [0, value, falling],
+        2 => [rising, value, 0],
+        3 => [value, falling, 0],
+        4 => [value, 0, rising],
+        _ => [falling, 0, value],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(width: usize, height: usize, f: impl Fn(i32, i32) -> i32) -> Image<u8> {
+        let mut grey = Image::new(width, height, 1);
+        for (y, row) in grey.rows_mut().enumerate() {
+            for (x, out) in row.iter_mut().enumerate() {
+                *out = f(x as i32, y as i32).clamp(0, 255) as u8;
+            }
+        }
+        grey
+    }
+
+    fn gradient(grey: &Image<u8>, operator: EdgeOperator) -> Gradient {
+        let options = FrameOptions {
+            border: BorderMode::Replicate,
+            operator,
+            strips: 3,
+            ..Default::default()
+        };
+        Gradient::from_grey(&grey.view(), &options)
+    }
+
+    // (gx, gy) at one pixel
+    fn at(gradient: &Gradient, x: usize, y: usize) -> (i16, i16) {
+        let g = &gradient.planes().row(y)[2 * x..2 * x + 2];
+        (g[0], g[1])
+    }
+
+    #[test]
+    fn step_edges() {
+        // brighter to the right: gx positive, and brighter at the top: gy positive (y is up)
+        let right = gradient(
+            &frame(12, 10, |x, _| if x >= 6 { 200 } else { 0 }),
+            EdgeOperator::Sobel,
+        );
+        let left = gradient(
+            &frame(12, 10, |x, _| if x < 6 { 200 } else { 0 }),
+            EdgeOperator::Sobel,
+        );
+        let top = gradient(
+            &frame(12, 10, |_, y| if y < 5 { 200 } else { 0 }),
+            EdgeOperator::Sobel,
+        );
+        let bottom = gradient(
+            &frame(12, 10, |_, y| if y >= 5 { 200 } else { 0 }),
+            EdgeOperator::Sobel,
+        );
+
+        for y in 0..10 {
+            assert_eq!(at(&right, 5, y), (800, 0));
+            assert_eq!(at(&left, 6, y), (-800, 0));
+        }
+        for x in 0..12 {
+            assert_eq!(at(&top, x, 4), (0, 800));
+            assert_eq!(at(&bottom, x, 5), (0, -800));
+        }
+        // away from the step there's nothing
+        assert_eq!(at(&right, 2, 3), (0, 0));
+        assert_eq!(right.gx().row(7)[5], 800);
+        assert_eq!(top.gy().row(4)[11], 800);
+        assert_eq!(right.gy(), Image::new(12, 10, 1));
+
+        // opposite directions fold onto the same bin: 0 for horizontal gradients, 2 for
+        // vertical ones
+        assert_eq!(right.orientation(4).row(4)[5], 0);
+        assert_eq!(left.orientation(4).row(4)[6], 0);
+        assert_eq!(top.orientation(4).row(4)[6], 2);
+        assert_eq!(bottom.orientation(4).row(5)[6], 2);
+    }
+
+    #[test]
+    fn diagonals() {
+        // brighter towards the top right, then towards the bottom right
+        let up = gradient(
+            &frame(9, 9, |x, y| 100 + 10 * x - 10 * y),
+            EdgeOperator::Sobel,
+        );
+        let down = gradient(
+            &frame(9, 9, |x, y| 20 + 10 * x + 10 * y),
+            EdgeOperator::Sobel,
+        );
+        for y in 1..8 {
+            for x in 1..8 {
+                assert_eq!(at(&up, x, y), (80, 80));
+                assert_eq!(at(&down, x, y), (80, -80));
+            }
+        }
+        assert_eq!(up.orientation(4).row(4)[4], 1);
+        assert_eq!(down.orientation(4).row(4)[4], 3);
+        // 8 bins, 22.5 degrees each
+        assert_eq!(up.orientation(8).row(4)[4], 2);
+        assert_eq!(down.orientation(8).row(4)[4], 6);
+    }
+
+    #[test]
+    fn l2_magnitude() {
+        // a 3-4-5 slope: gx = 8 * 3, gy = 8 * 4
+        let slope = gradient(
+            &frame(9, 9, |x, y| 100 + 3 * x - 4 * y),
+            EdgeOperator::Sobel,
+        );
+        assert_eq!(at(&slope, 4, 4), (24, 32));
+        assert_eq!(slope.magnitude(MagnitudeNorm::L2).row(4)[4], 40);
+        assert_eq!(slope.magnitude(MagnitudeNorm::L1).row(4)[4], 56);
+        assert_eq!(slope.magnitude_u8(MagnitudeNorm::L2).row(4)[4], 40);
+    }
+
+    #[test]
+    fn magnitude_is_unclamped() {
+        let step = frame(8, 4, |x, _| if x >= 4 { 255 } else { 0 });
+        let sobel = gradient(&step, EdgeOperator::Sobel);
+        assert_eq!(sobel.magnitude(MagnitudeNorm::L1).row(1)[3], 1020);
+        assert_eq!(sobel.magnitude(MagnitudeNorm::L2).row(1)[3], 1020);
+        assert_eq!(sobel.magnitude_u8(MagnitudeNorm::L1).row(1)[3], 255);
+
+        // scharr is the biggest any operator gets
+        let scharr = gradient(&step, EdgeOperator::Scharr);
+        assert_eq!(scharr.magnitude(MagnitudeNorm::L1).row(1)[3], 16 * 255);
+        assert_eq!(scharr.magnitude_u8(MagnitudeNorm::L2).row(1)[3], 255);
+    }
+
+    #[test]
+    fn l1_u8_matches_the_sobel_stage() {
+        let grey = frame(37, 23, |x, y| {
+            ((x * 37 + y * 101) % 256) ^ ((x * y % 7) * 31)
+        });
+        for operator in EdgeOperator::ALL {
+            for border in BorderMode::ALL {
+                let options = FrameOptions {
+                    border,
+                    operator,
+                    strips: 4,
+                    ..Default::default()
+                };
+                let gradient = Gradient::from_grey(&grey.view(), &options);
+                assert_eq!(
+                    gradient.magnitude_u8(MagnitudeNorm::L1),
+                    my_arm_neon::to442_sobel_simd(&grey.view(), border, operator),
+                    "{} with {}",
+                    operator.name(),
+                    border.name()
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn hsv_hue_follows_the_direction() {
+        // a square, so the edges point every way
+        let square = frame(12, 12, |x, y| {
+            if (3..9).contains(&x) && (3..9).contains(&y) {
+                120
+            } else {
+                0
+            }
+        });
+        let gradient = gradient(&square, EdgeOperator::Sobel);
+        let hsv = gradient.hsv_visualization();
+        let pixel =
+            |x: usize, y: usize| -> [u8; 3] { hsv.row(y)[3 * x..3 * x + 3].try_into().unwrap() };
+
+        // left edge points right (0 degrees, red), right edge left (180, cyan), top edge down
+        // (270, violet), bottom edge up (90, yellow-green)
+        assert_eq!(at(&gradient, 2, 6), (480, 0));
+        assert_eq!(pixel(2, 6), [0, 0, 255]);
+        assert_eq!(pixel(9, 6), [255, 255, 0]);
+        assert_eq!(pixel(6, 2), [255, 0, 128]);
+        assert_eq!(pixel(6, 9), [0, 255, 128]);
+
+        // flat inside and out is black
+        assert_eq!(pixel(0, 0), [0, 0, 0]);
+        assert_eq!(pixel(6, 6), [0, 0, 0]);
+    }
+}
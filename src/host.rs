@@ -0,0 +1,275 @@
+// The lab 6 host: read the video, push every frame out to the workers (PUSH on TASK_PORT), pull
+// the results back (PULL on RESULT_PORT) and put them back in order for display.
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+
+use opencv::{core::Mat, prelude::*, Result};
+use tokio::{sync::Mutex, task::yield_now};
+use zmq::{Context, Socket};
+
+use crate::image::ImageView;
+use crate::mat_packet::{self, MatMessage, Reorder};
+use crate::pipeline::RunStats;
+use crate::playback::Presenter;
+use crate::timing;
+use crate::video::{Display, VideoOutput, VideoSettings, VideoSource};
+
+// Send every frame of `input` out to the workers and show (and/or save) what comes back, in
+// order. `pipeline` is the chain for the workers as JSON (a PipelineConfig), sent along with
+// every frame. The stats count the frames that made it back, there's no processing time on this
+// end.
+pub async fn run_host(
+    input: &str,
+    pipeline: Option<String>,
+    settings: &VideoSettings,
+) -> Result<RunStats> {
+    // Open the video file
+    let video = VideoSource::open(input, settings.range)?;
+    let display = Display::new(settings.display, &["Video Frame"])?;
+    let mut output = VideoOutput::from_settings(settings, video.fps()?)?;
+    let mut presenter = Presenter::new(settings.pacing, video.fps()?);
+
+    // open zeromq ports for communication with clients
+    let (tx, rx, _context) = init_zmq()?;
+
+    // screw around with Arc<Mutex<>> patterns because rust is rust
+    let tx_safe = Arc::new(Mutex::new(tx));
+    let tx_clone: Arc<Mutex<Socket>> = Arc::clone(&tx_safe);
+    let rx_safe = Arc::new(Mutex::new(rx));
+    let rx_clone: Arc<Mutex<Socket>> = Arc::clone(&rx_safe);
+
+    let shared_counter = Arc::new(AtomicU64::new(0));
+    let counter1 = Arc::clone(&shared_counter);
+    let counter2 = Arc::clone(&shared_counter);
+
+    // how many frames the video had, once the sender gets to the end of it
+    let total_frames = Arc::new(AtomicU64::new(u64::MAX));
+    let total1 = Arc::clone(&total_frames);
+
+    let start_time = Instant::now();
+
+    // spawn thread for transmission
+    tokio::spawn(async move { send_frames(tx_clone, video, counter1, total1, pipeline).await });
+
+    // spawn thread for reception
+    let skipped = receive_frames(
+        rx_clone,
+        counter2,
+        total_frames,
+        &display,
+        &mut presenter,
+        &mut output,
+    )
+    .await?;
+    presenter.print_dropped();
+    if skipped > 0 {
+        println!("Skipped {} frames the workers didn't process", skipped);
+    }
+    if let Some(output) = &output {
+        println!("Wrote {} frames to {}", output.frames(), output.path());
+    }
+
+    Ok(RunStats {
+        frames: (shared_counter.load(Ordering::SeqCst) - skipped) as u32,
+        elapsed: start_time.elapsed(),
+        dropped: presenter.dropped(),
+        ..RunStats::default()
+    })
+}
+
+async fn send_frames(
+    tx_mutex: Arc<Mutex<Socket>>,
+    mut video: VideoSource,
+    rx_count: Arc<AtomicU64>,
+    total: Arc<AtomicU64>,
+    pipeline: Option<String>,
+) -> Result<()> {
+    let mut frame_count = 0;
+
+    let tx_guard = tx_mutex.lock().await;
+
+    loop {
+        // Read the next frame
+        let mut frame = Mat::default();
+        let decode_start = Instant::now();
+        if !video.read(&mut frame)? {
+            println!("Video processing finished.");
+            total.store(frame_count, Ordering::SeqCst);
+            break;
+        }
+        timing::record("decode", frame_count, decode_start);
+
+        let send_span = timing::span("send", frame_count);
+        let mut mat_message = mat_packet::from_mat(&frame, frame_count, 0)?;
+        mat_message.pipeline = pipeline.clone();
+        let serialized: Vec<u8> = bincode::serialize(&mat_message).expect("Serialization failed");
+        (*tx_guard)
+            .send(serialized, 0)
+            .expect("Failed to send task");
+        drop(send_span);
+
+        frame_count += 1;
+
+        // don't get more than 8 frames ahead of the display
+        while frame_count > rx_count.load(Ordering::SeqCst) + 8 {
+            yield_now().await;
+        }
+    }
+
+    Ok(())
+}
+
+// Shows (and/or writes) the results in frame order until every frame sent is accounted for,
+// returns how many of them got skipped because a worker couldn't process them or they never
+// came back.
+async fn receive_frames(
+    rx_mutex: Arc<Mutex<Socket>>,
+    count: Arc<AtomicU64>,
+    total: Arc<AtomicU64>,
+    display: &Display,
+    presenter: &mut Presenter,
+    output: &mut Option<VideoOutput>,
+) -> Result<u64> {
+    let start = std::time::Instant::now();
+    let mut last: std::time::Instant = start;
+
+    let mut frames = Reorder::new(mat_packet::RESULT_TIMEOUT);
+    let mut skipped = 0;
+
+    let rx_guard = rx_mutex.lock().await;
+
+    // Waiting on the workers counts too, that's the stalls showing up. Only restarted once a
+    // message actually arrived and got handled, so a stall over the 100ms receive timeout is
+    // still one long receive.
+    let mut recv_start = Instant::now();
+    loop {
+        let received = match (*rx_guard).recv_msg(0) {
+            Ok(bytes) => Some(bytes),
+            // timed out (see init_zmq), nothing new but there may be a missing frame to give up on
+            Err(zmq::Error::EAGAIN) => None,
+            Err(err) => panic!("Failed to receive result: {}", err),
+        };
+
+        if let Some(bytes) = &received {
+            match bincode::deserialize::<MatMessage>(bytes) {
+                Ok(msg) => {
+                    let rx_num = msg.number;
+                    timing::record("receive", rx_num, recv_start);
+
+                    // a frame that already came out (or got skipped) turning up late
+                    if !frames.push(msg) {
+                        eprintln!("Frame {} came back too late, ignoring it", rx_num);
+                    }
+
+                    // Every 50 frames, calculate and print averages
+                    if rx_num % 50 == 0 {
+                        let now = std::time::Instant::now();
+                        let total_sobel_time = now.duration_since(start);
+                        let last_50_time = now.duration_since(last);
+
+                        println!(
+                            "Averages after {} frames: avg time to sobel: {:?}/only last 50: {:?}",
+                            rx_num,
+                            total_sobel_time / (rx_num.max(1) as u32),
+                            last_50_time / 50
+                        );
+
+                        last = now;
+                    }
+                }
+                Err(err) => eprintln!("Skipping a result that isn't a frame packet ({})", err),
+            }
+        } else {
+            // the last frames never came back, nothing later is going to push them out (total
+            // stays u64::MAX until the sender reaches the end of the video)
+            let sent = total.load(Ordering::SeqCst);
+            let shown = count.load(Ordering::SeqCst);
+            if sent != u64::MAX && shown < sent && frames.is_empty() && frames.is_stalled() {
+                eprintln!(
+                    "Frames {} to {} never came back from the workers, skipping them",
+                    shown,
+                    sent - 1
+                );
+                skipped += sent - shown;
+                count.store(sent, Ordering::SeqCst);
+            }
+        }
+
+        // Process messages in order
+        while let Some(frame) = frames.pop() {
+            count.store(frames.next_number(), Ordering::SeqCst);
+            let msg = match frame {
+                Ok(msg) => msg,
+                Err(number) => {
+                    eprintln!(
+                        "Frame {} didn't make it through the workers, skipping it",
+                        number
+                    );
+                    skipped += 1;
+                    continue;
+                }
+            };
+
+            // Convert to a frame and display
+            let combined_frame = Mat::try_from(&msg)?;
+
+            // late frames still get saved, they just don't get shown. Waits on tokio's timer
+            // rather than present()'s sleep, which would block the runtime thread.
+            if let Some(wait) = presenter.schedule() {
+                if !wait.is_zero() {
+                    tokio::time::sleep(wait).await;
+                }
+                let _span = timing::span("display", msg.number);
+                display.show(0, &combined_frame)?;
+            }
+            if let Some(output) = output {
+                let _span = timing::span("write", msg.number);
+                output.write(&ImageView::from_mat(&combined_frame)?)?;
+            }
+        }
+
+        if count.load(Ordering::SeqCst) >= total.load(Ordering::SeqCst) {
+            println!("All frames received.");
+            break;
+        }
+
+        // wait minimum time before continuing loop (note: maybe make display and packet reception different threads?)
+        if !display.poll()? {
+            break;
+        }
+        if received.is_some() {
+            recv_start = Instant::now();
+        }
+    }
+
+    Ok(skipped)
+}
+
+fn init_zmq() -> Result<(Socket, Socket, Context)> {
+    let context = Context::new();
+
+    // Task sender (PUSH)
+    let tx: zmq::Socket = context
+        .socket(zmq::PUSH)
+        .expect("Failed to create task sender");
+    tx.bind(&format!("tcp://*:{}", mat_packet::TASK_PORT))
+        .expect("Failed to bind task sender");
+    tx.set_sndhwm(1).expect("failed to set high water mark");
+
+    // Result receiver (PULL)
+    let rx: zmq::Socket = context
+        .socket(zmq::PULL)
+        .expect("Failed to create result receiver");
+    rx.bind(&format!("tcp://*:{}", mat_packet::RESULT_PORT))
+        .expect("Failed to bind result receiver");
+    rx.set_rcvhwm(1) // Set receive high water mark (max messages to buffer)
+        .expect("Failed to set receive HWM for result receiver");
+    // so the receiver gets to notice the video is over instead of blocking forever
+    rx.set_rcvtimeo(100)
+        .expect("Failed to set receive timeout for result receiver");
+
+    println!("Host is ready to distribute tasks and receive results.");
+
+    Ok((tx, rx, context))
+}
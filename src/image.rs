@@ -0,0 +1,615 @@
+// Owned and borrowed image buffers that the kernels work on, so the processing code never has
+// to touch Mat::data() pointers directly.
+//
+// Layout is row major with `channels` interleaved elements per pixel (BGR for colour frames),
+// and rows `stride` elements apart (stride >= width * channels, more for ROIs of a bigger frame).
+// Converting to/from an opencv Mat (with the opencv-io feature) is zero-copy, see the bottom of
+// this file.
+
+// number of elements a buffer needs to hold an image with this shape
+fn required_len(width: usize, height: usize, channels: usize, stride: usize) -> usize {
+    match height {
+        0 => 0,
+        _ => stride * (height - 1) + width * channels,
+    }
+}
+
+// A sub-rectangle of an image, in pixels
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Rect {
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
+}
+
+impl Rect {
+    pub fn new(x: usize, y: usize, width: usize, height: usize) -> Self {
+        Rect {
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+
+    // whether this fits inside a width x height image
+    pub fn fits(&self, width: usize, height: usize) -> bool {
+        self.x + self.width <= width && self.y + self.height <= height
+    }
+
+    // grown by `margin` pixels on every side, but never past a width x height image
+    pub fn expand(&self, margin: usize, width: usize, height: usize) -> Rect {
+        let x = self.x.saturating_sub(margin);
+        let y = self.y.saturating_sub(margin);
+        Rect {
+            x,
+            y,
+            width: (self.x + self.width + margin).min(width) - x,
+            height: (self.y + self.height + margin).min(height) - y,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Image<T> {
+    data: Vec<T>,
+    width: usize,
+    height: usize,
+    channels: usize,
+    stride: usize,
+}
+
+impl<T: Copy + Default> Image<T> {
+    // zero filled, tightly packed image
+    pub fn new(width: usize, height: usize, channels: usize) -> Self {
+        Image {
+            data: vec![T::default(); width * height * channels],
+            width,
+            height,
+            channels,
+            stride: width * channels,
+        }
+    }
+}
+
+impl<T> Image<T> {
+    // wrap an existing tightly packed buffer
+    pub fn from_vec(width: usize, height: usize, channels: usize, data: Vec<T>) -> Self {
+        assert_eq!(
+            data.len(),
+            width * height * channels,
+            "buffer length does not match {}x{}x{} image",
+            width,
+            height,
+            channels
+        );
+        Image {
+            data,
+            width,
+            height,
+            channels,
+            stride: width * channels,
+        }
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    pub fn channels(&self) -> usize {
+        self.channels
+    }
+
+    pub fn stride(&self) -> usize {
+        self.stride
+    }
+
+    pub fn data(&self) -> &[T] {
+        &self.data
+    }
+
+    pub fn data_mut(&mut self) -> &mut [T] {
+        &mut self.data
+    }
+
+    pub fn into_vec(self) -> Vec<T> {
+        self.data
+    }
+
+    pub fn row(&self, y: usize) -> &[T] {
+        let start = y * self.stride;
+        &self.data[start..start + self.width * self.channels]
+    }
+
+    pub fn row_mut(&mut self, y: usize) -> &mut [T] {
+        let start = y * self.stride;
+        &mut self.data[start..start + self.width * self.channels]
+    }
+
+    pub fn rows_mut(&mut self) -> impl Iterator<Item = &mut [T]> {
+        let row_len = self.width * self.channels;
+        self.data
+            .chunks_mut(self.stride.max(1))
+            .take(self.height)
+            .map(move |row| &mut row[..row_len])
+    }
+
+    pub fn view(&self) -> ImageView<'_, T> {
+        ImageView::new(
+            &self.data,
+            self.width,
+            self.height,
+            self.channels,
+            self.stride,
+        )
+    }
+
+    pub fn view_mut(&mut self) -> ImageViewMut<'_, T> {
+        ImageViewMut::new(
+            &mut self.data,
+            self.width,
+            self.height,
+            self.channels,
+            self.stride,
+        )
+    }
+}
+
+// Borrowed, read only window onto image memory (a whole frame or a sub-rectangle of one)
+#[derive(Debug)]
+pub struct ImageView<'a, T> {
+    data: &'a [T],
+    width: usize,
+    height: usize,
+    channels: usize,
+    stride: usize,
+}
+
+// derive would put a `T: Clone` bound on these, which isn't needed for a shared slice
+impl<T> Clone for ImageView<'_, T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for ImageView<'_, T> {}
+
+impl<'a, T> ImageView<'a, T> {
+    pub fn new(data: &'a [T], width: usize, height: usize, channels: usize, stride: usize) -> Self {
+        assert!(stride >= width * channels, "stride is shorter than a row");
+        assert!(
+            data.len() >= required_len(width, height, channels, stride),
+            "buffer too small for {}x{}x{} image with stride {}",
+            width,
+            height,
+            channels,
+            stride
+        );
+        ImageView {
+            data,
+            width,
+            height,
+            channels,
+            stride,
+        }
+    }
+
+    pub fn packed(data: &'a [T], width: usize, height: usize, channels: usize) -> Self {
+        Self::new(data, width, height, channels, width * channels)
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    pub fn channels(&self) -> usize {
+        self.channels
+    }
+
+    pub fn stride(&self) -> usize {
+        self.stride
+    }
+
+    // the underlying memory, starting at the first pixel (includes row padding)
+    pub fn data(&self) -> &'a [T] {
+        &self.data[..required_len(self.width, self.height, self.channels, self.stride)]
+    }
+
+    pub fn is_packed(&self) -> bool {
+        self.stride == self.width * self.channels
+    }
+
+    pub fn row(&self, y: usize) -> &'a [T] {
+        let start = y * self.stride;
+        &self.data[start..start + self.width * self.channels]
+    }
+
+    pub fn rows(&self) -> impl Iterator<Item = &'a [T]> + '_ {
+        (0..self.height).map(move |y| self.row(y))
+    }
+
+    // zero-copy window onto part of this view
+    pub fn sub_view(&self, x: usize, y: usize, width: usize, height: usize) -> ImageView<'a, T> {
+        assert!(
+            x + width <= self.width && y + height <= self.height,
+            "sub view out of bounds"
+        );
+        if height == 0 {
+            return ImageView::new(&[], width, 0, self.channels, self.stride);
+        }
+        let start = y * self.stride + x * self.channels;
+        ImageView::new(
+            &self.data[start..],
+            width,
+            height,
+            self.channels,
+            self.stride,
+        )
+    }
+
+    pub fn sub_view_rect(&self, rect: Rect) -> ImageView<'a, T> {
+        self.sub_view(rect.x, rect.y, rect.width, rect.height)
+    }
+
+    // copy into a tightly packed owned image
+    pub fn to_image(&self) -> Image<T>
+    where
+        T: Copy,
+    {
+        let mut data = Vec::with_capacity(self.width * self.height * self.channels);
+        for row in self.rows() {
+            data.extend_from_slice(row);
+        }
+        Image::from_vec(self.width, self.height, self.channels, data)
+    }
+}
+
+// Borrowed, writable window onto image memory
+#[derive(Debug)]
+pub struct ImageViewMut<'a, T> {
+    data: &'a mut [T],
+    width: usize,
+    height: usize,
+    channels: usize,
+    stride: usize,
+}
+
+impl<'a, T> ImageViewMut<'a, T> {
+    pub fn new(
+        data: &'a mut [T],
+        width: usize,
+        height: usize,
+        channels: usize,
+        stride: usize,
+    ) -> Self {
+        assert!(stride >= width * channels, "stride is shorter than a row");
+        assert!(
+            data.len() >= required_len(width, height, channels, stride),
+            "buffer too small for {}x{}x{} image with stride {}",
+            width,
+            height,
+            channels,
+            stride
+        );
+        ImageViewMut {
+            data,
+            width,
+            height,
+            channels,
+            stride,
+        }
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    pub fn channels(&self) -> usize {
+        self.channels
+    }
+
+    pub fn stride(&self) -> usize {
+        self.stride
+    }
+
+    pub fn as_view(&self) -> ImageView<'_, T> {
+        ImageView::new(
+            self.data,
+            self.width,
+            self.height,
+            self.channels,
+            self.stride,
+        )
+    }
+
+    pub fn row(&self, y: usize) -> &[T] {
+        let start = y * self.stride;
+        &self.data[start..start + self.width * self.channels]
+    }
+
+    pub fn row_mut(&mut self, y: usize) -> &mut [T] {
+        let start = y * self.stride;
+        &mut self.data[start..start + self.width * self.channels]
+    }
+
+    // every row, without the padding between them (which belongs to the rest of the frame)
+    pub fn rows_mut(&mut self) -> impl Iterator<Item = &mut [T]> {
+        let row_len = self.width * self.channels;
+        self.data
+            .chunks_mut(self.stride.max(1))
+            .take(self.height)
+            .map(move |row| &mut row[..row_len])
+    }
+
+    pub fn sub_view_mut(
+        &mut self,
+        x: usize,
+        y: usize,
+        width: usize,
+        height: usize,
+    ) -> ImageViewMut<'_, T> {
+        assert!(
+            x + width <= self.width && y + height <= self.height,
+            "sub view out of bounds"
+        );
+        if height == 0 {
+            return ImageViewMut::new(&mut [], width, 0, self.channels, self.stride);
+        }
+        let start = y * self.stride + x * self.channels;
+        ImageViewMut::new(
+            &mut self.data[start..],
+            width,
+            height,
+            self.channels,
+            self.stride,
+        )
+    }
+
+    pub fn sub_view_mut_rect(&mut self, rect: Rect) -> ImageViewMut<'_, T> {
+        self.sub_view_mut(rect.x, rect.y, rect.width, rect.height)
+    }
+}
+
+// Conversions to and from opencv Mat. Both directions share memory with the source, so the
+// usual borrow rules keep the Mat/Image alive for as long as the other side is in use.
+#[cfg(feature = "opencv-io")]
+mod mat_conversions {
+    use super::{required_len, Image, ImageView, ImageViewMut, Rect};
+    use opencv::{
+        boxed_ref::BoxedRef,
+        core::{DataType, Mat, CV_MAKETYPE},
+        prelude::*,
+    };
+
+    // (width, height, channels, stride in elements) of a Mat that can be viewed as Image<T>.
+    // Mat ROIs are fine, their step is the parent's row pitch and data() points at the first pixel.
+    fn mat_layout<T: DataType>(
+        mat: &impl MatTraitConst,
+    ) -> opencv::Result<(usize, usize, usize, usize)> {
+        if mat.depth() != T::opencv_depth() {
+            return Err(opencv::Error::new(
+                opencv::core::StsUnsupportedFormat,
+                format!(
+                    "Mat depth {} does not match the requested element type",
+                    mat.depth()
+                ),
+            ));
+        }
+        if mat.dims() > 2 {
+            return Err(opencv::Error::new(
+                opencv::core::StsUnsupportedFormat,
+                "only 2d Mats can be viewed as images",
+            ));
+        }
+
+        let (width, height) = (mat.cols() as usize, mat.rows() as usize);
+        let channels = mat.channels() as usize;
+        if width == 0 || height == 0 {
+            return Ok((width, 0, channels, width * channels));
+        }
+        // step1 is the row pitch in elements rather than bytes
+        Ok((width, height, channels, mat.step1(0)?))
+    }
+
+    impl<'a, T: DataType> ImageView<'a, T> {
+        pub fn from_mat(mat: &'a impl MatTraitConst) -> opencv::Result<Self> {
+            let (width, height, channels, stride) = mat_layout::<T>(mat)?;
+            if height == 0 {
+                return Ok(ImageView::new(&[], width, 0, channels, stride));
+            }
+            let len = required_len(width, height, channels, stride);
+            let data = unsafe { std::slice::from_raw_parts(mat.data() as *const T, len) };
+            Ok(ImageView::new(data, width, height, channels, stride))
+        }
+
+        pub fn as_mat(&self) -> opencv::Result<BoxedRef<'a, Mat>> {
+            if self.width == 0 || self.height == 0 {
+                return Ok(BoxedRef::from(Mat::default()));
+            }
+            let typ = CV_MAKETYPE(T::opencv_depth(), self.channels as i32);
+            let mat = unsafe {
+                Mat::new_rows_cols_with_data_unsafe(
+                    self.height as i32,
+                    self.width as i32,
+                    typ,
+                    self.data.as_ptr() as *mut std::ffi::c_void,
+                    self.stride * std::mem::size_of::<T>(),
+                )
+            }?;
+            Ok(BoxedRef::from(mat))
+        }
+    }
+
+    impl<'a, T: DataType> ImageViewMut<'a, T> {
+        // writable view of a Mat (or a Mat ROI), for editing frames in place
+        pub fn from_mat(mat: &'a mut impl MatTrait) -> opencv::Result<Self> {
+            let (width, height, channels, stride) = mat_layout::<T>(mat)?;
+            if height == 0 {
+                return Ok(ImageViewMut::new(&mut [], width, 0, channels, stride));
+            }
+            let len = required_len(width, height, channels, stride);
+            let data = unsafe { std::slice::from_raw_parts_mut(mat.data_mut() as *mut T, len) };
+            Ok(ImageViewMut::new(data, width, height, channels, stride))
+        }
+    }
+
+    impl From<opencv::core::Rect> for Rect {
+        fn from(rect: opencv::core::Rect) -> Self {
+            Rect::new(
+                rect.x.max(0) as usize,
+                rect.y.max(0) as usize,
+                rect.width.max(0) as usize,
+                rect.height.max(0) as usize,
+            )
+        }
+    }
+
+    impl<T: DataType> Image<T> {
+        // owned copy of a Mat's pixels
+        pub fn from_mat(mat: &impl MatTraitConst) -> opencv::Result<Self> {
+            Ok(ImageView::from_mat(mat)?.to_image())
+        }
+
+        pub fn as_mat(&self) -> opencv::Result<BoxedRef<'_, Mat>> {
+            self.view().as_mat()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 6x4 BGR frame where every element is its own index, so any pixel says where it came from
+    fn numbered() -> Image<u16> {
+        Image::from_vec(6, 4, 3, (0..72).collect())
+    }
+
+    #[test]
+    #[should_panic(expected = "stride is shorter than a row")]
+    fn stride_below_a_row_is_rejected() {
+        ImageView::new(&[0u8; 64], 4, 4, 3, 11);
+    }
+
+    #[test]
+    #[should_panic(expected = "buffer too small")]
+    fn short_buffer_is_rejected() {
+        // 3 rows of stride 16, the last only needs 12 of them: 44, one short
+        ImageView::new(&[0u8; 43], 4, 3, 3, 16);
+    }
+
+    #[test]
+    #[should_panic(expected = "buffer too small")]
+    fn short_mutable_buffer_is_rejected() {
+        ImageViewMut::new(&mut [0u8; 11], 4, 1, 3, 12);
+    }
+
+    #[test]
+    fn last_row_doesnt_need_padding() {
+        let data = [7u8; 44];
+        let view = ImageView::new(&data, 4, 3, 3, 16);
+        assert_eq!(view.data().len(), 44);
+        assert_eq!(view.row(2), &[7; 12]);
+        assert!(!view.is_packed());
+    }
+
+    #[test]
+    fn sub_views_keep_the_parent_stride() {
+        let frame = numbered();
+        let view = frame.view();
+
+        // across: starts 2 pixels in, rows still 18 apart
+        let across = view.sub_view(2, 0, 3, 2);
+        assert_eq!(across.stride(), 18);
+        assert_eq!(across.row(0), &[6, 7, 8, 9, 10, 11, 12, 13, 14]);
+        assert_eq!(across.row(1), &[24, 25, 26, 27, 28, 29, 30, 31, 32]);
+
+        // down: whole rows starting at row 3
+        let down = view.sub_view(0, 3, 6, 1);
+        assert_eq!(down.stride(), 18);
+        assert!(down.is_packed() || down.height() == 1);
+        assert_eq!(down.row(0), frame.row(3));
+
+        // and both, through a sub view of a sub view
+        let inner = view
+            .sub_view(1, 1, 4, 3)
+            .sub_view_rect(Rect::new(1, 1, 2, 2));
+        assert_eq!((inner.width(), inner.height(), inner.stride()), (2, 2, 18));
+        assert_eq!(inner.row(0), &[42, 43, 44, 45, 46, 47]);
+        assert_eq!(inner.row(1), &[60, 61, 62, 63, 64, 65]);
+    }
+
+    #[test]
+    fn to_image_packs_a_strided_view() {
+        let frame = numbered();
+        let packed = frame.view().sub_view(4, 1, 2, 3).to_image();
+        assert_eq!(packed.stride(), 6);
+        assert_eq!(
+            packed.into_vec(),
+            [30, 31, 32, 33, 34, 35, 48, 49, 50, 51, 52, 53, 66, 67, 68, 69, 70, 71]
+        );
+    }
+
+    #[test]
+    fn rows_mut_stays_inside_the_rect() {
+        let mut frame = Image::<u8>::new(7, 5, 1);
+        {
+            let mut view = frame.view_mut();
+            let mut inner = view.sub_view_mut_rect(Rect::new(2, 1, 3, 3));
+            assert_eq!(inner.rows_mut().count(), 3);
+            for (y, row) in inner.rows_mut().enumerate() {
+                row.fill(y as u8 + 1);
+            }
+        }
+        assert_eq!(
+            frame.into_vec(),
+            [
+                0, 0, 0, 0, 0, 0, 0, //
+                0, 0, 1, 1, 1, 0, 0, //
+                0, 0, 2, 2, 2, 0, 0, //
+                0, 0, 3, 3, 3, 0, 0, //
+                0, 0, 0, 0, 0, 0, 0, //
+            ]
+        );
+    }
+
+    #[test]
+    fn rects_fit_and_expand_within_the_frame() {
+        assert!(Rect::new(0, 0, 10, 8).fits(10, 8));
+        assert!(Rect::new(3, 2, 7, 6).fits(10, 8));
+        assert!(!Rect::new(3, 2, 8, 6).fits(10, 8));
+        assert!(!Rect::new(0, 3, 10, 6).fits(10, 8));
+
+        // room on every side
+        assert_eq!(
+            Rect::new(4, 3, 2, 2).expand(1, 10, 8),
+            Rect::new(3, 2, 4, 4)
+        );
+        // clamped at the top left and at the bottom right
+        assert_eq!(
+            Rect::new(1, 0, 3, 2).expand(2, 10, 8),
+            Rect::new(0, 0, 6, 4)
+        );
+        assert_eq!(
+            Rect::new(7, 5, 3, 3).expand(2, 10, 8),
+            Rect::new(5, 3, 5, 5)
+        );
+        assert_eq!(
+            Rect::new(0, 0, 10, 8).expand(4, 10, 8),
+            Rect::new(0, 0, 10, 8)
+        );
+    }
+}
@@ -1,2 +1,28 @@
+pub mod args;
+pub mod batch;
+pub mod border;
+pub mod canny;
+pub mod cli;
+pub mod cpu_dispatch;
+pub mod edge;
+pub mod engine;
+pub mod gradient;
+#[cfg(feature = "opencv-io")]
+pub mod host;
+pub mod image;
+pub mod luma;
 pub mod mat_packet;
 pub mod my_arm_neon;
+pub mod my_scalar;
+#[cfg(target_arch = "x86_64")]
+pub mod my_x86_simd;
+pub mod pipeline;
+pub mod pipeline_config;
+pub mod playback;
+pub mod prefilter;
+pub mod strips;
+pub mod timing;
+#[cfg(feature = "opencv-io")]
+pub mod video;
+pub mod worker;
+pub mod y4m;
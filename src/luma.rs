@@ -0,0 +1,295 @@
+// Which luma coefficients grayscale uses, and whether it rounds or truncates.
+//
+// Every grayscale path (scalar, the lab3/lab4 engines, NEON, SSE2/AVX2) uses the same 14 bit fixed
+// point table, the same one cv::cvtColor uses for 8 bit images: weights sum to exactly 1 << 14 so
+// white stays 255, and the weighted sum of u8s gets widened to 32 bits before the shift. With
+// `round` BT.601 matches cvtColor(COLOR_BGR2GRAY) exactly, 8 bit weights can't (pure red comes out
+// 77 instead of 76).
+use crate::args;
+
+// fixed point fraction bits of the weights
+pub const LUMA_SHIFT: u32 = 14;
+// what the weights add up to
+pub const LUMA_ONE: u32 = 1 << LUMA_SHIFT;
+
+// R, G and B weights in 14 bit fixed point, always summing to LUMA_ONE
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LumaWeights {
+    pub r: u16,
+    pub g: u16,
+    pub b: u16,
+}
+
+impl LumaWeights {
+    // Scale arbitrary non-negative weights to sum to LUMA_ONE. The leftover from rounding down
+    // goes to the weights that lost the most, so the ratios stay as close as 14 bits allow.
+    // None if a weight is negative or not a number, they're all 0, or their sum overflows.
+    pub fn from_f32(r: f32, g: f32, b: f32) -> Option<LumaWeights> {
+        let weights = [r, g, b];
+        if weights.iter().any(|w| !w.is_finite() || *w < 0.0) {
+            return None;
+        }
+        let total: f32 = weights.iter().sum();
+        if !total.is_finite() || total <= 0.0 {
+            return None;
+        }
+
+        let scaled = weights.map(|w| w / total * LUMA_ONE as f32);
+        let mut fixed = scaled.map(|w| w.floor() as u16);
+        let mut order = [0, 1, 2];
+        order.sort_by(|&a, &b| {
+            let fraction = |i: usize| scaled[i] - scaled[i].floor();
+            fraction(b).total_cmp(&fraction(a))
+        });
+        // float rounding can also leave the floors a little over, then it comes off the weights
+        // that lost the least
+        let mut leftover = LUMA_ONE as i32 - fixed.iter().map(|&w| w as i32).sum::<i32>();
+        while leftover > 0 {
+            for &i in order.iter().take(leftover as usize) {
+                fixed[i] += 1;
+                leftover -= 1;
+            }
+        }
+        while leftover < 0 {
+            for &i in order.iter().rev() {
+                if leftover < 0 && fixed[i] > 0 {
+                    fixed[i] -= 1;
+                    leftover += 1;
+                }
+            }
+        }
+
+        Some(LumaWeights {
+            r: fixed[0],
+            g: fixed[1],
+            b: fixed[2],
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LumaProfile {
+    // SD video / JPEG (0.299, 0.587, 0.114)
+    Bt601,
+    // HD video (0.2126, 0.7152, 0.0722), what the labs have always used
+    #[default]
+    Bt709,
+    // UHD video (0.2627, 0.6780, 0.0593)
+    Bt2020,
+    // (r + g + b) / 3
+    Average,
+    Custom(LumaWeights),
+}
+
+impl LumaProfile {
+    pub fn name(self) -> &'static str {
+        match self {
+            LumaProfile::Bt601 => "bt601",
+            LumaProfile::Bt709 => "bt709",
+            LumaProfile::Bt2020 => "bt2020",
+            LumaProfile::Average => "average",
+            LumaProfile::Custom(_) => "custom",
+        }
+    }
+
+    // the named profiles, or `custom:R,G,B` with any non-negative weights (they get normalized)
+    pub fn from_name(name: &str) -> Option<LumaProfile> {
+        let name = name.to_ascii_lowercase();
+        if let Some(weights) = name.strip_prefix("custom:") {
+            let weights: Vec<f32> = weights
+                .split(',')
+                .map(|w| w.trim().parse())
+                .collect::<Result<_, _>>()
+                .ok()?;
+            return match weights[..] {
+                [r, g, b] => LumaWeights::from_f32(r, g, b).map(LumaProfile::Custom),
+                _ => None,
+            };
+        }
+
+        match name.as_str() {
+            "bt601" | "601" => Some(LumaProfile::Bt601),
+            "bt709" | "709" => Some(LumaProfile::Bt709),
+            "bt2020" | "2020" => Some(LumaProfile::Bt2020),
+            "average" | "mean" => Some(LumaProfile::Average),
+            _ => None,
+        }
+    }
+
+    // The weights in 14 bit fixed point. BT.601 is cvtColor's R2Y/G2Y/B2Y, the others are the
+    // coefficients times 16384 rounded so they still sum to 16384.
+    pub fn weights(self) -> LumaWeights {
+        match self {
+            LumaProfile::Bt601 => LumaWeights {
+                r: 4899,
+                g: 9617,
+                b: 1868,
+            },
+            LumaProfile::Bt709 => LumaWeights {
+                r: 3483,
+                g: 11718,
+                b: 1183,
+            },
+            LumaProfile::Bt2020 => LumaWeights {
+                r: 4304,
+                g: 11108,
+                b: 972,
+            },
+            LumaProfile::Average => LumaWeights {
+                r: 5461,
+                g: 5462,
+                b: 5461,
+            },
+            LumaProfile::Custom(w) => w,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Rounding {
+    // drop the fraction, like `as u8` always did
+    #[default]
+    Truncate,
+    // round half up
+    Nearest,
+}
+
+impl Rounding {
+    pub fn name(self) -> &'static str {
+        match self {
+            Rounding::Truncate => "truncate",
+            Rounding::Nearest => "round",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Rounding> {
+        match name.to_ascii_lowercase().as_str() {
+            "truncate" | "trunc" => Some(Rounding::Truncate),
+            "round" | "nearest" => Some(Rounding::Nearest),
+            _ => None,
+        }
+    }
+
+    // added to the fixed point sum before shifting it down
+    pub fn bias(self) -> u32 {
+        match self {
+            Rounding::Truncate => 0,
+            Rounding::Nearest => LUMA_ONE / 2,
+        }
+    }
+}
+
+// everything grayscale needs to know
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Luma {
+    pub profile: LumaProfile,
+    pub rounding: Rounding,
+}
+
+impl Luma {
+    // `--luma bt601|bt709|bt2020|average|custom:R,G,B --rounding truncate|round`
+    pub fn from_args(args: &[String]) -> Result<Luma, String> {
+        let profile = match args::option_value(args, "luma") {
+            None => LumaProfile::default(),
+            Some(name) => LumaProfile::from_name(name).ok_or_else(|| {
+                format!(
+                    "unknown luma profile '{}', expected bt601, bt709, bt2020, average or custom:R,G,B",
+                    name
+                )
+            })?,
+        };
+        let rounding = match args::option_value(args, "rounding") {
+            None => Rounding::default(),
+            Some(name) => Rounding::from_name(name).ok_or_else(|| {
+                format!("unknown rounding '{}', expected truncate or round", name)
+            })?,
+        };
+        Ok(Luma { profile, rounding })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sum(weights: LumaWeights) -> u32 {
+        weights.r as u32 + weights.g as u32 + weights.b as u32
+    }
+
+    #[test]
+    fn weights_sum_to_one() {
+        for profile in [
+            LumaProfile::Bt601,
+            LumaProfile::Bt709,
+            LumaProfile::Bt2020,
+            LumaProfile::Average,
+        ] {
+            assert_eq!(sum(profile.weights()), LUMA_ONE, "{}", profile.name());
+        }
+    }
+
+    #[test]
+    fn named_weights_match_their_coefficients() {
+        // bt601 straight from the coefficients comes out as cvtColor's table
+        assert_eq!(
+            LumaWeights::from_f32(0.299, 0.587, 0.114),
+            Some(LumaProfile::Bt601.weights())
+        );
+        assert_eq!(
+            LumaWeights::from_f32(0.2126, 0.7152, 0.0722),
+            Some(LumaProfile::Bt709.weights())
+        );
+        assert_eq!(
+            LumaWeights::from_f32(0.2627, 0.6780, 0.0593),
+            Some(LumaProfile::Bt2020.weights())
+        );
+    }
+
+    #[test]
+    fn custom_weights_get_normalized() {
+        let Some(LumaProfile::Custom(weights)) = LumaProfile::from_name("custom:1,1,2") else {
+            panic!("custom:1,1,2 didn't parse");
+        };
+        assert_eq!((weights.r, weights.g, weights.b), (4096, 4096, 8192));
+        assert_eq!(
+            LumaWeights::from_f32(1.0, 0.0, 0.0).map(sum),
+            Some(LUMA_ONE)
+        );
+        assert_eq!(LumaWeights::from_f32(0.0, 0.0, 0.0), None);
+        assert_eq!(LumaWeights::from_f32(-1.0, 1.0, 1.0), None);
+        assert_eq!(LumaProfile::from_name("custom:1,2"), None);
+    }
+
+    #[test]
+    fn extreme_weights_still_sum_to_one() {
+        let extremes = [
+            (f32::MAX, 1.0, 0.0),
+            (f32::MAX / 2.0, f32::MAX / 4.0, f32::MAX / 4.0),
+            (f32::MIN_POSITIVE, f32::MIN_POSITIVE, f32::MIN_POSITIVE),
+            (1e-45, 0.0, 3e-45),
+            (1.0, 1e-30, 1e30),
+            (1.0, 1.0, 1.0),
+            (1.0, 2.0, 3.0),
+        ];
+        for (r, g, b) in extremes {
+            let weights = LumaWeights::from_f32(r, g, b);
+            assert_eq!(weights.map(sum), Some(LUMA_ONE), "{} {} {}", r, g, b);
+        }
+
+        // and a sweep of ordinary ratios, whatever the floats round to
+        for i in 1..2000 {
+            let (r, g, b) = (i as f32 * 0.37, 1.0 / i as f32, (i % 7) as f32 * 0.1);
+            assert_eq!(
+                LumaWeights::from_f32(r, g, b).map(sum),
+                Some(LUMA_ONE),
+                "{} {} {}",
+                r,
+                g,
+                b
+            );
+        }
+
+        // the sum itself overflows
+        assert_eq!(LumaWeights::from_f32(f32::MAX, f32::MAX, f32::MAX), None);
+    }
+}
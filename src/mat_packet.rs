@@ -1,14 +1,41 @@
-use opencv::{
-    core::{self, CV_8UC1, CV_8UC3},
-    prelude::*,
-};
+#[cfg(feature = "opencv-io")]
+use opencv::{core, prelude::*};
 use serde::{Deserialize, Serialize};
 
+use crate::image::{Image, ImageView};
+
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::time::{Duration, Instant};
+
 pub const TASK_PORT: &str = "5555"; // For sending tasks
 pub const RESULT_PORT: &str = "5556"; // For receiving results
 pub const HOST_IP: &str = "10.0.1.152"; // Replace with host's IP
 
+// opencv type codes for the formats we send, same values as opencv::core::CV_8UC1 etc.
+// Defined here so the wire format (and the workers) don't need opencv.
+pub const CV_8UC1: i32 = 0;
+pub const CV_8UC3: i32 = 16;
+pub const CV_8UC4: i32 = 24;
+pub const CV_16UC1: i32 = 2;
+pub const CV_16UC3: i32 = 18;
+pub const CV_16UC4: i32 = 26;
+
+// (channels, bytes per channel) for the type codes above
+pub fn type_layout(mat_type: i32) -> Option<(usize, usize)> {
+    match mat_type {
+        CV_8UC1 => Some((1, 1)),
+        CV_8UC3 => Some((3, 1)),
+        CV_8UC4 => Some((4, 1)),
+        CV_16UC1 => Some((1, 2)),
+        CV_16UC3 => Some((3, 2)),
+        CV_16UC4 => Some((4, 2)),
+        _ => None,
+    }
+}
+
 use std::cmp::Ordering;
+use std::fmt;
 
 #[derive(Serialize, Deserialize, Debug)] // Include Debug for better debug output
 pub struct MatMessage {
@@ -18,6 +45,9 @@ pub struct MatMessage {
     pub number: u64,    // the frame number
     pub send_time: i32, // should be time/Instant type though
     pub data: Vec<u8>,
+    // the host's chain as JSON, for the workers to run. Packets from before this field don't
+    // decode, and older workers just ignore it, so hosts and workers have to be the same version.
+    pub pipeline: Option<String>,
 }
 
 // traits to support comparing (and thus ordering) the packets by frame number
@@ -41,48 +71,259 @@ impl PartialOrd for MatMessage {
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PacketError {
+    InvalidDimensions,
+    UnsupportedType(i32),
+    SizeMismatch,
+}
+
+impl fmt::Display for PacketError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PacketError::InvalidDimensions => write!(f, "Invalid matrix dimensions"),
+            PacketError::UnsupportedType(mat_type) => {
+                write!(f, "Unsupported matrix type {}", mat_type)
+            }
+            PacketError::SizeMismatch => {
+                write!(f, "Matrix size does not match its data buffer length")
+            }
+        }
+    }
+}
+
+impl std::error::Error for PacketError {}
+
+impl MatMessage {
+    // Test Assertions: check the header against the data buffer, returns the channel count and
+    // bytes per channel
+    fn validate(&self) -> Result<(usize, usize), PacketError> {
+        // Validate dimensions
+        if self.rows <= 0 || self.cols <= 0 {
+            //dbg!(&self.rows, &self.cols, &self.mat_type, &self.number);
+            return Err(PacketError::InvalidDimensions);
+        }
+
+        // Validate data size expectations
+        let (channels, element_size) =
+            type_layout(self.mat_type).ok_or(PacketError::UnsupportedType(self.mat_type))?;
+        let expected_size = self.rows as usize * self.cols as usize * channels * element_size;
+        if self.data.len() != expected_size {
+            return Err(PacketError::SizeMismatch);
+        }
+
+        Ok((channels, element_size))
+    }
+
+    // what a worker sends back for a frame it couldn't process, so the host can move past it
+    pub fn failed(number: u64) -> MatMessage {
+        MatMessage {
+            rows: 0,
+            cols: 0,
+            mat_type: CV_8UC1,
+            number,
+            send_time: 0,
+            data: Vec::new(),
+            pipeline: None,
+        }
+    }
+
+    pub fn is_failed(&self) -> bool {
+        self.rows == 0 && self.cols == 0 && self.data.is_empty()
+    }
+
+    pub fn is_16bit(&self) -> bool {
+        type_layout(self.mat_type).is_some_and(|(_, element_size)| element_size == 2)
+    }
+
+    // borrow the pixel data of an 8 bit packet as an image (no copy)
+    pub fn view(&self) -> Result<ImageView<'_, u8>, PacketError> {
+        match self.validate()? {
+            (channels, 1) => Ok(ImageView::packed(
+                &self.data,
+                self.cols as usize,
+                self.rows as usize,
+                channels,
+            )),
+            _ => Err(PacketError::UnsupportedType(self.mat_type)),
+        }
+    }
+
+    // copy out the pixels of a 16 bit packet (the bytes aren't necessarily u16 aligned, so no view)
+    pub fn image16(&self) -> Result<Image<u16>, PacketError> {
+        match self.validate()? {
+            (channels, 2) => {
+                let data = self
+                    .data
+                    .chunks_exact(2)
+                    .map(|bytes| u16::from_ne_bytes([bytes[0], bytes[1]]))
+                    .collect();
+                Ok(Image::from_vec(
+                    self.cols as usize,
+                    self.rows as usize,
+                    channels,
+                    data,
+                ))
+            }
+            _ => Err(PacketError::UnsupportedType(self.mat_type)),
+        }
+    }
+}
+
+// how long the host waits on one frame while later ones are already back before giving up on
+// it (a packet the worker couldn't even decode never gets an answer), far longer than any Pi takes
+pub const RESULT_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Results from the workers put back in frame order. Frames the workers reported as failed, and
+// ones still missing RESULT_TIMEOUT after the frame before them came out while later ones are
+// already waiting, come out as Err(number) so one bad frame can't hold up the rest.
+pub struct Reorder {
+    next: u64,
+    buffer: BinaryHeap<Reverse<MatMessage>>,
+    // when `next` became the frame being waited on
+    waiting_since: Instant,
+    timeout: Duration,
+}
+
+impl Reorder {
+    pub fn new(timeout: Duration) -> Reorder {
+        Reorder::new_at(timeout, Instant::now())
+    }
+
+    fn new_at(timeout: Duration, now: Instant) -> Reorder {
+        Reorder {
+            next: 0,
+            buffer: BinaryHeap::new(),
+            waiting_since: now,
+            timeout,
+        }
+    }
+
+    // the frame it's waiting for, every one before it has come out
+    pub fn next_number(&self) -> u64 {
+        self.next
+    }
+
+    // false (and the message is dropped) if that frame already came out
+    pub fn push(&mut self, msg: MatMessage) -> bool {
+        if msg.number < self.next {
+            return false;
+        }
+        self.buffer.push(Reverse(msg));
+        true
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buffer.is_empty()
+    }
+
+    // whether `next` has been waited on for longer than the timeout
+    pub fn is_stalled(&self) -> bool {
+        self.is_stalled_at(Instant::now())
+    }
+
+    fn is_stalled_at(&self, now: Instant) -> bool {
+        now.duration_since(self.waiting_since) >= self.timeout
+    }
+
+    // The next frame if it's here, Err(number) for one that failed or timed out
+    pub fn pop(&mut self) -> Option<Result<MatMessage, u64>> {
+        self.pop_at(Instant::now())
+    }
+
+    fn pop_at(&mut self, now: Instant) -> Option<Result<MatMessage, u64>> {
+        let front = self.buffer.peek()?.0.number;
+        if front != self.next {
+            // a later frame is back but this one isn't, give it until the timeout
+            if !self.is_stalled_at(now) {
+                return None;
+            }
+            self.next += 1;
+            return Some(Err(self.next - 1));
+        }
+
+        let Reverse(msg) = self.buffer.pop()?;
+        self.next += 1;
+        self.waiting_since = now;
+        if msg.is_failed() {
+            Some(Err(msg.number))
+        } else {
+            Some(Ok(msg))
+        }
+    }
+}
+
 // Conversion Traits/functions
-pub fn from_mat(mat: &core::Mat, number: u64, send_time: i32) -> Result<MatMessage, opencv::Error> {
-    Ok(MatMessage {
-        rows: mat.rows(),
-        cols: mat.cols(),
-        mat_type: mat.typ(),
+pub fn from_image(image: &ImageView<u8>, number: u64, send_time: i32) -> MatMessage {
+    let mat_type = match image.channels() {
+        1 => CV_8UC1,
+        3 => CV_8UC3,
+        4 => CV_8UC4,
+        channels => panic!("no packet format for {} channel images", channels),
+    };
+
+    MatMessage {
+        rows: image.height() as i32,
+        cols: image.width() as i32,
+        mat_type,
+        number,
+        send_time,
+        data: image.to_image().into_vec(),
+        pipeline: None,
+    }
+}
+
+// same for 16 bit images, the samples go out in native byte order like opencv's own buffers
+pub fn from_image16(image: &ImageView<u16>, number: u64, send_time: i32) -> MatMessage {
+    let mat_type = match image.channels() {
+        1 => CV_16UC1,
+        3 => CV_16UC3,
+        4 => CV_16UC4,
+        channels => panic!("no packet format for {} channel images", channels),
+    };
+
+    MatMessage {
+        rows: image.height() as i32,
+        cols: image.width() as i32,
+        mat_type,
         number,
         send_time,
-        data: mat.data_bytes()?.to_vec(),
-    })
+        data: image
+            .rows()
+            .flatten()
+            .flat_map(|value| value.to_ne_bytes())
+            .collect(),
+        pipeline: None,
+    }
 }
 
+#[cfg(feature = "opencv-io")]
+pub fn from_mat(mat: &core::Mat, number: u64, send_time: i32) -> Result<MatMessage, opencv::Error> {
+    // goes through an image view so ROIs and padded rows get packed instead of copied verbatim
+    match type_layout(mat.typ()) {
+        Some((_, 1)) => Ok(from_image(&ImageView::from_mat(mat)?, number, send_time)),
+        Some(_) => Ok(from_image16(&ImageView::from_mat(mat)?, number, send_time)),
+        None => Err(opencv::Error::new(
+            core::StsUnsupportedFormat,
+            PacketError::UnsupportedType(mat.typ()).to_string(),
+        )),
+    }
+}
+
+#[cfg(feature = "opencv-io")]
 impl TryFrom<&MatMessage> for opencv::core::Mat {
     type Error = opencv::Error;
 
     fn try_from(msg: &MatMessage) -> Result<Self, Self::Error> {
-        // Test Assertions:
-        // Validate dimensions
-        if msg.rows <= 0 || msg.cols <= 0 {
-            //dbg!(&msg.rows, &msg.cols, &msg.mat_type, &msg.number);
-            //dbg!(&msg.data[0..8]);
-            return Err(opencv::Error::new(
-                opencv::core::StsOutOfRange,
-                "Invalid matrix dimensions",
-            ));
+        if let Err(err) = msg.validate() {
+            let code = match err {
+                PacketError::SizeMismatch => opencv::core::StsUnmatchedSizes,
+                PacketError::UnsupportedType(_) => opencv::core::StsUnsupportedFormat,
+                PacketError::InvalidDimensions => opencv::core::StsOutOfRange,
+            };
+            return Err(opencv::Error::new(code, err.to_string()));
         }
 
-        // Validate data size expectations
-        let size = match msg.mat_type {
-            CV_8UC3 => 3,
-            CV_8UC1 => 1,
-            _ => 0,
-        };
-        let expected_size = (msg.rows * msg.cols * size) as usize;
-        if msg.data.len() != expected_size {
-            return Err(opencv::Error::new(
-                opencv::core::StsUnmatchedSizes,
-                "Matrix size does not match its data buffer length",
-            ));
-        }
-        // Test Assertions End
-
         unsafe {
             opencv::core::Mat::new_rows_cols_with_data_unsafe_def(
                 msg.rows,
@@ -93,3 +334,157 @@ impl TryFrom<&MatMessage> for opencv::core::Mat {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a one pixel grey frame numbered `number`
+    fn frame(number: u64) -> MatMessage {
+        from_image(&ImageView::packed(&[number as u8], 1, 1, 1), number, 0)
+    }
+
+    fn numbers(results: Vec<Result<MatMessage, u64>>) -> Vec<Result<u64, u64>> {
+        results
+            .into_iter()
+            .map(|result| result.map(|msg| msg.number))
+            .collect()
+    }
+
+    #[test]
+    fn reorder_puts_frames_back_in_order() {
+        let now = Instant::now();
+        let mut frames = Reorder::new_at(RESULT_TIMEOUT, now);
+        for number in [2, 0, 3] {
+            assert!(frames.push(frame(number)));
+        }
+        let out: Vec<_> = std::iter::from_fn(|| frames.pop_at(now)).collect();
+        assert_eq!(numbers(out), [Ok(0)]);
+
+        // 2 and 3 wait for 1
+        frames.push(frame(1));
+        let out: Vec<_> = std::iter::from_fn(|| frames.pop_at(now)).collect();
+        assert_eq!(numbers(out), [Ok(1), Ok(2), Ok(3)]);
+        assert!(frames.is_empty());
+
+        // one that's already out gets turned away
+        assert!(!frames.push(frame(2)));
+    }
+
+    #[test]
+    fn reorder_skips_failed_frames() {
+        let now = Instant::now();
+        let mut frames = Reorder::new_at(RESULT_TIMEOUT, now);
+        frames.push(frame(0));
+        frames.push(MatMessage::failed(1));
+        frames.push(frame(2));
+        let out: Vec<_> = std::iter::from_fn(|| frames.pop_at(now)).collect();
+        assert_eq!(numbers(out), [Ok(0), Err(1), Ok(2)]);
+        assert_eq!(frames.next_number(), 3);
+    }
+
+    #[test]
+    fn reorder_gives_up_on_missing_frames_after_the_timeout() {
+        let start = Instant::now();
+        let timeout = Duration::from_secs(5);
+        let mut frames = Reorder::new_at(timeout, start);
+        frames.push(frame(0));
+        assert_eq!(numbers(vec![frames.pop_at(start).unwrap()]), [Ok(0)]);
+
+        // 1 and 2 never come back, 3 and 4 do
+        frames.push(frame(3));
+        frames.push(frame(4));
+        let almost = start + timeout - Duration::from_millis(1);
+        assert!(frames.pop_at(almost).is_none());
+        assert!(!frames.is_stalled_at(almost));
+
+        let late = start + timeout;
+        assert!(frames.is_stalled_at(late));
+        let out: Vec<_> = std::iter::from_fn(|| frames.pop_at(late)).collect();
+        assert_eq!(numbers(out), [Err(1), Err(2), Ok(3), Ok(4)]);
+
+        // and the clock starts over for the next one
+        frames.push(frame(6));
+        assert!(frames.pop_at(late).is_none());
+        assert!(frames.pop_at(late + timeout).is_some());
+    }
+
+    #[test]
+    fn type_layouts() {
+        assert_eq!(type_layout(CV_8UC1), Some((1, 1)));
+        assert_eq!(type_layout(CV_8UC3), Some((3, 1)));
+        assert_eq!(type_layout(CV_8UC4), Some((4, 1)));
+        assert_eq!(type_layout(CV_16UC1), Some((1, 2)));
+        assert_eq!(type_layout(CV_16UC3), Some((3, 2)));
+        assert_eq!(type_layout(CV_16UC4), Some((4, 2)));
+        // CV_32FC1, and something that isn't a type at all
+        assert_eq!(type_layout(5), None);
+        assert_eq!(type_layout(-1), None);
+    }
+
+    #[test]
+    fn payload_has_to_match_the_header() {
+        let bgr16 = Image::<u16>::from_vec(3, 2, 3, (0..18).collect());
+        let mut msg = from_image16(&bgr16.view(), 0, 0);
+        assert_eq!(msg.data.len(), 3 * 2 * 3 * 2);
+        assert_eq!(msg.validate(), Ok((3, 2)));
+
+        // a byte short, and the right length for 8 bit samples instead of 16
+        msg.data.pop();
+        assert_eq!(msg.image16(), Err(PacketError::SizeMismatch));
+        msg.data.truncate(18);
+        assert_eq!(msg.image16(), Err(PacketError::SizeMismatch));
+
+        let mut msg = from_image(&ImageView::packed(&[0; 12], 2, 2, 3), 0, 0);
+        msg.mat_type = CV_8UC4;
+        assert_eq!(msg.view().err(), Some(PacketError::SizeMismatch));
+        msg.mat_type = 5;
+        assert_eq!(msg.view().err(), Some(PacketError::UnsupportedType(5)));
+        msg.rows = 0;
+        assert_eq!(msg.view().err(), Some(PacketError::InvalidDimensions));
+        assert!(MatMessage::failed(3).view().is_err());
+    }
+
+    #[test]
+    fn sixteen_bit_images_round_trip() {
+        for channels in [1, 3, 4] {
+            let image = Image::<u16>::from_vec(
+                5,
+                3,
+                channels,
+                (0..15 * channels as u16)
+                    .map(|v| v.wrapping_mul(4099))
+                    .collect(),
+            );
+            let msg = from_image16(&image.view(), 7, 0);
+            assert!(msg.is_16bit());
+            assert_eq!(type_layout(msg.mat_type), Some((channels, 2)));
+            assert_eq!(msg.image16(), Ok(image.clone()));
+            // and it's not an 8 bit packet
+            assert_eq!(
+                msg.view().err(),
+                Some(PacketError::UnsupportedType(msg.mat_type))
+            );
+        }
+
+        // a sub view gets packed on the way in
+        let image = Image::<u16>::from_vec(4, 4, 1, (0..16).collect());
+        let msg = from_image16(&image.view().sub_view(1, 1, 2, 2), 0, 0);
+        assert_eq!(msg.image16().unwrap().into_vec(), [5, 6, 9, 10]);
+    }
+
+    #[test]
+    fn bgra_packets_view_as_four_channels() {
+        let pixels: Vec<u8> = (0..24).collect();
+        let msg = from_image(&ImageView::packed(&pixels, 3, 2, 4), 1, 0);
+        assert_eq!((msg.mat_type, msg.is_16bit()), (CV_8UC4, false));
+
+        let view = msg.view().unwrap();
+        assert_eq!((view.width(), view.height(), view.channels()), (3, 2, 4));
+        assert_eq!(view.row(1), &pixels[12..]);
+        assert_eq!(
+            msg.image16().err(),
+            Some(PacketError::UnsupportedType(CV_8UC4))
+        );
+    }
+}
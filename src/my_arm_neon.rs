@@ -1,320 +1,1252 @@
-use opencv::{boxed_ref::BoxedRef, core::Rect, prelude::*};
-use opencv::{
-    core::{Mat, MatTrait, MatTraitConst, CV_8UC1},
-    Result,
-};
-use rayon::prelude::*;
+use crate::args;
+use crate::border::BorderMode;
+use crate::canny::CannyOptions;
+use crate::cpu_dispatch::{self, SimdLevel};
+use crate::edge::EdgeOperator;
+use crate::image::{Image, ImageView, ImageViewMut, Rect};
+use crate::luma::Luma;
+#[cfg(target_arch = "aarch64")]
+use crate::luma::{Rounding, LUMA_SHIFT};
+use crate::my_scalar;
+#[cfg(target_arch = "x86_64")]
+use crate::my_x86_simd;
+use crate::pipeline::Pipeline;
+use crate::prefilter::{self, PreFilter};
+use crate::strips;
 
 #[cfg(target_arch = "aarch64")]
 use std::arch::aarch64::*;
 
-const NUM_THREADS: usize = 4;
-
-pub fn do_frame(frame: &Mat) -> Result<Mat> {
-
-    // Calculate the height for each smaller matrix
-    let split_height = frame.rows() / NUM_THREADS as i32;
-
-    // Create the smaller matrices with the specified overlaps
-    let mat1 = Mat::roi(frame, Rect::new(0, 0, frame.cols(), split_height + 1))?;
-    let mat2 = Mat::roi(
-        frame,
-        Rect::new(0, split_height - 1, frame.cols(), split_height + 2),
-    )?;
-    let mat3 = Mat::roi(
-        frame,
-        Rect::new(0, split_height * 2 - 1, frame.cols(), split_height + 2),
-    )?;
-    let mat4 = Mat::roi(
-        frame,
-        Rect::new(0, split_height * 3 - 1, frame.cols(), split_height + 1),
-    )?;
-
-
-    //move these to parallel
-    let mats = vec![mat1, mat2, mat3, mat4];
-    let sobel_results = do_sobel_parallel(&mats)?;
-    //end parallel
-
-
-    // Trim the results
-    let mat1_trimmed = Mat::roi(
-        &sobel_results[0],
-        Rect::new(
-            1,
-            1,
-            sobel_results[0].cols() - 2,
-            sobel_results[0].rows() - 2,
-        ),
-    )?;
-    let mat2_trimmed = Mat::roi(
-        &sobel_results[1],
-        Rect::new(
-            1,
-            1,
-            sobel_results[1].cols() - 2,
-            sobel_results[1].rows() - 2,
-        ),
-    )?;
-    let mat3_trimmed = Mat::roi(
-        &sobel_results[2],
-        Rect::new(
-            1,
-            1,
-            sobel_results[2].cols() - 2,
-            sobel_results[2].rows() - 2,
-        ),
-    )?;
-    let mat4_trimmed = Mat::roi(
-        &sobel_results[3],
-        Rect::new(
-            1,
-            1,
-            sobel_results[3].cols() - 2,
-            sobel_results[3].rows() - 1,
-        ),
-    )?;
-
-    // Create a new Mat for the combined result
-    let combined_height =
-        mat1_trimmed.rows() + mat2_trimmed.rows() + mat3_trimmed.rows() + mat4_trimmed.rows(); // Total height
-    let mut combined_frame =
-        unsafe { Mat::new_rows_cols(combined_height, mat1_trimmed.cols(), CV_8UC1) }?; // Create an empty matrix of the appropriate size
-
-    // Copy the data from each matrix into the combined frame
-    let mut current_row = 0;
-
-    for mat in &[mat1_trimmed, mat2_trimmed, mat3_trimmed, mat4_trimmed] {
-        // Create a ROI for the current position in the combined frame
-        let mut roi = Mat::roi_mut(
-            &mut combined_frame,
-            Rect::new(0, current_row, mat.cols(), mat.rows()),
-        )?;
-
-        // Copy the data
-        mat.copy_to(&mut roi)?;
-
-        current_row += mat.rows(); // Move to the next position
-    }
-
-
-    Ok(combined_frame)
-}
-
-// Process Sobel in parallel
-pub fn do_sobel_parallel(mats: &[BoxedRef<'_, Mat>]) -> Result<Vec<Mat>> {
-    let results: Vec<Mat> = mats
-        .par_iter()
-        .map(|mat| to442_sobel_simd(&to442_grayscale_simd(mat).unwrap()).unwrap())
-        .collect();
-
-    // Sequential implementation (still splits the frame)
-    // let results = vec![to442_sobel_simd(&to442_grayscale_simd(&mats[0]).unwrap()).unwrap(),
-    // to442_sobel_simd(&to442_grayscale_simd(&mats[1]).unwrap()).unwrap(),
-    // to442_sobel_simd(&to442_grayscale_simd(&mats[2]).unwrap()).unwrap(),
-    // to442_sobel_simd(&to442_grayscale_simd(&mats[3]).unwrap()).unwrap()];
-
-    Ok(results)
-}
-
-pub fn to442_grayscale_simd(frame: &opencv::mod_prelude::BoxedRef<'_, Mat>) -> Result<Mat> {
-
-    // Convert the frame reference to a mutable slice of `u8`
-    // let bgr_data: &[u8] = unsafe {
-    //     std::slice::from_raw_parts(frame.data(), (frame.rows() * frame.cols() * 3) as usize)
-    // // unsurprisingly, this caused a ton of errors.
-    // };
-    let bgr_data = frame.data_bytes()?;
+// rows of context the sobel kernel needs above and below each strip
+pub const SOBEL_HALO: usize = 1;
+
+// How a strip gets from BGR to sobel output. Both give identical results.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Backend {
+    // grayscale the whole strip into an intermediate image, then sobel that
+    #[default]
+    TwoStage,
+    // grayscale three rows at a time into a ring buffer and sobel straight from it, so the grey
+    // intermediate never exists (less memory traffic, which the pi is short on). A pre-filter
+    // needs the whole grey strip, so with one of those this falls back to two stage.
+    Fused,
+}
+
+impl Backend {
+    pub fn name(self) -> &'static str {
+        match self {
+            Backend::TwoStage => "two-stage",
+            Backend::Fused => "fused",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Backend> {
+        match name.to_ascii_lowercase().as_str() {
+            "two-stage" | "twostage" | "two_stage" => Some(Backend::TwoStage),
+            "fused" => Some(Backend::Fused),
+            _ => None,
+        }
+    }
+
+    // CPE442_BACKEND=two-stage|fused picks the backend without touching the code (like
+    // CPE442_SIMD), anything else gives the default
+    pub fn from_env() -> Backend {
+        std::env::var("CPE442_BACKEND")
+            .ok()
+            .and_then(|name| Backend::from_name(&name))
+            .unwrap_or_default()
+    }
+}
+
+// What do_frame_with outputs
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Mode {
+    // the edge operator's clamped magnitude
+    #[default]
+    Sobel,
+    // thinned, thresholded edges (see canny.rs)
+    Canny(CannyOptions),
+}
+
+impl Mode {
+    // `--mode sobel|canny`, plus the canny thresholds (see CannyOptions::from_args)
+    pub fn from_args(args: &[String]) -> Result<Mode, String> {
+        match args::option_value(args, "mode") {
+            None => Ok(Mode::default()),
+            Some(name) => match name.to_ascii_lowercase().as_str() {
+                "sobel" => Ok(Mode::Sobel),
+                "canny" => Ok(Mode::Canny(CannyOptions::from_args(args)?)),
+                _ => Err(format!("unknown mode '{}', expected sobel or canny", name)),
+            },
+        }
+    }
+}
+
+// knobs for do_frame_with, Default gives the same result as do_frame
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FrameOptions {
+    pub strips: usize,
+    pub border: BorderMode,
+    pub backend: Backend,
+    pub operator: EdgeOperator,
+    pub mode: Mode,
+    pub luma: Luma,
+    pub prefilter: PreFilter,
+}
+
+impl FrameOptions {
+    // defaults plus whatever the command line overrides (--operator, --mode, --luma, ...)
+    pub fn from_args(args: &[String]) -> Result<FrameOptions, String> {
+        Ok(FrameOptions {
+            operator: EdgeOperator::from_args(args)?,
+            mode: Mode::from_args(args)?,
+            luma: Luma::from_args(args)?,
+            prefilter: PreFilter::from_args(args)?,
+            ..Default::default()
+        })
+    }
+
+    // rows of context each strip needs for the whole chain, pre-filter included
+    pub fn halo(&self) -> usize {
+        Pipeline::from_options(self).halo()
+    }
+}
+
+impl Default for FrameOptions {
+    fn default() -> Self {
+        FrameOptions {
+            strips: strips::default_strip_count(),
+            border: BorderMode::default(),
+            backend: Backend::from_env(),
+            operator: EdgeOperator::default(),
+            mode: Mode::default(),
+            luma: Luma::default(),
+            prefilter: PreFilter::default(),
+        }
+    }
+}
+
+// grayscale + sobel over the whole frame, one strip per rayon worker
+pub fn do_frame(frame: &ImageView<u8>) -> Image<u8> {
+    do_frame_with(frame, &FrameOptions::default())
+}
+
+// see Pipeline::from_options for the stages each set of options turns into
+pub fn do_frame_with(frame: &ImageView<u8>, options: &FrameOptions) -> Image<u8> {
+    Pipeline::from_options(options).run(frame)
+}
+
+// grayscale and then the pre-filter, if there is one
+pub fn to442_grey_filtered(frame: &ImageView<u8>, options: &FrameOptions) -> Image<u8> {
+    let grey = to442_grayscale_simd(frame, options.luma);
+    match options.prefilter {
+        PreFilter::None => grey,
+        filter => prefilter::apply(&grey.view(), options.border, filter),
+    }
+}
+
+// Edge detection on just `rect` of the frame, written back in place as grey (alpha is left alone)
+// with the rest of the frame untouched. The pixels just outside the rect are still read as the
+// kernels' context, so the rect's edges come out the same as with do_frame_with on the whole frame.
+// Not for Mode::Canny: hysteresis follows edges across the whole frame, and a halo can't hold
+// everything an edge in the rect might connect through.
+pub fn process_roi(frame: &mut ImageViewMut<u8>, rect: Rect) {
+    process_roi_with(frame, rect, &FrameOptions::default())
+}
+
+pub fn process_roi_with(frame: &mut ImageViewMut<u8>, rect: Rect, options: &FrameOptions) {
     assert!(
-        bgr_data.len() % 12 == 0,
-        "Input data length must be a multiple of 12"
+        rect.fits(frame.width(), frame.height()),
+        "roi {:?} is outside the {}x{} frame",
+        rect,
+        frame.width(),
+        frame.height()
+    );
+    assert!(
+        !matches!(options.mode, Mode::Canny(_)),
+        "canny needs the whole frame, it can't run on just a roi"
+    );
+    let context = rect.expand(options.halo(), frame.width(), frame.height());
+    let edges = do_frame_with(&frame.as_view().sub_view_rect(context), options);
+    let edges = edges.view().sub_view(
+        rect.x - context.x,
+        rect.y - context.y,
+        rect.width,
+        rect.height,
     );
 
-    // convert the output to a mutable slice
-    let output: Mat =
-        unsafe { opencv::core::Mat::new_rows_cols(frame.rows(), frame.cols(), CV_8UC1)? };
-    let out_ptr: &mut [u8] = unsafe {
-        std::slice::from_raw_parts_mut(
-            output.data() as *mut u8,
-            (frame.rows() * frame.cols()) as usize,
-        )
-    };
+    let channels = frame.channels();
+    let colour = channels.min(3);
+    let mut roi = frame.sub_view_mut_rect(rect);
+    for y in 0..rect.height {
+        for (pixel, &edge) in roi.row_mut(y).chunks_exact_mut(channels).zip(edges.row(y)) {
+            pixel[..colour].fill(edge);
+        }
+    }
+}
+
+// BGR or BGRA to grey, single channel input is already grey and just gets copied
+pub fn to442_grayscale_simd(frame: &ImageView<u8>, luma: Luma) -> Image<u8> {
+    assert_grayscale_input(frame.channels());
+
+    let mut output = Image::new(frame.width(), frame.height(), 1);
+    for (in_row, out_row) in frame.rows().zip(output.rows_mut()) {
+        grayscale_row(in_row, frame.channels(), out_row, luma);
+    }
+
+    output
+}
+
+// 16 bit grey/BGR/BGRA (16 bit PNGs and TIFFs) down to 8 bit grey. No SIMD version, these
+// don't come in at video rates.
+pub fn to442_grayscale16(frame: &ImageView<u16>, luma: Luma) -> Image<u8> {
+    assert_grayscale_input(frame.channels());
+
+    let mut output = Image::new(frame.width(), frame.height(), 1);
+    for (in_row, out_row) in frame.rows().zip(output.rows_mut()) {
+        my_scalar::grayscale_u16(in_row, frame.channels(), out_row, luma);
+    }
+
+    output
+}
+
+// do_frame_with for 16 bit input: convert to 8 bit grey first, the rest is the same
+pub fn do_frame16_with(frame: &ImageView<u16>, options: &FrameOptions) -> Image<u8> {
+    do_frame_with(&to442_grayscale16(frame, options.luma).view(), options)
+}
+
+// 16 bit input scaled down to 8 bits with the channels kept, for chains that start with their
+// own grayscale stage (a --pipeline file)
+pub fn to442_narrow16(frame: &ImageView<u16>) -> Image<u8> {
+    let mut output = Image::new(frame.width(), frame.height(), frame.channels());
+    for (in_row, out_row) in frame.rows().zip(output.rows_mut()) {
+        my_scalar::narrow_u16(in_row, out_row);
+    }
+
+    output
+}
+
+pub(crate) fn assert_grayscale_input(channels: usize) {
+    assert!(
+        matches!(channels, 1 | 3 | 4),
+        "grayscale expects grey, BGR or BGRA input, got {} channels",
+        channels
+    );
+}
+
+// output has the same size as the input, `border` decides what the edge pixels see
+pub fn to442_sobel_simd(
+    frame: &ImageView<u8>,
+    border: BorderMode,
+    operator: EdgeOperator,
+) -> Image<u8> {
+    assert_eq!(frame.channels(), 1, "sobel expects a single channel image");
+
+    let mut output = Image::new(frame.width(), frame.height(), 1);
+    sobel(frame, &mut output.view_mut(), operator);
+    my_scalar::sobel_border(frame, &mut output.view_mut(), border, operator);
+
+    output
+}
 
-    // Process each chunk of 12 bytes (4 pixels * 3 channels)
-
-    for (index, chunk) in bgr_data.chunks_exact(12).enumerate() {
-        // dbg!(index, chunk[0]);
-        // Load the BGR bytes into separate arrays for NEON operations
-        let b: [f32; 4] = [
-            chunk[0].into(),
-            chunk[3].into(),
-            chunk[6].into(),
-            chunk[9].into(),
-        ]; // Blue values
-        let g: [f32; 4] = [
-            chunk[1].into(),
-            chunk[4].into(),
-            chunk[7].into(),
-            chunk[10].into(),
-        ]; // Green values
-        let r: [f32; 4] = [
-            chunk[2].into(),
-            chunk[5].into(),
-            chunk[8].into(),
-            chunk[11].into(),
-        ]; // Red values
-
-        unsafe {
-            // 4 pixels split into 3 vectors
-            let mut b: float32x4_t = vld1q_f32(b.as_ptr());
-            let mut g: float32x4_t = vld1q_f32(g.as_ptr());
-            let mut r: float32x4_t = vld1q_f32(r.as_ptr());
-
-            // multiplication by scalar coefficients
-            b = vmulq_n_f32(b, 0.0722);
-            g = vmulq_n_f32(g, 0.7152);
-            r = vmulq_n_f32(r, 0.2126);
-
-            // add em back up into one 4 pixel vector
-            let grey: float32x4_t = vaddq_f32(r, vaddq_f32(b, g));
-
-            let mut grey_vec: [f32; 4] = [0.0; 4];
-            vst1q_f32(grey_vec.as_mut_ptr(), grey);
-
-            out_ptr[index * 4] = grey_vec[0] as u8;
-            out_ptr[index * 4 + 1] = grey_vec[1] as u8;
-            out_ptr[index * 4 + 2] = grey_vec[2] as u8;
-            out_ptr[index * 4 + 3] = grey_vec[3] as u8;
+// Grayscale + sobel in one pass over colour input. Each output row only needs the grey rows
+// above, at and below it, so those live in a 3 row ring buffer and each grey row is computed once.
+pub fn to442_fused_simd(
+    frame: &ImageView<u8>,
+    border: BorderMode,
+    operator: EdgeOperator,
+    luma: Luma,
+) -> Image<u8> {
+    assert_grayscale_input(frame.channels());
+
+    let (rows, cols) = (frame.height(), frame.width());
+    let mut output = Image::new(cols, rows, 1);
+    if cols == 0 {
+        return output;
+    }
+
+    let mut ring = GreyRing::new(cols, luma);
+    // stands in for the rows past the edge with BorderMode::Zero
+    let zero_row = vec![0u8; cols];
+
+    for (y, out_row) in output.rows_mut().enumerate() {
+        let needed = [-1isize, 0, 1].map(|dy| border.resolve(y as isize + dy, rows));
+        ring.fill(frame, needed);
+        let [above, row, below] = needed.map(|src_y| match src_y {
+            Some(src_y) => ring.row(src_y),
+            None => zero_row.as_slice(),
+        });
+
+        let rows = [above, row, below];
+        sobel_row(above, row, below, out_row, operator);
+        out_row[0] = my_scalar::sobel_pixel_rows_border(rows, 0, border, operator);
+        out_row[cols - 1] = my_scalar::sobel_pixel_rows_border(rows, cols - 1, border, operator);
+    }
+
+    output
+}
+
+// Three grey rows and which frame row each one holds. Rows are only converted when they're not
+// already in there, so walking down the frame converts each row once.
+struct GreyRing {
+    data: Vec<u8>,
+    width: usize,
+    luma: Luma,
+    tags: [Option<usize>; 3],
+}
+
+impl GreyRing {
+    fn new(width: usize, luma: Luma) -> Self {
+        GreyRing {
+            data: vec![0; 3 * width],
+            width,
+            luma,
+            tags: [None; 3],
         }
     }
 
+    // make sure every row in `needed` is in the ring, reusing slots nothing needs any more
+    fn fill(&mut self, frame: &ImageView<u8>, needed: [Option<usize>; 3]) {
+        for src_y in needed.into_iter().flatten() {
+            if self.tags.contains(&Some(src_y)) {
+                continue;
+            }
+            let slot = (0..3)
+                .find(|&slot| !self.tags[slot].is_some_and(|tag| needed.contains(&Some(tag))))
+                .expect("3 slots always fit 3 rows");
+            grayscale_row(
+                frame.row(src_y),
+                frame.channels(),
+                &mut self.data[slot * self.width..(slot + 1) * self.width],
+                self.luma,
+            );
+            self.tags[slot] = Some(src_y);
+        }
+    }
 
-    Ok(output)
+    fn row(&self, src_y: usize) -> &[u8] {
+        let slot = self
+            .tags
+            .iter()
+            .position(|&tag| tag == Some(src_y))
+            .expect("row was not loaded into the ring");
+        &self.data[slot * self.width..(slot + 1) * self.width]
+    }
+}
+
+// pick the kernel for whatever this cpu supports (see cpu_dispatch)
+fn grayscale_row(input: &[u8], channels: usize, out: &mut [u8], luma: Luma) {
+    // detect() only hands out levels this cpu has
+    unsafe { grayscale_row_at(cpu_dispatch::detect(), input, channels, out, luma) }
+}
+
+// grayscale_row with a given kernel set, so the tests can check every one the cpu has
+//
+// Safety: `level` has to be supported by the cpu (SimdLevel::is_supported)
+unsafe fn grayscale_row_at(
+    level: SimdLevel,
+    input: &[u8],
+    channels: usize,
+    out: &mut [u8],
+    luma: Luma,
+) {
+    if channels == 1 {
+        out.copy_from_slice(input);
+        return;
+    }
+    match (level, channels) {
+        #[cfg(target_arch = "aarch64")]
+        (SimdLevel::Neon, 3) => unsafe { grayscale_bgr_neon(input, out, luma) },
+        #[cfg(target_arch = "aarch64")]
+        (SimdLevel::Neon, _) => unsafe { grayscale_bgra_neon(input, out, luma) },
+        #[cfg(target_arch = "x86_64")]
+        (SimdLevel::Avx2, _) => unsafe { my_x86_simd::grayscale_avx2(input, channels, out, luma) },
+        #[cfg(target_arch = "x86_64")]
+        (SimdLevel::Sse2, _) => unsafe { my_x86_simd::grayscale_sse2(input, channels, out, luma) },
+        _ => my_scalar::grayscale_interleaved(input, channels, out, luma),
+    }
+}
+
+fn sobel(input: &ImageView<u8>, output: &mut ImageViewMut<u8>, operator: EdgeOperator) {
+    for y in 1..input.height().saturating_sub(1) {
+        sobel_row(
+            input.row(y - 1),
+            input.row(y),
+            input.row(y + 1),
+            output.row_mut(y),
+            operator,
+        );
+    }
+}
+
+// one interior sobel row, see my_scalar::sobel_row
+fn sobel_row(above: &[u8], row: &[u8], below: &[u8], out: &mut [u8], operator: EdgeOperator) {
+    // the vector kernels read/write whole registers at a time, so every row has to be full length
+    assert!(
+        above.len() >= row.len() && below.len() >= row.len() && out.len() >= row.len(),
+        "sobel rows have different lengths"
+    );
+    // detect() only hands out levels this cpu has
+    unsafe { sobel_row_at(cpu_dispatch::detect(), above, row, below, out, operator) }
+}
+
+// sobel_row with a given kernel set, for the tests
+//
+// Safety: `level` has to be supported by the cpu, and the rows as long as sobel_row checks
+unsafe fn sobel_row_at(
+    level: SimdLevel,
+    above: &[u8],
+    row: &[u8],
+    below: &[u8],
+    out: &mut [u8],
+    operator: EdgeOperator,
+) {
+    match (level, operator) {
+        #[cfg(target_arch = "aarch64")]
+        (SimdLevel::Neon, EdgeOperator::Sobel) => unsafe { sobel_row_neon(above, row, below, out) },
+        #[cfg(target_arch = "aarch64")]
+        (SimdLevel::Neon, _) => unsafe { edge_row_neon(above, row, below, out, operator) },
+        #[cfg(target_arch = "x86_64")]
+        (SimdLevel::Avx2, EdgeOperator::Sobel) => unsafe {
+            my_x86_simd::sobel_row_avx2(above, row, below, out)
+        },
+        #[cfg(target_arch = "x86_64")]
+        (SimdLevel::Sse2, EdgeOperator::Sobel) => unsafe {
+            my_x86_simd::sobel_row_sse2(above, row, below, out)
+        },
+        _ => my_scalar::sobel_row(above, row, below, out, operator),
+    }
+}
+
+// pre-filter row kernels, see my_scalar for what each one computes. NEON or scalar only.
+pub(crate) fn convolve_row(row: &[u8], weights: &[u16], out: &mut [u16]) {
+    assert!(
+        row.len() + 1 >= out.len() + weights.len(),
+        "blur row too short"
+    );
+    // detect() only hands out levels this cpu has
+    unsafe { convolve_row_at(cpu_dispatch::detect(), row, weights, out) }
 }
 
-pub fn to442_sobel_simd(frame: &Mat) -> Result<Mat> {
-    let input = unsafe {
-        std::slice::from_raw_parts(
-            frame.data() as *mut u8,
-            (frame.rows() * frame.cols()) as usize,
-        )
+pub(crate) fn convolve_column(rows: &[&[u16]], weights: &[u16], out: &mut [u8]) {
+    assert!(
+        rows.len() == weights.len() && rows.iter().all(|row| row.len() >= out.len()),
+        "blur column doesn't match the kernel"
+    );
+    unsafe { convolve_column_at(cpu_dispatch::detect(), rows, weights, out) }
+}
+
+pub(crate) fn box_row(top: &[u32], bottom: &[u32], size: usize, out: &mut [u8]) {
+    assert!(
+        top.len() >= out.len() + size && bottom.len() >= out.len() + size,
+        "integral rows too short"
+    );
+    unsafe { box_row_at(cpu_dispatch::detect(), top, bottom, size, out) }
+}
+
+pub(crate) fn median_row(rows: &[&[u8]], out: &mut [u8]) {
+    let size = rows.len();
+    assert!(
+        matches!(size, 3 | 5) && rows.iter().all(|row| row.len() + 1 >= out.len() + size),
+        "median window doesn't match the rows"
+    );
+    unsafe { median_row_at(cpu_dispatch::detect(), rows, out) }
+}
+
+// The pre-filter kernels with a given kernel set, for the tests
+//
+// Safety: `level` has to be supported by the cpu, and the rows as long as the functions above
+// check
+unsafe fn convolve_row_at(level: SimdLevel, row: &[u8], weights: &[u16], out: &mut [u16]) {
+    match level {
+        #[cfg(target_arch = "aarch64")]
+        SimdLevel::Neon => unsafe { convolve_row_neon(row, weights, out) },
+        _ => my_scalar::convolve_row(row, weights, out),
+    }
+}
+
+unsafe fn convolve_column_at(level: SimdLevel, rows: &[&[u16]], weights: &[u16], out: &mut [u8]) {
+    match level {
+        #[cfg(target_arch = "aarch64")]
+        SimdLevel::Neon => unsafe { convolve_column_neon(rows, weights, out) },
+        _ => my_scalar::convolve_column(rows, weights, out),
+    }
+}
+
+unsafe fn box_row_at(level: SimdLevel, top: &[u32], bottom: &[u32], size: usize, out: &mut [u8]) {
+    match level {
+        #[cfg(target_arch = "aarch64")]
+        SimdLevel::Neon => unsafe { box_row_neon(top, bottom, size, out) },
+        _ => my_scalar::box_row(top, bottom, size, out),
+    }
+}
+
+unsafe fn median_row_at(level: SimdLevel, rows: &[&[u8]], out: &mut [u8]) {
+    match level {
+        #[cfg(target_arch = "aarch64")]
+        SimdLevel::Neon => unsafe { median_row_neon(rows, out) },
+        _ => my_scalar::median_row(rows, out),
+    }
+}
+
+// 16 pixels per step: vld3q_u8 deinterleaves BGRBGR... into one register per channel. Each
+// channel gets widened to u16 and the widening multiply-accumulate builds b*wb + g*wg + r*wr in
+// u32 (14 bit fixed point weights from luma.rs, max 255 * 16384), then the narrowing shift brings
+// it back down, rounding (vrshrn adds the 8192 itself) or truncating as asked.
+#[cfg(target_arch = "aarch64")]
+#[target_feature(enable = "neon")]
+unsafe fn grayscale_bgr_neon(bgr_data: &[u8], out_ptr: &mut [u8], luma: Luma) {
+    let pixels = (bgr_data.len() / 3).min(out_ptr.len());
+
+    let mut i = 0;
+    while i + 16 <= pixels {
+        let bgr = vld3q_u8(bgr_data.as_ptr().add(i * 3));
+        let lo = grey8_neon(
+            vget_low_u8(bgr.0),
+            vget_low_u8(bgr.1),
+            vget_low_u8(bgr.2),
+            luma,
+        );
+        let hi = grey8_neon(
+            vget_high_u8(bgr.0),
+            vget_high_u8(bgr.1),
+            vget_high_u8(bgr.2),
+            luma,
+        );
+        vst1q_u8(out_ptr.as_mut_ptr().add(i), vcombine_u8(lo, hi));
+        i += 16;
+    }
+
+    // one half width step for what's left
+    if i + 8 <= pixels {
+        let bgr = vld3_u8(bgr_data.as_ptr().add(i * 3));
+        let grey = grey8_neon(bgr.0, bgr.1, bgr.2, luma);
+        vst1_u8(out_ptr.as_mut_ptr().add(i), grey);
+        i += 8;
+    }
+
+    // and the last few pixels of the row
+    my_scalar::grayscale_bgr(&bgr_data[i * 3..pixels * 3], &mut out_ptr[i..pixels], luma);
+}
+
+// same thing for BGRA, vld4 splits off the alpha channel which then just isn't used
+#[cfg(target_arch = "aarch64")]
+#[target_feature(enable = "neon")]
+unsafe fn grayscale_bgra_neon(bgra_data: &[u8], out_ptr: &mut [u8], luma: Luma) {
+    let pixels = (bgra_data.len() / 4).min(out_ptr.len());
+
+    let mut i = 0;
+    while i + 16 <= pixels {
+        let bgra = vld4q_u8(bgra_data.as_ptr().add(i * 4));
+        let lo = grey8_neon(
+            vget_low_u8(bgra.0),
+            vget_low_u8(bgra.1),
+            vget_low_u8(bgra.2),
+            luma,
+        );
+        let hi = grey8_neon(
+            vget_high_u8(bgra.0),
+            vget_high_u8(bgra.1),
+            vget_high_u8(bgra.2),
+            luma,
+        );
+        vst1q_u8(out_ptr.as_mut_ptr().add(i), vcombine_u8(lo, hi));
+        i += 16;
+    }
+
+    if i + 8 <= pixels {
+        let bgra = vld4_u8(bgra_data.as_ptr().add(i * 4));
+        let grey = grey8_neon(bgra.0, bgra.1, bgra.2, luma);
+        vst1_u8(out_ptr.as_mut_ptr().add(i), grey);
+        i += 8;
+    }
+
+    my_scalar::grayscale_bgra(&bgra_data[i * 4..pixels * 4], &mut out_ptr[i..pixels], luma);
+}
+
+// 8 pixels of each channel -> 8 grey pixels
+#[cfg(target_arch = "aarch64")]
+#[target_feature(enable = "neon")]
+#[inline]
+unsafe fn grey8_neon(b: uint8x8_t, g: uint8x8_t, r: uint8x8_t, luma: Luma) -> uint8x8_t {
+    let weights = luma.profile.weights();
+    let (b, g, r) = (vmovl_u8(b), vmovl_u8(g), vmovl_u8(r));
+
+    // 4 pixels' u32 sums at a time
+    let weighted_sum = |b: uint16x4_t, g: uint16x4_t, r: uint16x4_t| {
+        let acc = vmull_n_u16(r, weights.r);
+        let acc = vmlal_n_u16(acc, g, weights.g);
+        vmlal_n_u16(acc, b, weights.b)
     };
-    let mut output: Mat =
-        unsafe { opencv::core::Mat::new_rows_cols(frame.rows(), frame.cols(), CV_8UC1)? };
-
-    let input_2d: &[&[u8]] = &input.chunks(frame.cols() as usize).collect::<Vec<&[u8]>>();
-
-    // Define the Sobel kernels as arrays of 8-bit signed integers
-    let gx_data: [[i8; 8]; 3] = [
-        [-1, 0, 1, 0, 0, 0, 0, 0], // First row of gx kernel, padded with 0s
-        [-2, 0, 2, 0, 0, 0, 0, 0], // Second row of gx kernel
-        [-1, 0, 1, 0, 0, 0, 0, 0], // Third row of gx kernel
-    ];
-
-    let gy_data: [[i8; 8]; 2] = [
-        [1, 2, 1, 0, 0, 0, 0, 0], // First row of gy kernel
-        // [0, 0, 0, 0, 0, 0, 0, 0],   //  row of gy kernel (all zeros)
-        [-1, -2, -1, 0, 0, 0, 0, 0], // Second row of gy kernel
-    ];
-
-    // Load the arrays into NEON registers
-    let gx: (int8x8_t, int8x8_t, int8x8_t) = unsafe {
-        (
-            vld1_s8(gx_data[0].as_ptr()), // Load first row into int8x8_t
-            vld1_s8(gx_data[1].as_ptr()), // Load second row into int8x8_t
-            vld1_s8(gx_data[2].as_ptr()), // Load third row into int8x8_t
-        )
+    let narrow = |sum: uint32x4_t| match luma.rounding {
+        Rounding::Truncate => vshrn_n_u32::<{ LUMA_SHIFT as i32 }>(sum),
+        Rounding::Nearest => vrshrn_n_u32::<{ LUMA_SHIFT as i32 }>(sum),
     };
 
-    let gy: (int8x8_t, int8x8_t) = unsafe {
-        (
-            vld1_s8(gy_data[0].as_ptr()), // Load first row into int8x8_t
-            // vld1_s8(gy_data[].as_ptr()), // Load second row into int8x8_t
-            vld1_s8(gy_data[1].as_ptr()), // Load third row into int8x8_t
-        )
+    let lo = weighted_sum(vget_low_u16(b), vget_low_u16(g), vget_low_u16(r));
+    let hi = weighted_sum(vget_high_u16(b), vget_high_u16(g), vget_high_u16(r));
+    // the shifted sums are at most 255, so the last narrowing can't overflow
+    vmovn_u16(vcombine_u16(narrow(lo), narrow(hi)))
+}
+
+// Row parallel sobel: instead of sliding the kernel over a 3x8 block one pixel at a time, load
+// each neighbour row three times (shifted by -1, 0, +1 columns) so every lane holds the
+// neighbourhood of its own output pixel, then gx/gy for all lanes are plain vector adds.
+// 16 outputs per step, then one 8 wide step and the last few pixels in scalar.
+#[cfg(target_arch = "aarch64")]
+#[target_feature(enable = "neon")]
+unsafe fn sobel_row_neon(above: &[u8], row: &[u8], below: &[u8], out: &mut [u8]) {
+    let cols = row.len();
+
+    let mut x = 1;
+    // loads cover x - 1 .. x + 17, which has to stay inside the row
+    while x + 17 <= cols {
+        let load = |r: &[u8], x: usize| vld1q_u8(r.as_ptr().add(x));
+        let (a0, a1, a2) = (load(above, x - 1), load(above, x), load(above, x + 1));
+        let (b0, b2) = (load(row, x - 1), load(row, x + 1));
+        let (c0, c1, c2) = (load(below, x - 1), load(below, x), load(below, x + 1));
+
+        let lo = sobel8_neon(
+            [vget_low_u8(a0), vget_low_u8(a1), vget_low_u8(a2)],
+            [vget_low_u8(b0), vget_low_u8(b2)],
+            [vget_low_u8(c0), vget_low_u8(c1), vget_low_u8(c2)],
+        );
+        let hi = sobel8_neon(
+            [vget_high_u8(a0), vget_high_u8(a1), vget_high_u8(a2)],
+            [vget_high_u8(b0), vget_high_u8(b2)],
+            [vget_high_u8(c0), vget_high_u8(c1), vget_high_u8(c2)],
+        );
+        vst1q_u8(out.as_mut_ptr().add(x), vcombine_u8(lo, hi));
+        x += 16;
+    }
+
+    if x + 9 <= cols {
+        let load = |r: &[u8], x: usize| vld1_u8(r.as_ptr().add(x));
+        let magnitude = sobel8_neon(
+            [load(above, x - 1), load(above, x), load(above, x + 1)],
+            [load(row, x - 1), load(row, x + 1)],
+            [load(below, x - 1), load(below, x), load(below, x + 1)],
+        );
+        vst1_u8(out.as_mut_ptr().add(x), magnitude);
+        x += 8;
+    }
+
+    // leftover pixels at the end of the row
+    let end = cols.saturating_sub(1);
+    for (x, out) in out.iter_mut().enumerate().take(end).skip(x) {
+        *out = my_scalar::sobel_pixel_rows([above, row, below], x, EdgeOperator::Sobel);
+    }
+}
+
+// sobel magnitude for 8 adjacent pixels, given the row above / the row / the row below shifted
+// by (-1, 0, +1) columns (the centre pixel has weight 0 in both kernels so it isn't needed).
+// Same math as my_scalar::sobel_pixel: |gx| + |gy| saturated to 255.
+#[cfg(target_arch = "aarch64")]
+#[target_feature(enable = "neon")]
+unsafe fn sobel8_neon(
+    above: [uint8x8_t; 3],
+    row: [uint8x8_t; 2],
+    below: [uint8x8_t; 3],
+) -> uint8x8_t {
+    // widening subtract, the u16 wraps around to the right i16 difference
+    let diff = |right, left| vreinterpretq_s16_u16(vsubl_u8(right, left));
+
+    // gx = (a2 - a0) + 2(b2 - b0) + (c2 - c0)
+    let gx = vaddq_s16(
+        vaddq_s16(diff(above[2], above[0]), diff(below[2], below[0])),
+        vshlq_n_s16::<1>(diff(row[1], row[0])),
+    );
+
+    // gy = (a0 + 2a1 + a2) - (c0 + 2c1 + c2), each side fits in a u16 (max 1020)
+    let weighted = |r: [uint8x8_t; 3]| vaddq_u16(vaddl_u8(r[0], r[2]), vshll_n_u8::<1>(r[1]));
+    let gy = vsubq_s16(
+        vreinterpretq_s16_u16(weighted(above)),
+        vreinterpretq_s16_u16(weighted(below)),
+    );
+
+    // |gx| + |gy| is at most 2040, the saturating narrow clamps it to 0..255
+    let magnitude = vaddq_s16(vabsq_s16(gx), vabsq_s16(gy));
+
+    #[cfg(feature = "debug")]
+    println!("gx: {:?}, gy: {:?}, magnitude: {:?}", gx, gy, magnitude);
+
+    vqmovun_s16(magnitude)
+}
+
+// Any other edge operator, with the coefficients as multiply-accumulates instead of the adds and
+// shifts sobel8_neon hardcodes. Same shifted row loads, 8 outputs per step.
+#[cfg(target_arch = "aarch64")]
+#[target_feature(enable = "neon")]
+unsafe fn edge_row_neon(
+    above: &[u8],
+    row: &[u8],
+    below: &[u8],
+    out: &mut [u8],
+    operator: EdgeOperator,
+) {
+    let cols = row.len();
+    let rows = [above, row, below];
+    let [gx, gy] = operator
+        .kernels()
+        .map(|kernel| kernel.map(|r| r.map(|k| k as i16)));
+
+    let mut x = 1;
+    while x + 9 <= cols {
+        let mut sum_x = vdupq_n_s16(0);
+        let mut sum_y = vdupq_n_s16(0);
+        for ky in 0..3 {
+            for kx in 0..3 {
+                if gx[ky][kx] == 0 && gy[ky][kx] == 0 {
+                    continue;
+                }
+                // column kx - 1 relative to each output pixel, widened to i16
+                let tap = vld1_u8(rows[ky].as_ptr().add(x + kx - 1));
+                let tap = vreinterpretq_s16_u16(vmovl_u8(tap));
+                sum_x = vmlaq_n_s16(sum_x, tap, gx[ky][kx]);
+                sum_y = vmlaq_n_s16(sum_y, tap, gy[ky][kx]);
+            }
+        }
+
+        // worst case (scharr) is 2 * 16 * 255, still fits an i16
+        let magnitude = vaddq_s16(vabsq_s16(sum_x), vabsq_s16(sum_y));
+        vst1_u8(out.as_mut_ptr().add(x), vqmovun_s16(magnitude));
+        x += 8;
+    }
+
+    let end = cols.saturating_sub(1);
+    for (x, out) in out.iter_mut().enumerate().take(end).skip(x) {
+        *out = my_scalar::sobel_pixel_rows(rows, x, operator);
+    }
+}
+
+// Horizontal blur pass, 8 pixels per step: widen each shifted load to u16 and multiply-accumulate
+// it by its tap. Taps sum to 256 so the u16 lanes can't overflow.
+#[cfg(target_arch = "aarch64")]
+#[target_feature(enable = "neon")]
+unsafe fn convolve_row_neon(row: &[u8], weights: &[u16], out: &mut [u16]) {
+    let mut x = 0;
+    while x + 8 <= out.len() {
+        let mut acc = vdupq_n_u16(0);
+        for (k, &weight) in weights.iter().enumerate() {
+            let pixels = vmovl_u8(vld1_u8(row.as_ptr().add(x + k)));
+            acc = vmlaq_n_u16(acc, pixels, weight);
+        }
+        vst1q_u16(out.as_mut_ptr().add(x), acc);
+        x += 8;
+    }
+    my_scalar::convolve_row(&row[x..], weights, &mut out[x..]);
+}
+
+// Vertical blur pass, 8 pixels per step in two u32x4 accumulators, then the rounding shift by 16
+// and a saturating narrow back to u8
+#[cfg(target_arch = "aarch64")]
+#[target_feature(enable = "neon")]
+unsafe fn convolve_column_neon(rows: &[&[u16]], weights: &[u16], out: &mut [u8]) {
+    let mut x = 0;
+    while x + 8 <= out.len() {
+        let mut lo = vdupq_n_u32(0);
+        let mut hi = vdupq_n_u32(0);
+        for (row, &weight) in rows.iter().zip(weights) {
+            let values = vld1q_u16(row.as_ptr().add(x));
+            lo = vmlal_n_u16(lo, vget_low_u16(values), weight);
+            hi = vmlal_high_n_u16(hi, values, weight);
+        }
+        let sum = vcombine_u16(vrshrn_n_u32::<16>(lo), vrshrn_n_u32::<16>(hi));
+        vst1_u8(out.as_mut_ptr().add(x), vqmovn_u16(sum));
+        x += 8;
+    }
+    let rest: Vec<&[u16]> = rows.iter().map(|row| &row[x..]).collect();
+    my_scalar::convolve_column(&rest, weights, &mut out[x..]);
+}
+
+// Box mean, 8 pixels per step: the four integral image corners in wrapping u32 math, then the
+// same f32 multiply and +0.5 as the scalar version so the rounding matches exactly
+#[cfg(target_arch = "aarch64")]
+#[target_feature(enable = "neon")]
+unsafe fn box_row_neon(top: &[u32], bottom: &[u32], size: usize, out: &mut [u8]) {
+    let inv_area = 1.0 / (size * size) as f32;
+    let half = vdupq_n_f32(0.5);
+    let mean4 = |x: usize| {
+        let sum = vsubq_u32(
+            vld1q_u32(bottom.as_ptr().add(x + size)),
+            vld1q_u32(bottom.as_ptr().add(x)),
+        );
+        let sum = vsubq_u32(sum, vld1q_u32(top.as_ptr().add(x + size)));
+        let sum = vaddq_u32(sum, vld1q_u32(top.as_ptr().add(x)));
+        let mean = vaddq_f32(vmulq_n_f32(vcvtq_f32_u32(sum), inv_area), half);
+        vmovn_u32(vcvtq_u32_f32(mean))
     };
 
-    let mut out_x = 0;
-
-    let input_2d = &input_2d[1..input_2d.len() - 1]; // don't sobel the first or last rows
-    for (out_y, row) in input_2d.iter().enumerate() {
-        let row = &row[1..row.len() - 1]; // don't sobel the first or last columns
-                                          // for value in row.chunks(6) {
-        for chunk in row.chunks(6).enumerate() {
-            unsafe {
-                // load next u8 (x8)
-                let surround: [uint8x8_t; 3] = [
-                    vld1_u8((&(chunk.1)[0] as *const u8).offset((-frame.cols() as isize) - 1)), // row above
-                    vld1_u8((&(chunk.1)[0] as *const u8).offset(-1)), // row
-                    vld1_u8((&(chunk.1)[0] as *const u8).offset(frame.cols() as isize - 1)), // row below
-                ];
-
-                // u8 to signed 16 bit greyscale pixels, 3x8 grid (3 vectors of 8)
-                let signed_surround = surround.map(|x| vreinterpretq_s16_u16(vmovl_u8(x)));
-
-                #[cfg(feature = "debug")]
-                println!(
-                    "\n\nSigned surrounding pixels: {:?}, {:?}, {:?}",
-                    signed_surround[0], signed_surround[1], signed_surround[2]
+    let mut x = 0;
+    while x + 8 <= out.len() {
+        let means = vcombine_u16(mean4(x), mean4(x + 4));
+        vst1_u8(out.as_mut_ptr().add(x), vqmovn_u16(means));
+        x += 8;
+    }
+    my_scalar::box_row(&top[x..], &bottom[x..], size, &mut out[x..]);
+}
+
+// Median, 16 pixels per step: load the 9 or 25 shifted windows, run them through a sorting
+// network with vmin/vmax as the compare-exchange, and take the middle one. Every lane is sorted
+// independently so this is the same median the scalar code picks.
+#[cfg(target_arch = "aarch64")]
+#[target_feature(enable = "neon")]
+unsafe fn median_row_neon(rows: &[&[u8]], out: &mut [u8]) {
+    let size = rows.len();
+    let count = size * size;
+    let network = sorting_network(count);
+
+    let mut x = 0;
+    let mut values = [vdupq_n_u8(0); 25];
+    while x + 16 <= out.len() {
+        for (ky, row) in rows.iter().enumerate() {
+            for kx in 0..size {
+                values[ky * size + kx] = vld1q_u8(row.as_ptr().add(x + kx));
+            }
+        }
+        for &(a, b) in &network {
+            let (low, high) = (
+                vminq_u8(values[a], values[b]),
+                vmaxq_u8(values[a], values[b]),
+            );
+            values[a] = low;
+            values[b] = high;
+        }
+        vst1q_u8(out.as_mut_ptr().add(x), values[count / 2]);
+        x += 16;
+    }
+    let rest: Vec<&[u8]> = rows.iter().map(|row| &row[x..]).collect();
+    my_scalar::median_row(&rest, &mut out[x..]);
+}
+
+// Batcher's odd-even merge sort for the next power of two above `count`, minus the comparators
+// that touch the elements past `count` (think of those as +infinity, they'd never move)
+#[cfg(any(target_arch = "aarch64", test))]
+fn sorting_network(count: usize) -> Vec<(usize, usize)> {
+    let n = count.next_power_of_two();
+    let mut pairs = Vec::new();
+    let mut p = 1;
+    while p < n {
+        let mut k = p;
+        while k >= 1 {
+            let mut j = k % p;
+            while j + k < n {
+                for i in 0..k.min(n - j - k) {
+                    if (i + j) / (2 * p) == (i + j + k) / (2 * p) && i + j + k < count {
+                        pairs.push((i + j, i + j + k));
+                    }
+                }
+                j += 2 * k;
+            }
+            k /= 2;
+        }
+        p *= 2;
+    }
+    pairs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine;
+    use crate::luma::{LumaProfile, Rounding};
+
+    // every kernel set this cpu can run, scalar included
+    fn levels() -> Vec<SimdLevel> {
+        [
+            SimdLevel::Scalar,
+            SimdLevel::Sse2,
+            SimdLevel::Avx2,
+            SimdLevel::Neon,
+        ]
+        .into_iter()
+        .filter(|level| level.is_supported())
+        .collect()
+    }
+
+    fn bt601(rounding: Rounding) -> Luma {
+        Luma {
+            profile: LumaProfile::Bt601,
+            rounding,
+        }
+    }
+
+    // `count` copies of one BGR pixel, enough for the 16 wide bodies, the 8 wide steps and a
+    // scalar tail
+    fn solid(bgr: [u8; 3], count: usize) -> Vec<u8> {
+        bgr.repeat(count)
+    }
+
+    // Every path that makes grey out of one row of BGR: each kernel set, the BGRA versions and
+    // the lab3/lab4 engine stage
+    fn grey_everywhere(bgr: &[u8], luma: Luma) -> Vec<Vec<u8>> {
+        let pixels = bgr.len() / 3;
+        let bgra: Vec<u8> = bgr
+            .chunks_exact(3)
+            .flat_map(|p| [p[0], p[1], p[2], 7])
+            .collect();
+
+        let mut results = Vec::new();
+        for level in levels() {
+            for (input, channels) in [(bgr, 3), (&bgra[..], 4)] {
+                let mut out = vec![0; pixels];
+                unsafe { grayscale_row_at(level, input, channels, &mut out, luma) };
+                results.push(out);
+            }
+        }
+        let frame = ImageView::packed(bgr, pixels, 1, 3);
+        results.push(engine::to442_grayscale(&frame, luma).into_vec());
+        results
+    }
+
+    #[test]
+    fn bt601_rounded_matches_cvtcolor() {
+        // what cv::cvtColor(COLOR_BGR2GRAY) gives for pure blue, green, red, white and black
+        let known = [
+            ([255, 0, 0], 29),
+            ([0, 255, 0], 150),
+            ([0, 0, 255], 76),
+            ([255, 255, 255], 255),
+            ([0, 0, 0], 0),
+            ([40, 120, 200], 135),
+        ];
+        for (bgr, expected) in known {
+            for grey in grey_everywhere(&solid(bgr, 37), bt601(Rounding::Nearest)) {
+                assert!(
+                    grey.iter().all(|&g| g == expected),
+                    "{:?} gave {:?}",
+                    bgr,
+                    grey
                 );
+            }
+        }
+    }
 
-                let mut x_kernel = { [vmovl_s8(gx.0), vmovl_s8(gx.1), vmovl_s8(gx.2)] };
-                let mut y_kernel = { [vmovl_s8(gy.0), vmovl_s8(gy.1)] };
-
-                for i in 0..chunk.1.len() {
-                    #[cfg(feature = "debug")]
-                    println!(
-                        "x kern: {:?}, {:?}, {:?}",
-                        x_kernel[0], x_kernel[1], x_kernel[2]
-                    );
-                    #[cfg(feature = "debug")]
-                    println!("y kern: {:?}, {:?}", y_kernel[0], y_kernel[1]);
-
-                    // perform x kernel convolution for first position
-                    let mut acc: int16x8_t = vdupq_n_s16(0); // Initialize all 8 elements to 0
-                    acc = vmlaq_s16(acc, signed_surround[0], x_kernel[0]);
-                    #[cfg(feature = "debug")]
-                    println!("x1 acc {:?}", acc);
-
-                    acc = vmlaq_s16(acc, signed_surround[1], x_kernel[1]);
-                    #[cfg(feature = "debug")]
-                    println!("x2 acc {:?}", acc);
-
-                    acc = vmlaq_s16(acc, signed_surround[2], x_kernel[2]);
-                    #[cfg(feature = "debug")]
-                    println!("x3 acc {:?}", acc);
-
-                    let x_kernel_sum: i16 = vaddvq_s16(acc); // This sums all the elements in the vector and returns a scalar value
-                    #[cfg(feature = "debug")]
-                    println!("X kernel sum: {}", x_kernel_sum);
-
-                    // perform y kernel convolution for first position
-                    acc = vdupq_n_s16(0); // Initialize all 8 elements to 0
-                    acc = vmlaq_s16(acc, signed_surround[0], y_kernel[0]);
-                    acc = vmlaq_s16(acc, signed_surround[2], y_kernel[1]); // note the indexes are slightly different due to the blank row in kernel y
-                    let y_kernel_sum = vaddvq_s16(acc);
-                    #[cfg(feature = "debug")]
-                    println!("Y kernel sum: {}", y_kernel_sum);
-
-                    // save the results into the output frame
-                    let magnitude = (x_kernel_sum.abs() + y_kernel_sum.abs()).min(255) as u8;
-                    *(output.at_2d_mut::<u8>(out_y as i32 + 1, out_x + 1 + i as i32)?) = magnitude;
-                    #[cfg(feature = "debug")]
-                    println!(
-                        "Stored magnitude ({}) at (x: {}, y: {})",
-                        magnitude, x_kernel_sum, y_kernel_sum
-                    );
-
-                    // shift kernels over by one pixel (vector rotate elements)
-                    let r_shift_kernel_row = |kernel_row| vextq_s16::<7>(kernel_row, kernel_row);
-                    x_kernel = x_kernel.map(r_shift_kernel_row);
-                    y_kernel = y_kernel.map(r_shift_kernel_row);
+    #[test]
+    fn bt601_truncated() {
+        let known = [
+            ([255, 0, 0], 29),
+            ([0, 255, 0], 149),
+            ([0, 0, 255], 76),
+            ([255, 255, 255], 255),
+        ];
+        for (bgr, expected) in known {
+            for grey in grey_everywhere(&solid(bgr, 37), bt601(Rounding::Truncate)) {
+                assert!(
+                    grey.iter().all(|&g| g == expected),
+                    "{:?} gave {:?}",
+                    bgr,
+                    grey
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn grey_ramp_stays_put() {
+        let ramp: Vec<u8> = (0..=255).flat_map(|v| [v, v, v]).collect();
+        let expected: Vec<u8> = (0..=255).collect();
+        for rounding in [Rounding::Truncate, Rounding::Nearest] {
+            for profile in [
+                LumaProfile::Bt601,
+                LumaProfile::Bt709,
+                LumaProfile::Bt2020,
+                LumaProfile::Average,
+            ] {
+                for grey in grey_everywhere(&ramp, Luma { profile, rounding }) {
+                    assert_eq!(grey, expected, "{} {}", profile.name(), rounding.name());
                 }
             }
-            out_x += chunk.1.len() as i32;
         }
-        out_x = 0;
     }
 
-    Ok(output)
+    #[test]
+    fn kernels_agree_on_every_pixel() {
+        // every combination of a few levels per channel
+        let values = [0, 1, 63, 127, 128, 200, 254, 255];
+        let mut bgr = Vec::new();
+        for b in values {
+            for g in values {
+                for r in values {
+                    bgr.extend([b, g, r]);
+                }
+            }
+        }
+        for rounding in [Rounding::Truncate, Rounding::Nearest] {
+            let luma = bt601(rounding);
+            let results = grey_everywhere(&bgr, luma);
+            let expected: Vec<u8> = bgr
+                .chunks_exact(3)
+                .map(|p| {
+                    my_scalar::grey_pixel(
+                        p[0],
+                        p[1],
+                        p[2],
+                        luma.profile.weights(),
+                        luma.rounding.bias(),
+                    )
+                })
+                .collect();
+            for grey in results {
+                assert_eq!(grey, expected);
+            }
+        }
+    }
+
+    // xorshift, so the "random" frames are the same every run
+    fn noise(len: usize, mut seed: u32) -> Vec<u8> {
+        (0..len)
+            .map(|_| {
+                seed ^= seed << 13;
+                seed ^= seed >> 17;
+                seed ^= seed << 5;
+                (seed >> 24) as u8
+            })
+            .collect()
+    }
+
+    // Rows wide enough for the 16 wide body, the 8 wide step and the scalar tail in every
+    // combination, through each kernel set against my_scalar
+    #[test]
+    fn sobel_rows_match_scalar() {
+        for width in [1, 2, 3, 7, 8, 9, 16, 17, 18, 25, 33, 64, 65] {
+            let checker = |phase: usize| -> Vec<u8> {
+                (0..width)
+                    .map(|x| {
+                        if (x + phase).is_multiple_of(2) {
+                            255
+                        } else {
+                            0
+                        }
+                    })
+                    .collect()
+            };
+            let mut frames = vec![
+                [checker(0), checker(1), checker(0)],
+                [checker(1), checker(0), checker(1)],
+                [vec![255; width], vec![0; width], vec![255; width]],
+                [vec![0; width], vec![255; width], vec![255; width]],
+            ];
+            for seed in 1..20 {
+                frames.push([
+                    noise(width, seed),
+                    noise(width, seed * 7919),
+                    noise(width, seed * 104729),
+                ]);
+            }
+
+            for [above, row, below] in &frames {
+                for operator in EdgeOperator::ALL {
+                    let mut expected = vec![0; width];
+                    my_scalar::sobel_row(above, row, below, &mut expected, operator);
+                    for level in levels() {
+                        let mut out = vec![0; width];
+                        unsafe { sobel_row_at(level, above, row, below, &mut out, operator) };
+                        assert_eq!(
+                            out,
+                            expected,
+                            "{} {:?} at width {}",
+                            level.name(),
+                            operator,
+                            width
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    // widths around the 8 and 16 lane steps, each with a few random rows
+    const PREFILTER_WIDTHS: std::ops::RangeInclusive<usize> = 1..=40;
+
+    #[test]
+    fn blur_rows_match_scalar() {
+        let kernels: [&[u16]; 4] = [
+            &[256],
+            &[64, 128, 64],
+            &[16, 64, 96, 64, 16],
+            &[4, 24, 60, 80, 60, 24, 4],
+        ];
+        for width in PREFILTER_WIDTHS {
+            for weights in kernels {
+                let taps = weights.len();
+                for seed in 1..6 {
+                    let row = noise(width + taps - 1, seed * 31);
+                    let mut expected = vec![0; width];
+                    my_scalar::convolve_row(&row, weights, &mut expected);
+                    for level in levels() {
+                        let mut out = vec![0; width];
+                        unsafe { convolve_row_at(level, &row, weights, &mut out) };
+                        assert_eq!(out, expected, "{} {:?} at {}", level.name(), weights, width);
+                    }
+
+                    // horizontal pass output is what the vertical pass gets fed
+                    let rows: Vec<Vec<u16>> = (0..taps as u32)
+                        .map(|k| {
+                            let row = noise(width + taps - 1, seed * 7 + k * 1013);
+                            let mut pass = vec![0; width];
+                            my_scalar::convolve_row(&row, weights, &mut pass);
+                            pass
+                        })
+                        .collect();
+                    let rows: Vec<&[u16]> = rows.iter().map(Vec::as_slice).collect();
+                    let mut expected = vec![0; width];
+                    my_scalar::convolve_column(&rows, weights, &mut expected);
+                    for level in levels() {
+                        let mut out = vec![0; width];
+                        unsafe { convolve_column_at(level, &rows, weights, &mut out) };
+                        assert_eq!(out, expected, "{} {:?} at {}", level.name(), weights, width);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn box_rows_match_scalar() {
+        for width in PREFILTER_WIDTHS {
+            for size in [1, 3, 5, 31] {
+                for seed in 1..4 {
+                    // integral image of some noise, offset so it has wrapped around like it
+                    // does on a big frame
+                    let cols = width + size;
+                    let mut integral = vec![vec![0u32; cols]; size + 3];
+                    for y in 1..integral.len() {
+                        let pixels = noise(cols - 1, seed * 97 + y as u32);
+                        let mut row_sum = 0u32;
+                        for x in 1..cols {
+                            row_sum += pixels[x - 1] as u32;
+                            integral[y][x] = integral[y - 1][x] + row_sum;
+                        }
+                    }
+                    for row in &mut integral {
+                        for value in row.iter_mut() {
+                            *value = value.wrapping_add(0xffff_ff00);
+                        }
+                    }
+                    let (top, bottom) = (&integral[2], &integral[2 + size]);
+
+                    let mut expected = vec![0; width];
+                    my_scalar::box_row(top, bottom, size, &mut expected);
+                    for level in levels() {
+                        let mut out = vec![0; width];
+                        unsafe { box_row_at(level, top, bottom, size, &mut out) };
+                        assert_eq!(out, expected, "{} box {} at {}", level.name(), size, width);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn median_rows_match_scalar() {
+        for width in PREFILTER_WIDTHS {
+            for size in [3, 5] {
+                for seed in 1..6u32 {
+                    let rows: Vec<Vec<u8>> = (0..size as u32)
+                        .map(|k| noise(width + size - 1, seed * 389 + k * 7))
+                        .collect();
+                    // and a few with long runs of the same value, where ties matter
+                    let flat: Vec<Vec<u8>> = rows
+                        .iter()
+                        .map(|row| row.iter().map(|v| v & 0xc0).collect())
+                        .collect();
+                    for rows in [&rows, &flat] {
+                        let rows: Vec<&[u8]> = rows.iter().map(Vec::as_slice).collect();
+                        let mut expected = vec![0; width];
+                        my_scalar::median_row(&rows, &mut expected);
+                        for level in levels() {
+                            let mut out = vec![0; width];
+                            unsafe { median_row_at(level, &rows, &mut out) };
+                            assert_eq!(
+                                out,
+                                expected,
+                                "{} median{} at {}",
+                                level.name(),
+                                size,
+                                width
+                            );
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    // By the 0-1 principle a comparator network sorts everything if it sorts every input of 0s
+    // and 1s. Each u64 holds 64 of those inputs side by side, one per bit, so min/max are just
+    // and/or.
+    #[test]
+    fn sorting_networks_sort() {
+        for count in [9, 25] {
+            let network = sorting_network(count);
+            assert!(network.iter().all(|&(a, b)| a < b && b < count));
+
+            // the low 6 positions vary across the bits of a word, the rest across words
+            let lane_patterns: [u64; 6] = [
+                0xaaaa_aaaa_aaaa_aaaa,
+                0xcccc_cccc_cccc_cccc,
+                0xf0f0_f0f0_f0f0_f0f0,
+                0xff00_ff00_ff00_ff00,
+                0xffff_0000_ffff_0000,
+                0xffff_ffff_0000_0000,
+            ];
+            for word in 0u64..1 << (count - 6) {
+                let mut values: Vec<u64> = (0..count)
+                    .map(|i| match i {
+                        0..6 => lane_patterns[i],
+                        _ if word >> (i - 6) & 1 == 1 => u64::MAX,
+                        _ => 0,
+                    })
+                    .collect();
+                for &(a, b) in &network {
+                    let (low, high) = (values[a] & values[b], values[a] | values[b]);
+                    values[a] = low;
+                    values[b] = high;
+                }
+                for i in 1..count {
+                    // a 1 before a 0 in any of the 64 inputs
+                    assert_eq!(values[i - 1] & !values[i], 0, "{} inputs", count);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn roi_matches_the_whole_frame() {
+        let (width, height) = (23, 17);
+        let bgr = noise(width * height * 3, 11);
+        let options = FrameOptions {
+            prefilter: PreFilter::Gaussian { sigma: 1.0 },
+            ..FrameOptions::default()
+        };
+        let whole = do_frame_with(&ImageView::packed(&bgr, width, height, 3), &options);
+
+        let rect = Rect::new(5, 4, 9, 7);
+        let mut frame = Image::from_vec(width, height, 3, bgr.clone());
+        process_roi_with(&mut frame.view_mut(), rect, &options);
+        for y in 0..height {
+            for x in 0..width {
+                let pixel = &frame.row(y)[x * 3..x * 3 + 3];
+                if (rect.x..rect.x + rect.width).contains(&x)
+                    && (rect.y..rect.y + rect.height).contains(&y)
+                {
+                    assert_eq!(pixel, [whole.row(y)[x]; 3], "({}, {})", x, y);
+                } else {
+                    assert_eq!(pixel, &bgr[(y * width + x) * 3..][..3], "({}, {})", x, y);
+                }
+            }
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "canny needs the whole frame")]
+    fn roi_rejects_canny() {
+        let mut frame = Image::<u8>::new(16, 16, 3);
+        let options = FrameOptions {
+            mode: Mode::Canny(CannyOptions::default()),
+            ..FrameOptions::default()
+        };
+        process_roi_with(&mut frame.view_mut(), Rect::new(4, 4, 8, 8), &options);
+    }
 }
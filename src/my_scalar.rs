@@ -0,0 +1,270 @@
+// Plain rust versions of the kernels. These are the fallback when no SIMD unit is available and
+// the reference the SIMD versions are checked against, so keep the math in the same order.
+use crate::border::BorderMode;
+use crate::edge::EdgeOperator;
+use crate::image::{ImageView, ImageViewMut};
+use crate::luma::{Luma, LumaWeights, Rounding, LUMA_ONE, LUMA_SHIFT};
+
+// packed BGR (3 bytes per pixel) to one byte of grey per pixel
+pub fn grayscale_bgr(bgr: &[u8], out: &mut [u8], luma: Luma) {
+    grayscale_interleaved(bgr, 3, out, luma);
+}
+
+// same for BGRA, alpha is ignored
+pub fn grayscale_bgra(bgra: &[u8], out: &mut [u8], luma: Luma) {
+    grayscale_interleaved(bgra, 4, out, luma);
+}
+
+// `channels` bytes per pixel, the first three being B, G and R
+pub fn grayscale_interleaved(pixels: &[u8], channels: usize, out: &mut [u8], luma: Luma) {
+    let (weights, bias) = (luma.profile.weights(), luma.rounding.bias());
+    for (pixel, grey) in pixels.chunks_exact(channels).zip(out.iter_mut()) {
+        *grey = grey_pixel(pixel[0], pixel[1], pixel[2], weights, bias);
+    }
+}
+
+// 16 bit grey, BGR or BGRA to 8 bit grey. Same weights, but the weighted sum gets divided by
+// LUMA_ONE * 257 (65535 * LUMA_ONE maps to exactly 255). A single channel is just scaled down.
+pub fn grayscale_u16(pixels: &[u16], channels: usize, out: &mut [u8], luma: Luma) {
+    const SCALE: u32 = LUMA_ONE * 257;
+    let weights = luma.profile.weights();
+    let bias = match luma.rounding {
+        Rounding::Truncate => 0,
+        Rounding::Nearest => SCALE / 2,
+    };
+    for (pixel, grey) in pixels.chunks_exact(channels).zip(out.iter_mut()) {
+        let sum = match pixel {
+            [value] => *value as u32 * LUMA_ONE,
+            [b, g, r, ..] => {
+                *r as u32 * weights.r as u32
+                    + *g as u32 * weights.g as u32
+                    + *b as u32 * weights.b as u32
+            }
+            _ => panic!("no grayscale for {} channel images", channels),
+        };
+        *grey = ((sum + bias) / SCALE) as u8;
+    }
+}
+
+// 16 bit samples to 8 bits, rounded (65535 / 257 is exactly 255)
+pub fn narrow_u16(samples: &[u16], out: &mut [u8]) {
+    for (&value, narrow) in samples.iter().zip(out.iter_mut()) {
+        *narrow = ((value as u32 + 128) / 257) as u8;
+    }
+}
+
+// weighted sum in 14 bit fixed point (see luma.rs), `bias` is Rounding::bias()
+#[inline]
+pub fn grey_pixel(b: u8, g: u8, r: u8, weights: LumaWeights, bias: u32) -> u8 {
+    let sum = r as u32 * weights.r as u32
+        + g as u32 * weights.g as u32
+        + b as u32 * weights.b as u32
+        + bias;
+    (sum >> LUMA_SHIFT) as u8
+}
+
+// Edge operator (sobel by default) over the interior of a single channel image. The outer one
+// pixel ring is left alone, sobel_border fills that in.
+pub fn sobel(input: &ImageView<u8>, output: &mut ImageViewMut<u8>, operator: EdgeOperator) {
+    for y in 1..input.height().saturating_sub(1) {
+        sobel_row(
+            input.row(y - 1),
+            input.row(y),
+            input.row(y + 1),
+            output.row_mut(y),
+            operator,
+        );
+    }
+}
+
+// One output row from the rows above/at/below it, skipping the first and last pixel
+pub fn sobel_row(above: &[u8], row: &[u8], below: &[u8], out: &mut [u8], operator: EdgeOperator) {
+    let width = row.len();
+    for (x, out) in out
+        .iter_mut()
+        .enumerate()
+        .take(width.saturating_sub(1))
+        .skip(1)
+    {
+        *out = sobel_pixel_rows([above, row, below], x, operator);
+    }
+}
+
+// |gx| + |gy| clamped to 255 for the pixel at (y, x), which must not be on the image edge
+#[inline]
+pub fn sobel_pixel(input: &ImageView<u8>, y: usize, x: usize, operator: EdgeOperator) -> u8 {
+    sobel_pixel_rows(
+        [input.row(y - 1), input.row(y), input.row(y + 1)],
+        x,
+        operator,
+    )
+}
+
+// same thing given the three rows directly, x must not be the first or last column
+#[inline]
+pub fn sobel_pixel_rows(rows: [&[u8]; 3], x: usize, operator: EdgeOperator) -> u8 {
+    let (sum_x, sum_y) = gradient_pixel_rows(rows, x, operator);
+    (sum_x.abs() + sum_y.abs()).min(255) as u8
+}
+
+// the signed (gx, gy) sobel_pixel_rows builds its magnitude from
+#[inline]
+pub fn gradient_pixel_rows(rows: [&[u8]; 3], x: usize, operator: EdgeOperator) -> (i32, i32) {
+    let [gx, gy] = operator.kernels();
+    let mut sum_x = 0;
+    let mut sum_y = 0;
+    for ky in 0..3 {
+        let row = &rows[ky][x - 1..];
+        for kx in 0..3 {
+            let pixel = row[kx] as i32;
+            sum_x += pixel * gx[ky][kx];
+            sum_y += pixel * gy[ky][kx];
+        }
+    }
+
+    (sum_x, sum_y)
+}
+
+// Fill in the outer one pixel ring that sobel() skips, reading past the edge according to `border`
+pub fn sobel_border(
+    input: &ImageView<u8>,
+    output: &mut ImageViewMut<u8>,
+    border: BorderMode,
+    operator: EdgeOperator,
+) {
+    let (rows, cols) = (input.height(), input.width());
+    for y in 0..rows {
+        let out_row = output.row_mut(y);
+        if y == 0 || y == rows - 1 {
+            for (x, out) in out_row.iter_mut().enumerate() {
+                *out = sobel_pixel_border(input, y, x, border, operator);
+            }
+        } else if cols > 0 {
+            out_row[0] = sobel_pixel_border(input, y, 0, border, operator);
+            out_row[cols - 1] = sobel_pixel_border(input, y, cols - 1, border, operator);
+        }
+    }
+}
+
+// sobel_pixel for any (y, x), including the edges
+pub fn sobel_pixel_border(
+    input: &ImageView<u8>,
+    y: usize,
+    x: usize,
+    border: BorderMode,
+    operator: EdgeOperator,
+) -> u8 {
+    let (sum_x, sum_y) = gradient_pixel_border(input, y, x, border, operator);
+    (sum_x.abs() + sum_y.abs()).min(255) as u8
+}
+
+// and the signed (gx, gy) behind it
+pub fn gradient_pixel_border(
+    input: &ImageView<u8>,
+    y: usize,
+    x: usize,
+    border: BorderMode,
+    operator: EdgeOperator,
+) -> (i32, i32) {
+    let (rows, cols) = (input.height(), input.width());
+    let [gx, gy] = operator.kernels();
+    let mut sum_x = 0;
+    let mut sum_y = 0;
+    for ky in 0..3 {
+        let Some(src_y) = border.resolve(y as isize + ky as isize - 1, rows) else {
+            continue;
+        };
+        let row = input.row(src_y);
+        for kx in 0..3 {
+            let Some(src_x) = border.resolve(x as isize + kx as isize - 1, cols) else {
+                continue;
+            };
+            let pixel = row[src_x] as i32;
+            sum_x += pixel * gx[ky][kx];
+            sum_y += pixel * gy[ky][kx];
+        }
+    }
+
+    (sum_x, sum_y)
+}
+
+// Left/right edge pixel of a row whose vertical neighbours have already been picked (a zero row
+// stands in for rows outside the image with BorderMode::Zero). Only resolves columns.
+pub fn sobel_pixel_rows_border(
+    rows: [&[u8]; 3],
+    x: usize,
+    border: BorderMode,
+    operator: EdgeOperator,
+) -> u8 {
+    let cols = rows[1].len();
+    let [gx, gy] = operator.kernels();
+    let mut sum_x = 0;
+    let mut sum_y = 0;
+    for ky in 0..3 {
+        for kx in 0..3 {
+            let Some(src_x) = border.resolve(x as isize + kx as isize - 1, cols) else {
+                continue;
+            };
+            let pixel = rows[ky][src_x] as i32;
+            sum_x += pixel * gx[ky][kx];
+            sum_y += pixel * gy[ky][kx];
+        }
+    }
+
+    (sum_x.abs() + sum_y.abs()).min(255) as u8
+}
+
+// Horizontal pass of a separable blur: out[x] is the weighted sum of row[x..x + weights.len()],
+// `row` being the input padded by weights.len() / 2 on each side. Weights sum to 256 so the sum
+// fits a u16.
+pub fn convolve_row(row: &[u8], weights: &[u16], out: &mut [u16]) {
+    for (x, out) in out.iter_mut().enumerate() {
+        let window = &row[x..x + weights.len()];
+        *out = window
+            .iter()
+            .zip(weights)
+            .map(|(&pixel, &weight)| pixel as u16 * weight)
+            .sum();
+    }
+}
+
+// Vertical pass: weighted sum of the same column of each horizontal pass row, then divided by
+// 256 * 256 (both passes' weights) with rounding
+pub fn convolve_column(rows: &[&[u16]], weights: &[u16], out: &mut [u8]) {
+    for (x, out) in out.iter_mut().enumerate() {
+        let sum: u32 = rows
+            .iter()
+            .zip(weights)
+            .map(|(row, &weight)| row[x] as u32 * weight as u32)
+            .sum();
+        *out = ((sum + (1 << 15)) >> 16) as u8;
+    }
+}
+
+// Mean of each size x size window from the integral image rows above and below it (`top` is
+// row y, `bottom` row y + size), rounded. The integral image may have wrapped around on a big
+// frame, the window sum still comes out right in wrapping arithmetic.
+pub fn box_row(top: &[u32], bottom: &[u32], size: usize, out: &mut [u8]) {
+    let inv_area = 1.0 / (size * size) as f32;
+    for (x, out) in out.iter_mut().enumerate() {
+        let sum = bottom[x + size]
+            .wrapping_sub(bottom[x])
+            .wrapping_sub(top[x + size])
+            .wrapping_add(top[x]);
+        *out = (sum as f32 * inv_area + 0.5) as u8;
+    }
+}
+
+// median of each rows.len() x rows.len() window, rows padded by rows.len() / 2 on each side
+pub fn median_row(rows: &[&[u8]], out: &mut [u8]) {
+    let size = rows.len();
+    let mut window = Vec::with_capacity(size * size);
+    for (x, out) in out.iter_mut().enumerate() {
+        window.clear();
+        for row in rows {
+            window.extend_from_slice(&row[x..x + size]);
+        }
+        let middle = window.len() / 2;
+        *out = *window.select_nth_unstable(middle).1;
+    }
+}
@@ -0,0 +1,225 @@
+// SSE2 / AVX2 versions of the grayscale and sobel kernels, so the lab code can be developed and
+// benchmarked on a normal x86 workstation. Output is bit-identical to my_scalar.
+//
+// Everything here is `unsafe` because of #[target_feature]: only call these after checking the
+// cpu supports the feature (see cpu_dispatch::detect).
+use std::arch::x86_64::*;
+
+use crate::edge::EdgeOperator;
+use crate::luma::{Luma, LUMA_SHIFT};
+use crate::my_scalar;
+
+// Grayscale uses the same 14 bit fixed point weights as my_scalar::grey_pixel, for BGR (channels
+// = 3) or BGRA (4) input. There's no deinterleaving load on x86 (short of pshufb tables) so the
+// channels get gathered into i16 lanes, paired up as (r, g) and (b, 1) so one pmaddwd against
+// (wr, wg) and (wb, bias) gives the full 32 bit sum per pixel. Weights and the bias are at most
+// 1 << 14, so they fit the signed multiplier.
+
+/// # Safety
+/// The cpu must support SSE2.
+#[target_feature(enable = "sse2")]
+pub unsafe fn grayscale_sse2(input: &[u8], channels: usize, out: &mut [u8], luma: Luma) {
+    let pixels = (input.len() / channels).min(out.len());
+    let weights = luma.profile.weights();
+    let rg_weights = _mm_set1_epi32(pair_weight(weights.r, weights.g));
+    let b_weights = _mm_set1_epi32(pair_weight(weights.b, luma.rounding.bias() as u16));
+    let vector_pixels = pixels - pixels % 8;
+
+    // 8 pixels per step, two pmaddwd of 4 each
+    for (index, chunk) in input[..vector_pixels * channels]
+        .chunks_exact(8 * channels)
+        .enumerate()
+    {
+        let (rg, b) = gather_pairs::<16>(chunk, channels);
+        let sum = |pairs: &[i16; 16], weights: __m128i, half: usize| {
+            _mm_madd_epi16(
+                _mm_loadu_si128(pairs.as_ptr().add(half * 8) as *const __m128i),
+                weights,
+            )
+        };
+        let grey = |half: usize| {
+            _mm_srli_epi32::<{ LUMA_SHIFT as i32 }>(_mm_add_epi32(
+                sum(&rg, rg_weights, half),
+                sum(&b, b_weights, half),
+            ))
+        };
+
+        let grey = _mm_packs_epi32(grey(0), grey(1));
+        let grey = _mm_packus_epi16(grey, _mm_setzero_si128());
+        _mm_storel_epi64(out.as_mut_ptr().add(index * 8) as *mut __m128i, grey);
+    }
+
+    my_scalar::grayscale_interleaved(
+        &input[vector_pixels * channels..pixels * channels],
+        channels,
+        &mut out[vector_pixels..pixels],
+        luma,
+    );
+}
+
+/// # Safety
+/// The cpu must support AVX2.
+#[target_feature(enable = "avx2")]
+pub unsafe fn grayscale_avx2(input: &[u8], channels: usize, out: &mut [u8], luma: Luma) {
+    let pixels = (input.len() / channels).min(out.len());
+    let weights = luma.profile.weights();
+    let rg_weights = _mm256_set1_epi32(pair_weight(weights.r, weights.g));
+    let b_weights = _mm256_set1_epi32(pair_weight(weights.b, luma.rounding.bias() as u16));
+    let vector_pixels = pixels - pixels % 16;
+
+    // 16 pixels per step, two pmaddwd of 8 each
+    for (index, chunk) in input[..vector_pixels * channels]
+        .chunks_exact(16 * channels)
+        .enumerate()
+    {
+        let (rg, b) = gather_pairs::<32>(chunk, channels);
+        let sum = |pairs: &[i16; 32], weights: __m256i, half: usize| {
+            _mm256_madd_epi16(
+                _mm256_loadu_si256(pairs.as_ptr().add(half * 16) as *const __m256i),
+                weights,
+            )
+        };
+        let grey = |half: usize| {
+            _mm256_srli_epi32::<{ LUMA_SHIFT as i32 }>(_mm256_add_epi32(
+                sum(&rg, rg_weights, half),
+                sum(&b, b_weights, half),
+            ))
+        };
+        let (low, high) = (grey(0), grey(1));
+
+        // the packs work per 128 bit lane, so split the halves and pack them in order
+        let low = _mm_packs_epi32(
+            _mm256_castsi256_si128(low),
+            _mm256_extracti128_si256::<1>(low),
+        );
+        let high = _mm_packs_epi32(
+            _mm256_castsi256_si128(high),
+            _mm256_extracti128_si256::<1>(high),
+        );
+        let grey = _mm_packus_epi16(low, high);
+        _mm_storeu_si128(out.as_mut_ptr().add(index * 16) as *mut __m128i, grey);
+    }
+
+    my_scalar::grayscale_interleaved(
+        &input[vector_pixels * channels..pixels * channels],
+        channels,
+        &mut out[vector_pixels..pixels],
+        luma,
+    );
+}
+
+// `first` in the low i16 of every i32 lane and `second` in the high one, to pmaddwd against
+fn pair_weight(first: u16, second: u16) -> i32 {
+    (second as i32) << 16 | first as i32
+}
+
+// LANES / 2 pixels as (r, g) pairs and (b, 1) pairs
+fn gather_pairs<const LANES: usize>(chunk: &[u8], channels: usize) -> ([i16; LANES], [i16; LANES]) {
+    let mut rg = [0i16; LANES];
+    let mut b = [1i16; LANES];
+    for (pixel, bgr) in chunk.chunks_exact(channels).take(LANES / 2).enumerate() {
+        rg[pixel * 2] = bgr[2] as i16;
+        rg[pixel * 2 + 1] = bgr[1] as i16;
+        b[pixel * 2] = bgr[0] as i16;
+    }
+    (rg, b)
+}
+
+// The sobel kernels produce one output row from the rows above/at/below it (see
+// my_scalar::sobel_row), skipping the first and last pixel. Only plain sobel is vectorized here,
+// the other edge operators use the scalar code on x86.
+
+/// # Safety
+/// The cpu must support SSE2.
+#[target_feature(enable = "sse2")]
+pub unsafe fn sobel_row_sse2(above: &[u8], row: &[u8], below: &[u8], out: &mut [u8]) {
+    let cols = row.len();
+    let zero = _mm_setzero_si128();
+
+    // 8 bytes of `r` starting at column `x`, widened to i16
+    let load = |r: &[u8], x: usize| {
+        _mm_unpacklo_epi8(_mm_loadl_epi64(r.as_ptr().add(x) as *const __m128i), zero)
+    };
+
+    let mut x = 1;
+    // 8 outputs at a time, as long as the loads (x - 1 .. x + 9) stay inside the row
+    while x + 9 <= cols {
+        let (a0, a1, a2) = (load(above, x - 1), load(above, x), load(above, x + 1));
+        let (b0, b2) = (load(row, x - 1), load(row, x + 1));
+        let (c0, c1, c2) = (load(below, x - 1), load(below, x), load(below, x + 1));
+
+        // gx = (a2 - a0) + 2(b2 - b0) + (c2 - c0)
+        let mid = _mm_sub_epi16(b2, b0);
+        let gx = _mm_add_epi16(
+            _mm_add_epi16(_mm_sub_epi16(a2, a0), _mm_sub_epi16(c2, c0)),
+            _mm_add_epi16(mid, mid),
+        );
+
+        // gy = (a0 + 2a1 + a2) - (c0 + 2c1 + c2)
+        let top = _mm_add_epi16(_mm_add_epi16(a0, a2), _mm_add_epi16(a1, a1));
+        let bottom = _mm_add_epi16(_mm_add_epi16(c0, c2), _mm_add_epi16(c1, c1));
+        let gy = _mm_sub_epi16(top, bottom);
+
+        // no abs_epi16 before SSSE3, max(v, -v) does the same job
+        let abs = |v| _mm_max_epi16(v, _mm_sub_epi16(zero, v));
+        let magnitude = _mm_add_epi16(abs(gx), abs(gy));
+
+        // saturating pack clamps to 0..255
+        let packed = _mm_packus_epi16(magnitude, magnitude);
+        _mm_storel_epi64(out.as_mut_ptr().add(x) as *mut __m128i, packed);
+
+        x += 8;
+    }
+
+    // leftover pixels at the end of the row
+    let end = cols.saturating_sub(1);
+    for (x, out) in out.iter_mut().enumerate().take(end).skip(x) {
+        *out = my_scalar::sobel_pixel_rows([above, row, below], x, EdgeOperator::Sobel);
+    }
+}
+
+/// # Safety
+/// The cpu must support AVX2.
+#[target_feature(enable = "avx2")]
+pub unsafe fn sobel_row_avx2(above: &[u8], row: &[u8], below: &[u8], out: &mut [u8]) {
+    let cols = row.len();
+
+    // 16 bytes of `r` starting at column `x`, widened to i16
+    let load = |r: &[u8], x: usize| {
+        _mm256_cvtepu8_epi16(_mm_loadu_si128(r.as_ptr().add(x) as *const __m128i))
+    };
+
+    let mut x = 1;
+    // 16 outputs at a time, as long as the loads (x - 1 .. x + 17) stay inside the row
+    while x + 17 <= cols {
+        let (a0, a1, a2) = (load(above, x - 1), load(above, x), load(above, x + 1));
+        let (b0, b2) = (load(row, x - 1), load(row, x + 1));
+        let (c0, c1, c2) = (load(below, x - 1), load(below, x), load(below, x + 1));
+
+        let mid = _mm256_sub_epi16(b2, b0);
+        let gx = _mm256_add_epi16(
+            _mm256_add_epi16(_mm256_sub_epi16(a2, a0), _mm256_sub_epi16(c2, c0)),
+            _mm256_add_epi16(mid, mid),
+        );
+
+        let top = _mm256_add_epi16(_mm256_add_epi16(a0, a2), _mm256_add_epi16(a1, a1));
+        let bottom = _mm256_add_epi16(_mm256_add_epi16(c0, c2), _mm256_add_epi16(c1, c1));
+        let gy = _mm256_sub_epi16(top, bottom);
+
+        let magnitude = _mm256_add_epi16(_mm256_abs_epi16(gx), _mm256_abs_epi16(gy));
+
+        // packus works per 128 bit lane, so split the halves and pack them together
+        let packed = _mm_packus_epi16(
+            _mm256_castsi256_si128(magnitude),
+            _mm256_extracti128_si256::<1>(magnitude),
+        );
+        _mm_storeu_si128(out.as_mut_ptr().add(x) as *mut __m128i, packed);
+
+        x += 16;
+    }
+
+    let end = cols.saturating_sub(1);
+    for (x, out) in out.iter_mut().enumerate().take(end).skip(x) {
+        *out = my_scalar::sobel_pixel_rows([above, row, below], x, EdgeOperator::Sobel);
+    }
+}
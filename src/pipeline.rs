@@ -0,0 +1,397 @@
+// Frame processing as a chain of stages (grayscale, blur, sobel, threshold, ...) run by one strip
+// executor, instead of every binary wiring the kernels together by hand.
+//
+// Each stage says how many rows of context it reads, the executor adds those up into the strip
+// halo so the whole chain runs inside the strips without any seams. A stage that has to see the
+// whole frame (canny's hysteresis) splits the chain: everything before it runs in strips and
+// gets stitched, then it runs on the full frame, then the next stages go back to strips.
+use std::time::Duration;
+
+use crate::border::BorderMode;
+use crate::canny::{CannyEdges, Hysteresis};
+use crate::edge::EdgeOperator;
+use crate::image::{Image, ImageView};
+use crate::luma::Luma;
+use crate::my_arm_neon::{self, Backend, FrameOptions, Mode, SOBEL_HALO};
+use crate::prefilter::{self, PreFilter};
+use crate::strips;
+use crate::timing;
+
+pub trait FrameFilter: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    // rows (and columns) of context each output pixel reads on either side
+    fn halo(&self) -> usize;
+
+    // true if the stage needs the whole frame at once rather than a strip
+    fn whole_frame(&self) -> bool {
+        false
+    }
+
+    // output has the same width and height as the input, `border` decides what the pixels past
+    // the edge read as
+    fn apply(&self, input: &ImageView<u8>, border: BorderMode) -> Image<u8>;
+}
+
+pub struct Pipeline {
+    stages: Vec<Box<dyn FrameFilter>>,
+    strips: usize,
+    border: BorderMode,
+}
+
+impl Default for Pipeline {
+    fn default() -> Self {
+        Pipeline {
+            stages: Vec::new(),
+            strips: strips::default_strip_count(),
+            border: BorderMode::default(),
+        }
+    }
+}
+
+// frames processed and the time they took, for the averages (video::run and y4m::run)
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct RunStats {
+    pub frames: u32,
+    // time spent in the pipeline
+    pub processing: Duration,
+    // wall clock time for the whole run, reading and display included
+    pub elapsed: Duration,
+    // frames skipped to keep up with the source's frame rate (--pace drop)
+    pub dropped: u32,
+}
+
+impl RunStats {
+    pub fn average(&self) -> Duration {
+        self.processing / self.frames.max(1)
+    }
+
+    pub fn fps(&self) -> f64 {
+        self.frames as f64 / self.elapsed.as_secs_f64().max(f64::EPSILON)
+    }
+}
+
+impl Pipeline {
+    // no stages yet, one strip per rayon worker and the default border
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // the same chain do_frame_with runs for these options
+    pub fn from_options(options: &FrameOptions) -> Self {
+        let pipeline = Pipeline::new()
+            .strips(options.strips)
+            .border(options.border);
+
+        if let (Mode::Sobel, Backend::Fused, PreFilter::None) =
+            (options.mode, options.backend, options.prefilter)
+        {
+            return pipeline.then(FusedEdges {
+                luma: options.luma,
+                operator: options.operator,
+            });
+        }
+
+        let mut pipeline = pipeline.then(Grayscale { luma: options.luma });
+        if options.prefilter != PreFilter::None {
+            pipeline = pipeline.then(options.prefilter);
+        }
+        match options.mode {
+            Mode::Sobel => pipeline.then(Edges {
+                operator: options.operator,
+            }),
+            Mode::Canny(canny) => pipeline
+                .then(CannyEdges {
+                    operator: options.operator,
+                    options: canny,
+                })
+                .then(Hysteresis),
+        }
+    }
+
+    pub fn strips(mut self, strips: usize) -> Self {
+        self.strips = strips;
+        self
+    }
+
+    pub fn border(mut self, border: BorderMode) -> Self {
+        self.border = border;
+        self
+    }
+
+    // add a stage to the end of the chain
+    pub fn then(mut self, stage: impl FrameFilter + 'static) -> Self {
+        self.stages.push(Box::new(stage));
+        self
+    }
+
+    pub fn strip_count(&self) -> usize {
+        self.strips
+    }
+
+    pub fn stages(&self) -> impl Iterator<Item = &dyn FrameFilter> {
+        self.stages.iter().map(|stage| stage.as_ref())
+    }
+
+    // context the whole chain reads around each output pixel
+    pub fn halo(&self) -> usize {
+        self.stages.iter().map(|stage| stage.halo()).sum()
+    }
+
+    // run every stage over `frame`, an empty pipeline just copies it
+    pub fn run(&self, frame: &ImageView<u8>) -> Image<u8> {
+        self.run_frame(frame, 0)
+    }
+
+    // run, with the stage timings (see timing) filed under frame `number`
+    pub fn run_frame(&self, frame: &ImageView<u8>, number: u64) -> Image<u8> {
+        let mut current: Option<Image<u8>> = None;
+        let mut first = 0;
+        for (index, stage) in self.stages.iter().enumerate() {
+            if !stage.whole_frame() {
+                continue;
+            }
+            let input = current.as_ref().map_or(*frame, |image| image.view());
+            let stitched = self.run_segment(&input, &self.stages[first..index], number);
+            let _span = timing::span(stage.name(), number);
+            let output = match &stitched {
+                Some(image) => stage.apply(&image.view(), self.border),
+                None => stage.apply(&input, self.border),
+            };
+            current = Some(output);
+            first = index + 1;
+        }
+
+        let input = current.as_ref().map_or(*frame, |image| image.view());
+        match self.run_segment(&input, &self.stages[first..], number) {
+            Some(output) => output,
+            None => current.unwrap_or_else(|| frame.to_image()),
+        }
+    }
+
+    // a run of strip stages, None if there aren't any
+    fn run_segment(
+        &self,
+        frame: &ImageView<u8>,
+        stages: &[Box<dyn FrameFilter>],
+        number: u64,
+    ) -> Option<Image<u8>> {
+        let (first, rest) = stages.split_first()?;
+        let halo = stages.iter().map(|stage| stage.halo()).sum();
+        let (plan, results) = strips::map_strips(frame, self.strips, halo, self.border, |strip| {
+            // the whole strip task too, so uneven strips stand out in a trace
+            let _strip_span = timing::span("strip", number);
+            let mut image = {
+                let _span = timing::span(first.name(), number);
+                first.apply(strip, self.border)
+            };
+            for stage in rest {
+                let _span = timing::span(stage.name(), number);
+                image = stage.apply(&image.view(), self.border);
+            }
+            image
+        });
+
+        let _span = timing::span("stitch", number);
+        Some(strips::stitch(frame, &plan, &results))
+    }
+}
+
+// BGR/BGRA to grey (see to442_grayscale_simd)
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Grayscale {
+    pub luma: Luma,
+}
+
+impl FrameFilter for Grayscale {
+    fn name(&self) -> &'static str {
+        "grayscale"
+    }
+
+    fn halo(&self) -> usize {
+        0
+    }
+
+    fn apply(&self, input: &ImageView<u8>, _border: BorderMode) -> Image<u8> {
+        my_arm_neon::to442_grayscale_simd(input, self.luma)
+    }
+}
+
+impl FrameFilter for PreFilter {
+    fn name(&self) -> &'static str {
+        PreFilter::name(*self)
+    }
+
+    fn halo(&self) -> usize {
+        PreFilter::halo(*self)
+    }
+
+    fn apply(&self, input: &ImageView<u8>, border: BorderMode) -> Image<u8> {
+        prefilter::apply(input, border, *self)
+    }
+}
+
+// edge magnitude of a grey image, |gx| + |gy| clamped to 255
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Edges {
+    pub operator: EdgeOperator,
+}
+
+impl FrameFilter for Edges {
+    fn name(&self) -> &'static str {
+        self.operator.name()
+    }
+
+    fn halo(&self) -> usize {
+        SOBEL_HALO
+    }
+
+    fn apply(&self, input: &ImageView<u8>, border: BorderMode) -> Image<u8> {
+        my_arm_neon::to442_sobel_simd(input, border, self.operator)
+    }
+}
+
+// Grayscale followed by Edges in one pass (Backend::Fused)
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct FusedEdges {
+    pub luma: Luma,
+    pub operator: EdgeOperator,
+}
+
+impl FrameFilter for FusedEdges {
+    fn name(&self) -> &'static str {
+        "fused"
+    }
+
+    fn halo(&self) -> usize {
+        SOBEL_HALO
+    }
+
+    fn apply(&self, input: &ImageView<u8>, border: BorderMode) -> Image<u8> {
+        my_arm_neon::to442_fused_simd(input, border, self.operator, self.luma)
+    }
+}
+
+// 255 where the input is at least `level`, 0 everywhere else
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Threshold {
+    pub level: u8,
+}
+
+impl FrameFilter for Threshold {
+    fn name(&self) -> &'static str {
+        "threshold"
+    }
+
+    fn halo(&self) -> usize {
+        0
+    }
+
+    fn apply(&self, input: &ImageView<u8>, _border: BorderMode) -> Image<u8> {
+        let mut output = Image::new(input.width(), input.height(), input.channels());
+        for (in_row, out_row) in input.rows().zip(output.rows_mut()) {
+            for (&value, out) in in_row.iter().zip(out_row.iter_mut()) {
+                *out = if value >= self.level { 255 } else { 0 };
+            }
+        }
+        output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::canny::CannyOptions;
+
+    // BGR noise over a couple of blobs, so every stage has edges to find
+    fn frame(width: usize, height: usize) -> Image<u8> {
+        let bgr = (0..width * height * 3)
+            .map(|i| {
+                let pixel = i / 3;
+                let (x, y) = ((pixel % width) as i32, (pixel / width) as i32);
+                let blob = if (x - 15).pow(2) + (y - 12).pow(2) < 80 || x > 2 * y + 30 {
+                    190
+                } else {
+                    50
+                };
+                (blob + (i * 7919 % 31) as i32) as u8
+            })
+            .collect();
+        Image::from_vec(width, height, 3, bgr)
+    }
+
+    // gaussian -> sobel -> threshold, all strip stages with their halos stacked
+    fn blur_edges(border: BorderMode) -> Pipeline {
+        Pipeline::new()
+            .border(border)
+            .then(Grayscale::default())
+            .then(PreFilter::Gaussian { sigma: 1.5 })
+            .then(Edges::default())
+            .then(Threshold { level: 60 })
+    }
+
+    // box -> canny, split at hysteresis, with more strip stages after it
+    fn box_canny(border: BorderMode) -> Pipeline {
+        Pipeline::new()
+            .border(border)
+            .then(Grayscale::default())
+            .then(PreFilter::Box { radius: 2 })
+            .then(CannyEdges {
+                operator: EdgeOperator::Sobel,
+                options: CannyOptions::default(),
+            })
+            .then(Hysteresis)
+            .then(PreFilter::Median3)
+            .then(Edges::default())
+    }
+
+    #[test]
+    fn halo_is_the_sum_of_the_stages() {
+        let pipeline = blur_edges(BorderMode::default());
+        assert_eq!(
+            pipeline.halo(),
+            PreFilter::Gaussian { sigma: 1.5 }.halo() + SOBEL_HALO
+        );
+        assert_eq!(
+            pipeline.halo(),
+            pipeline.stages().map(|stage| stage.halo()).sum::<usize>()
+        );
+
+        let canny = CannyEdges::default();
+        assert_eq!(
+            box_canny(BorderMode::default()).halo(),
+            2 + canny.halo() + 1 + SOBEL_HALO
+        );
+        assert_eq!(Pipeline::new().halo(), 0);
+    }
+
+    #[test]
+    fn strips_match_one_strip() {
+        let bgr = frame(57, 43);
+        for border in BorderMode::ALL {
+            for (name, chain) in [
+                ("blur edges", blur_edges as fn(BorderMode) -> Pipeline),
+                ("box canny", box_canny),
+            ] {
+                let whole = chain(border).strips(1).run(&bgr.view());
+                assert!(whole.data().contains(&255) && whole.data().contains(&0));
+                for strips in [2, 3, 7, 43] {
+                    assert_eq!(
+                        chain(border).strips(strips).run(&bgr.view()),
+                        whole,
+                        "{} with {} in {} strips",
+                        name,
+                        border.name(),
+                        strips
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn empty_pipeline_copies_the_frame() {
+        let bgr = frame(9, 5);
+        assert_eq!(Pipeline::new().strips(3).run(&bgr.view()), bgr);
+    }
+}
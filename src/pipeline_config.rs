@@ -0,0 +1,557 @@
+// Pipelines described in a file instead of on the command line, so trying out a different chain
+// doesn't need a recompile (or another round of send.sh for the Pis):
+//
+//     border = "replicate"   # optional, reflect by default
+//     strips = 8             # optional, one per rayon worker by default
+//
+//     [[stage]]
+//     kind = "grayscale"
+//     luma = "bt601"
+//
+//     [[stage]]
+//     kind = "gaussian"
+//     sigma = 1.2
+//
+//     [[stage]]
+//     kind = "edges"
+//     operator = "scharr"
+//
+// That's TOML, a file ending in .json is read as JSON with the same layout
+// ({"stage": [{"kind": "grayscale"}, {"kind": "gaussian", "sigma": 1.2}, ...]}). Names and
+// parameters are the same as the command line options. Frames come in as colour, so everything
+// that works on grey needs a grayscale (or fused) stage somewhere before it. lab6_host sends the
+// config to the workers as JSON along with every frame, so they run exactly the chain the host
+// was given.
+use serde::{Deserialize, Serialize};
+
+use crate::args;
+use crate::border::BorderMode;
+use crate::canny::{CannyEdges, CannyOptions, Hysteresis};
+use crate::edge::EdgeOperator;
+use crate::gradient::MagnitudeNorm;
+use crate::luma::{Luma, LumaProfile, Rounding};
+use crate::my_arm_neon::{Backend, FrameOptions, Mode};
+use crate::pipeline::{Edges, FusedEdges, Grayscale, Pipeline, Threshold};
+use crate::prefilter::{PreFilter, MAX_BOX_RADIUS, MAX_SIGMA};
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PipelineConfig {
+    pub border: Option<String>,
+    pub strips: Option<usize>,
+    // `[[stage]]` in TOML, hence the singular name
+    #[serde(rename = "stage", default)]
+    pub stages: Vec<StageConfig>,
+}
+
+// one stage, picked by `kind`. Anything left out gets the same default as on the command line.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "lowercase", deny_unknown_fields)]
+pub enum StageConfig {
+    Grayscale {
+        luma: Option<String>,
+        rounding: Option<String>,
+    },
+    #[serde(alias = "gauss")]
+    Gaussian {
+        sigma: Option<f32>,
+    },
+    #[serde(alias = "mean")]
+    Box {
+        radius: Option<usize>,
+    },
+    Median3,
+    Median5,
+    #[serde(alias = "sobel")]
+    Edges {
+        operator: Option<String>,
+    },
+    // grayscale and edges in one pass
+    Fused {
+        luma: Option<String>,
+        rounding: Option<String>,
+        operator: Option<String>,
+    },
+    // CannyEdges followed by Hysteresis
+    Canny {
+        operator: Option<String>,
+        low: Option<u16>,
+        high: Option<u16>,
+        norm: Option<String>,
+    },
+    Threshold {
+        level: u8,
+    },
+}
+
+impl StageConfig {
+    // the `kind` it was written as (aliases aside)
+    pub fn kind(&self) -> &'static str {
+        match self {
+            StageConfig::Grayscale { .. } => "grayscale",
+            StageConfig::Gaussian { .. } => "gaussian",
+            StageConfig::Box { .. } => "box",
+            StageConfig::Median3 => "median3",
+            StageConfig::Median5 => "median5",
+            StageConfig::Edges { .. } => "edges",
+            StageConfig::Fused { .. } => "fused",
+            StageConfig::Canny { .. } => "canny",
+            StageConfig::Threshold { .. } => "threshold",
+        }
+    }
+
+    // the stages whose kernels only take one channel
+    fn needs_grey(&self) -> bool {
+        !matches!(
+            self,
+            StageConfig::Grayscale { .. }
+                | StageConfig::Fused { .. }
+                | StageConfig::Threshold { .. }
+        )
+    }
+
+    // whether what comes out is grey whatever went in
+    fn makes_grey(&self) -> bool {
+        !matches!(self, StageConfig::Threshold { .. })
+    }
+}
+
+impl PipelineConfig {
+    pub fn from_toml(text: &str) -> Result<PipelineConfig, String> {
+        toml::from_str(text).map_err(|err| format!("bad pipeline config: {}", err))
+    }
+
+    pub fn from_json(text: &str) -> Result<PipelineConfig, String> {
+        serde_json::from_str(text).map_err(|err| format!("bad pipeline config: {}", err))
+    }
+
+    // what lab6_host sends to the workers
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).expect("pipeline config always serializes")
+    }
+
+    // JSON if the file ends in .json, TOML otherwise
+    pub fn load(path: &str) -> Result<PipelineConfig, String> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|err| format!("couldn't read pipeline config '{}': {}", path, err))?;
+        let config = if path.to_ascii_lowercase().ends_with(".json") {
+            PipelineConfig::from_json(&text)
+        } else {
+            PipelineConfig::from_toml(&text)
+        };
+        config.map_err(|message| format!("{} ({})", message, path))
+    }
+
+    // `--pipeline FILE`, None if it isn't given
+    pub fn from_args(args: &[String]) -> Result<Option<PipelineConfig>, String> {
+        args::option_value(args, "pipeline")
+            .map(PipelineConfig::load)
+            .transpose()
+    }
+
+    // The chain Pipeline::from_options builds for `options`, written out as a config. This is
+    // what lab6_host sends when it wasn't given a --pipeline, so --operator, --luma and the rest
+    // reach the workers too. `strips` only when it was given, each worker knows its own cores.
+    pub fn from_options(options: &FrameOptions, strips: Option<usize>) -> PipelineConfig {
+        let (luma, rounding) = luma_names(options.luma);
+        let operator = Some(options.operator.name().to_string());
+
+        let mut stages = Vec::new();
+        if let (Mode::Sobel, Backend::Fused, PreFilter::None) =
+            (options.mode, options.backend, options.prefilter)
+        {
+            stages.push(StageConfig::Fused {
+                luma,
+                rounding,
+                operator,
+            });
+        } else {
+            stages.push(StageConfig::Grayscale { luma, rounding });
+            match options.prefilter {
+                PreFilter::None => {}
+                PreFilter::Gaussian { sigma } => {
+                    stages.push(StageConfig::Gaussian { sigma: Some(sigma) })
+                }
+                PreFilter::Box { radius } => stages.push(StageConfig::Box {
+                    radius: Some(radius),
+                }),
+                PreFilter::Median3 => stages.push(StageConfig::Median3),
+                PreFilter::Median5 => stages.push(StageConfig::Median5),
+            }
+            stages.push(match options.mode {
+                Mode::Sobel => StageConfig::Edges { operator },
+                Mode::Canny(canny) => StageConfig::Canny {
+                    operator,
+                    low: Some(canny.low),
+                    high: Some(canny.high),
+                    norm: Some(canny.norm.name().to_string()),
+                },
+            });
+        }
+
+        PipelineConfig {
+            border: Some(options.border.name().to_string()),
+            strips,
+            stages,
+        }
+    }
+
+    // Check every name and parameter, and that the stages come in an order that works on colour
+    // frames, and turn the config into stages
+    pub fn build(&self) -> Result<Pipeline, String> {
+        let mut pipeline = Pipeline::new();
+        if let Some(border) = &self.border {
+            pipeline = pipeline.border(parse_name(
+                "border mode",
+                border,
+                BorderMode::from_name,
+                "zero, replicate, reflect or wrap",
+            )?);
+        }
+        if let Some(strips) = self.strips {
+            if strips == 0 {
+                return Err("strips must be at least 1".to_string());
+            }
+            pipeline = pipeline.strips(strips);
+        }
+
+        // frames come in as BGR (or BGRA), the kernels after grayscale only take grey
+        let mut grey = false;
+        for (index, stage) in self.stages.iter().enumerate() {
+            if stage.needs_grey() && !grey {
+                return Err(format!(
+                    "stage {} ({}) needs grey input, put a grayscale or fused stage before it",
+                    index + 1,
+                    stage.kind()
+                ));
+            }
+            grey |= stage.makes_grey();
+
+            pipeline = match stage {
+                StageConfig::Grayscale { luma, rounding } => pipeline.then(Grayscale {
+                    luma: parse_luma(luma, rounding)?,
+                }),
+                StageConfig::Gaussian { sigma } => {
+                    let sigma = sigma.unwrap_or(1.0);
+                    if !(sigma > 0.0 && sigma <= MAX_SIGMA) {
+                        return Err(format!(
+                            "gaussian sigma {} is out of range, expected above 0 and up to {}",
+                            sigma, MAX_SIGMA
+                        ));
+                    }
+                    pipeline.then(PreFilter::Gaussian { sigma })
+                }
+                StageConfig::Box { radius } => {
+                    let radius = radius.unwrap_or(1);
+                    if !(1..=MAX_BOX_RADIUS).contains(&radius) {
+                        return Err(format!(
+                            "box radius {} is out of range, expected 1 to {}",
+                            radius, MAX_BOX_RADIUS
+                        ));
+                    }
+                    pipeline.then(PreFilter::Box { radius })
+                }
+                StageConfig::Median3 => pipeline.then(PreFilter::Median3),
+                StageConfig::Median5 => pipeline.then(PreFilter::Median5),
+                StageConfig::Edges { operator } => pipeline.then(Edges {
+                    operator: parse_operator(operator)?,
+                }),
+                StageConfig::Fused {
+                    luma,
+                    rounding,
+                    operator,
+                } => pipeline.then(FusedEdges {
+                    luma: parse_luma(luma, rounding)?,
+                    operator: parse_operator(operator)?,
+                }),
+                StageConfig::Canny {
+                    operator,
+                    low,
+                    high,
+                    norm,
+                } => {
+                    let default = CannyOptions::default();
+                    let options = CannyOptions {
+                        low: low.unwrap_or(default.low),
+                        high: high.unwrap_or(default.high),
+                        norm: match norm {
+                            None => default.norm,
+                            Some(name) => {
+                                parse_name("norm", name, MagnitudeNorm::from_name, "l1 or l2")?
+                            }
+                        },
+                    };
+                    if options.low > options.high {
+                        return Err(format!(
+                            "canny low ({}) must not be above high ({})",
+                            options.low, options.high
+                        ));
+                    }
+                    pipeline
+                        .then(CannyEdges {
+                            operator: parse_operator(operator)?,
+                            options,
+                        })
+                        .then(Hysteresis)
+                }
+                StageConfig::Threshold { level } => pipeline.then(Threshold { level: *level }),
+            };
+        }
+        Ok(pipeline)
+    }
+}
+
+fn parse_name<T>(
+    what: &str,
+    name: &str,
+    from_name: fn(&str) -> Option<T>,
+    expected: &str,
+) -> Result<T, String> {
+    from_name(name).ok_or_else(|| format!("unknown {} '{}', expected {}", what, name, expected))
+}
+
+fn parse_luma(luma: &Option<String>, rounding: &Option<String>) -> Result<Luma, String> {
+    let mut parsed = Luma::default();
+    if let Some(name) = luma {
+        parsed.profile = parse_name(
+            "luma profile",
+            name,
+            LumaProfile::from_name,
+            "bt601, bt709, bt2020, average or custom:R,G,B",
+        )?;
+    }
+    if let Some(name) = rounding {
+        parsed.rounding = parse_name("rounding", name, Rounding::from_name, "truncate or round")?;
+    }
+    Ok(parsed)
+}
+
+// the names parse_luma takes back
+fn luma_names(luma: Luma) -> (Option<String>, Option<String>) {
+    let profile = match luma.profile {
+        LumaProfile::Custom(weights) => {
+            format!("custom:{},{},{}", weights.r, weights.g, weights.b)
+        }
+        profile => profile.name().to_string(),
+    };
+    (Some(profile), Some(luma.rounding.name().to_string()))
+}
+
+fn parse_operator(operator: &Option<String>) -> Result<EdgeOperator, String> {
+    match operator {
+        None => Ok(EdgeOperator::default()),
+        Some(name) => {
+            let names: Vec<&str> = EdgeOperator::ALL.iter().map(|op| op.name()).collect();
+            parse_name(
+                "edge operator",
+                name,
+                EdgeOperator::from_name,
+                &format!("one of {}", names.join(", ")),
+            )
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image::Image;
+
+    // the example at the top of this file
+    const TOML_EXAMPLE: &str = r#"
+border = "replicate"
+strips = 8
+
+[[stage]]
+kind = "grayscale"
+luma = "bt601"
+
+[[stage]]
+kind = "gaussian"
+sigma = 1.2
+
+[[stage]]
+kind = "edges"
+operator = "scharr"
+"#;
+
+    const JSON_EXAMPLE: &str = r#"{"stage": [
+        {"kind": "grayscale"},
+        {"kind": "gaussian", "sigma": 1.2},
+        {"kind": "edges", "operator": "scharr"}
+    ]}"#;
+
+    fn build_error(text: &str) -> String {
+        PipelineConfig::from_toml(text)
+            .and_then(|config| config.build())
+            .err()
+            .expect("config should have been rejected")
+    }
+
+    #[test]
+    fn toml_example_builds() {
+        let config = PipelineConfig::from_toml(TOML_EXAMPLE).unwrap();
+        assert_eq!(config.border.as_deref(), Some("replicate"));
+        assert_eq!(config.strips, Some(8));
+        assert_eq!(
+            config.stages,
+            [
+                StageConfig::Grayscale {
+                    luma: Some("bt601".to_string()),
+                    rounding: None,
+                },
+                StageConfig::Gaussian { sigma: Some(1.2) },
+                StageConfig::Edges {
+                    operator: Some("scharr".to_string()),
+                },
+            ]
+        );
+        let pipeline = config.build().unwrap();
+        assert_eq!(pipeline.strip_count(), 8);
+
+        let frame = Image::<u8>::new(16, 12, 3);
+        let edges = pipeline.run(&frame.view());
+        assert_eq!(
+            (edges.width(), edges.height(), edges.channels()),
+            (16, 12, 1)
+        );
+    }
+
+    #[test]
+    fn json_example_builds_and_round_trips() {
+        let config = PipelineConfig::from_json(JSON_EXAMPLE).unwrap();
+        assert_eq!(config.stages.len(), 3);
+        assert!(config.build().is_ok());
+
+        // what lab6_host sends is what the workers read back
+        assert_eq!(
+            PipelineConfig::from_json(&config.to_json()).unwrap(),
+            config
+        );
+    }
+
+    #[test]
+    fn options_as_a_config_run_the_same_chain() {
+        let options = [
+            FrameOptions::default(),
+            FrameOptions {
+                strips: 3,
+                operator: EdgeOperator::Scharr,
+                luma: Luma {
+                    profile: LumaProfile::from_name("custom:1,2,3").unwrap(),
+                    rounding: Rounding::Nearest,
+                },
+                prefilter: PreFilter::Gaussian { sigma: 1.5 },
+                ..Default::default()
+            },
+            FrameOptions {
+                backend: Backend::Fused,
+                border: BorderMode::Wrap,
+                ..Default::default()
+            },
+            FrameOptions {
+                mode: Mode::Canny(CannyOptions::default()),
+                prefilter: PreFilter::Median3,
+                ..Default::default()
+            },
+        ];
+
+        let frame = Image::from_vec(
+            23,
+            17,
+            3,
+            (0..23 * 17 * 3).map(|i| (i * 37 % 256) as u8).collect(),
+        );
+        for options in options {
+            // through JSON, like it goes to the workers
+            let json = PipelineConfig::from_options(&options, None).to_json();
+            let config = PipelineConfig::from_json(&json).unwrap();
+            let from_config = config.build().unwrap().strips(options.strips);
+            let expected = Pipeline::from_options(&options);
+
+            let names = |pipeline: &Pipeline| -> Vec<String> {
+                pipeline
+                    .stages()
+                    .map(|stage| stage.name().to_string())
+                    .collect()
+            };
+            assert_eq!(names(&from_config), names(&expected), "{}", json);
+            assert_eq!(
+                from_config.run(&frame.view()),
+                expected.run(&frame.view()),
+                "{}",
+                json
+            );
+        }
+    }
+
+    #[test]
+    fn unknown_kind_is_rejected() {
+        let message = build_error("[[stage]]\nkind = \"sharpen\"\n");
+        assert!(message.contains("sharpen"), "{}", message);
+        // and so are unknown names inside a known one
+        let message = build_error("[[stage]]\nkind = \"fused\"\noperator = \"nope\"\n");
+        assert!(
+            message.contains("unknown edge operator 'nope'"),
+            "{}",
+            message
+        );
+    }
+
+    #[test]
+    fn sigma_out_of_range_is_rejected() {
+        for sigma in ["0.0", "-1.0", "100.0"] {
+            let message = build_error(&format!(
+                "[[stage]]\nkind = \"grayscale\"\n[[stage]]\nkind = \"gaussian\"\nsigma = {}\n",
+                sigma
+            ));
+            assert!(message.contains("out of range"), "{}", message);
+        }
+    }
+
+    #[test]
+    fn canny_low_above_high_is_rejected() {
+        let message = build_error(
+            "[[stage]]\nkind = \"grayscale\"\n[[stage]]\nkind = \"canny\"\nlow = 90\nhigh = 30\n",
+        );
+        assert!(message.contains("must not be above high"), "{}", message);
+    }
+
+    #[test]
+    fn zero_strips_are_rejected() {
+        let message = build_error("strips = 0\n[[stage]]\nkind = \"fused\"\n");
+        assert!(message.contains("strips must be at least 1"), "{}", message);
+    }
+
+    #[test]
+    fn grey_stages_need_a_grayscale_first() {
+        for kind in ["edges", "canny", "gaussian", "box", "median3", "median5"] {
+            let message = build_error(&format!("[[stage]]\nkind = \"{}\"\n", kind));
+            assert!(
+                message.contains("needs grey input"),
+                "{}: {}",
+                kind,
+                message
+            );
+        }
+        // threshold doesn't make colour grey
+        let message = build_error(
+            "[[stage]]\nkind = \"threshold\"\nlevel = 9\n[[stage]]\nkind = \"edges\"\n",
+        );
+        assert!(message.contains("stage 2 (edges)"), "{}", message);
+    }
+
+    #[test]
+    fn grey_stages_after_grayscale_or_fused_build() {
+        let configs = [
+            "[[stage]]\nkind = \"fused\"\n[[stage]]\nkind = \"median3\"\n",
+            "[[stage]]\nkind = \"threshold\"\nlevel = 9\n",
+            "[[stage]]\nkind = \"grayscale\"\n[[stage]]\nkind = \"threshold\"\nlevel = 9\n\
+             [[stage]]\nkind = \"canny\"\n",
+        ];
+        for text in configs {
+            let config = PipelineConfig::from_toml(text).unwrap();
+            assert!(config.build().is_ok(), "{}", text);
+        }
+    }
+}
@@ -0,0 +1,397 @@
+// Which frames of the input get played (`--start`, `--end`, `--every N` and `--loop N`) and how
+// fast (`--pace`). The actual seeking lives in video::VideoSource, this is just the bookkeeping
+// and the clock, so it doesn't need opencv (and can be checked without a video).
+use std::time::{Duration, Instant};
+
+use crate::args;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FrameRange {
+    // first frame played
+    pub start: u64,
+    // frame to stop before, None for the end of the video
+    pub end: Option<u64>,
+    // play every Nth frame from start, 1 for all of them
+    pub every: u64,
+    // times through the range, 0 to keep going until killed (soak tests)
+    pub loops: u32,
+}
+
+impl Default for FrameRange {
+    fn default() -> Self {
+        FrameRange {
+            start: 0,
+            end: None,
+            every: 1,
+            loops: 1,
+        }
+    }
+}
+
+impl FrameRange {
+    pub fn from_args(args: &[String]) -> Result<FrameRange, String> {
+        let range = FrameRange {
+            start: args::parse_option(args, "start", 0)?,
+            end: match args::option_value(args, "end") {
+                None => None,
+                Some(_) => Some(args::parse_option(args, "end", 0)?),
+            },
+            every: args::parse_option(args, "every", 1)?,
+            loops: args::parse_option(args, "loop", 1)?,
+        };
+
+        if range.every == 0 {
+            return Err("--every must be at least 1".to_string());
+        }
+        if range.end.is_some_and(|end| end <= range.start) {
+            return Err("--end has to come after --start".to_string());
+        }
+        Ok(range)
+    }
+
+    // true if this plays the whole video once, like before these options existed
+    pub fn is_everything(&self) -> bool {
+        *self == FrameRange::default()
+    }
+
+    // The frame to play after `last` in the same pass (the first one for None), or None once the
+    // pass is past --end
+    pub fn next_frame(&self, last: Option<u64>) -> Option<u64> {
+        let next = match last {
+            None => self.start,
+            Some(last) => last + self.every,
+        };
+        match self.end {
+            Some(end) if next >= end => None,
+            _ => Some(next),
+        }
+    }
+
+    // whether there's another pass after `passes` finished ones
+    pub fn another_pass(&self, passes: u32) -> bool {
+        self.loops == 0 || passes < self.loops
+    }
+
+    // frame rate to play (or write) the picked frames at, so --every 2 still takes as long as the
+    // original instead of turning into a timelapse
+    pub fn output_fps(&self, source_fps: f64) -> f64 {
+        source_fps / self.every as f64
+    }
+}
+
+// What happens when frames come out slower than the source's frame rate
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Pacing {
+    // as fast as they come, like the labs always did (bench numbers need this)
+    #[default]
+    Off,
+    // real time, frames that are already late get skipped
+    Drop,
+    // real time when it can keep up, every frame shown (just slower) when it can't
+    Slow,
+}
+
+impl Pacing {
+    pub fn name(self) -> &'static str {
+        match self {
+            Pacing::Off => "off",
+            Pacing::Drop => "drop",
+            Pacing::Slow => "slow",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Pacing> {
+        match name.to_ascii_lowercase().as_str() {
+            "off" | "none" => Some(Pacing::Off),
+            "drop" | "realtime" => Some(Pacing::Drop),
+            "slow" | "lossless" => Some(Pacing::Slow),
+            _ => None,
+        }
+    }
+
+    // --pace, `default` if it isn't given
+    pub fn from_args(args: &[String], default: Pacing) -> Result<Pacing, String> {
+        match args::option_value(args, "pace") {
+            None => Ok(default),
+            Some(name) => Pacing::from_name(name)
+                .ok_or_else(|| format!("unknown pacing '{}', expected off, drop or slow", name)),
+        }
+    }
+}
+
+// with Pacing::Drop, this far behind the clock just starts over from now instead of dropping
+// everything until it catches up (which it never would when writing every frame to a file)
+const RESYNC: Duration = Duration::from_secs(1);
+
+// Hands out frames on the source's clock: each one waits for its slot, one that's more than a
+// frame behind is late and gets dropped or pushes the clock back, depending on the pacing.
+pub struct Presenter {
+    pacing: Pacing,
+    interval: Duration,
+    // when the next frame is due, None until the first one
+    next: Option<Instant>,
+    shown: u32,
+    dropped: u32,
+}
+
+impl Presenter {
+    pub fn new(pacing: Pacing, fps: f64) -> Presenter {
+        Presenter {
+            pacing,
+            interval: Duration::from_secs_f64(1.0 / fps.max(f64::EPSILON)),
+            next: None,
+            shown: 0,
+            dropped: 0,
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.pacing != Pacing::Off
+    }
+
+    // Wait for the next frame's turn, false if it's too late and should be dropped. With pacing
+    // off this never waits and always says yes. Call it once the frame is ready to go up, so the
+    // processing counts against the clock. This sleeps the thread, async code (the lab 6 host)
+    // uses schedule() and sleeps on its own timer instead.
+    pub fn present(&mut self) -> bool {
+        self.present_at(Instant::now())
+    }
+
+    // present() with `now` passed in, so the decisions can be checked without a real clock
+    fn present_at(&mut self, now: Instant) -> bool {
+        match self.schedule_at(now) {
+            Some(wait) => {
+                if !wait.is_zero() {
+                    std::thread::sleep(wait);
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    // The same decision as present() without the waiting: how long until the next frame should
+    // go up, or None if it's too late and should be dropped. Counts it as shown or dropped.
+    pub fn schedule(&mut self) -> Option<Duration> {
+        self.schedule_at(Instant::now())
+    }
+
+    fn schedule_at(&mut self, now: Instant) -> Option<Duration> {
+        if !self.is_enabled() {
+            self.shown += 1;
+            return Some(Duration::ZERO);
+        }
+
+        let due = *self.next.get_or_insert(now);
+        self.next = Some(due + self.interval);
+        let mut wait = Duration::ZERO;
+        if now < due {
+            wait = due - now;
+        } else if now - due > self.interval {
+            match self.pacing {
+                Pacing::Drop => {
+                    if now - due > RESYNC {
+                        self.next = Some(now + self.interval);
+                    }
+                    self.dropped += 1;
+                    return None;
+                }
+                // everything after this one is later by however far behind it was
+                _ => self.next = Some(now + self.interval),
+            }
+        }
+        self.shown += 1;
+        Some(wait)
+    }
+
+    // Whether the next present() is going to drop its frame no matter what, so there's no point
+    // processing it unless something else needs it. Never waits.
+    pub fn is_late(&self) -> bool {
+        self.is_late_at(Instant::now())
+    }
+
+    fn is_late_at(&self, now: Instant) -> bool {
+        match (self.pacing, self.next) {
+            (Pacing::Drop, Some(due)) => now > due && now - due > self.interval,
+            _ => false,
+        }
+    }
+
+    pub fn shown(&self) -> u32 {
+        self.shown
+    }
+
+    pub fn dropped(&self) -> u32 {
+        self.dropped
+    }
+
+    // "Dropped 12 of 300 frames to keep up with 29.97 fps", nothing if there weren't any
+    pub fn print_dropped(&self) {
+        if self.dropped > 0 {
+            println!(
+                "Dropped {} of {} frames to keep up with {:.2} fps",
+                self.dropped,
+                self.dropped + self.shown,
+                1.0 / self.interval.as_secs_f64()
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn range(command_line: &str) -> Result<FrameRange, String> {
+        let args: Vec<String> = command_line
+            .split_whitespace()
+            .map(str::to_string)
+            .collect();
+        FrameRange::from_args(&args)
+    }
+
+    fn ms(millis: f64) -> Duration {
+        Duration::from_secs_f64(millis / 1000.0)
+    }
+
+    #[test]
+    fn range_options() {
+        assert!(range("").unwrap().is_everything());
+        assert_eq!(
+            range("--start 10 --end 20 --every 3 --loop 0").unwrap(),
+            FrameRange {
+                start: 10,
+                end: Some(20),
+                every: 3,
+                loops: 0,
+            }
+        );
+        assert!(range("--every 0").is_err());
+        assert!(range("--start 5 --end 5").is_err());
+        assert!(range("--start five").is_err());
+    }
+
+    #[test]
+    fn next_frame_steps_by_every_and_stops_at_end() {
+        let range = range("--start 3 --end 10 --every 3").unwrap();
+        let mut frames = Vec::new();
+        let mut last = None;
+        while let Some(frame) = range.next_frame(last) {
+            frames.push(frame);
+            last = Some(frame);
+        }
+        assert_eq!(frames, [3, 6, 9]);
+
+        // no --end just keeps going
+        let everything = FrameRange::default();
+        assert_eq!(everything.next_frame(None), Some(0));
+        assert_eq!(everything.next_frame(Some(1_000_000)), Some(1_000_001));
+    }
+
+    #[test]
+    fn passes() {
+        let once = FrameRange::default();
+        assert!(once.another_pass(0));
+        assert!(!once.another_pass(1));
+
+        let twice = range("--loop 2").unwrap();
+        assert!(twice.another_pass(1));
+        assert!(!twice.another_pass(2));
+
+        let forever = range("--loop 0").unwrap();
+        assert!(forever.another_pass(u32::MAX));
+    }
+
+    #[test]
+    fn output_fps_keeps_the_original_duration() {
+        assert_eq!(FrameRange::default().output_fps(30.0), 30.0);
+        assert_eq!(range("--every 2").unwrap().output_fps(30.0), 15.0);
+    }
+
+    #[test]
+    fn pacing_off_shows_everything() {
+        let mut presenter = Presenter::new(Pacing::Off, 1000.0);
+        let start = Instant::now();
+        for i in 0..10 {
+            assert!(!presenter.is_late_at(start + ms(100.0 * i as f64)));
+            assert!(presenter.present_at(start + ms(100.0 * i as f64)));
+        }
+        assert_eq!((presenter.shown(), presenter.dropped()), (10, 0));
+    }
+
+    #[test]
+    fn early_frames_wait_for_their_slot() {
+        let mut presenter = Presenter::new(Pacing::Drop, 1000.0);
+        let start = Instant::now();
+        assert!(presenter.present_at(start));
+        assert!(presenter.present_at(start));
+        assert!(Instant::now() >= start + ms(1.0));
+    }
+
+    #[test]
+    fn schedule_says_how_long_to_wait() {
+        let mut presenter = Presenter::new(Pacing::Slow, 100.0);
+        let start = Instant::now();
+        assert_eq!(presenter.schedule_at(start), Some(Duration::ZERO));
+        // due at 10ms, 20ms, ... and none of these wait on the real clock
+        assert_eq!(presenter.schedule_at(start + ms(4.0)), Some(ms(6.0)));
+        assert_eq!(
+            presenter.schedule_at(start + ms(20.0)),
+            Some(Duration::ZERO)
+        );
+        assert_eq!(presenter.schedule_at(start + ms(21.0)), Some(ms(9.0)));
+        assert!(Instant::now() < start + ms(30.0));
+
+        let mut presenter = Presenter::new(Pacing::Drop, 100.0);
+        assert_eq!(presenter.schedule_at(start), Some(Duration::ZERO));
+        assert_eq!(presenter.schedule_at(start + ms(25.0)), None);
+        assert_eq!((presenter.shown(), presenter.dropped()), (1, 1));
+    }
+
+    #[test]
+    fn drop_skips_late_frames() {
+        let mut presenter = Presenter::new(Pacing::Drop, 1000.0);
+        let start = Instant::now();
+        assert!(presenter.present_at(start));
+        // on time, then a bit late but less than a frame
+        assert!(presenter.present_at(start + ms(1.0)));
+        assert!(presenter.present_at(start + ms(2.5)));
+
+        // due at 3ms, two frames behind at 5ms
+        assert!(presenter.is_late_at(start + ms(5.0)));
+        assert!(!presenter.present_at(start + ms(5.0)));
+        // the next one was due at 4ms, which is close enough again
+        assert!(!presenter.is_late_at(start + ms(5.0)));
+        assert!(presenter.present_at(start + ms(5.0)));
+        assert_eq!((presenter.shown(), presenter.dropped()), (4, 1));
+    }
+
+    #[test]
+    fn drop_resyncs_after_a_long_stall() {
+        let mut presenter = Presenter::new(Pacing::Drop, 1000.0);
+        let start = Instant::now();
+        assert!(presenter.present_at(start));
+
+        // way past RESYNC, this one's dropped but the clock starts over from here
+        let stall = start + RESYNC + ms(500.0);
+        assert!(!presenter.present_at(stall));
+        assert!(!presenter.is_late_at(stall + ms(1.0)));
+        assert!(presenter.present_at(stall + ms(1.0)));
+        assert_eq!((presenter.shown(), presenter.dropped()), (2, 1));
+    }
+
+    #[test]
+    fn slow_shows_late_frames_and_pushes_the_clock_back() {
+        let mut presenter = Presenter::new(Pacing::Slow, 1000.0);
+        let start = Instant::now();
+        assert!(presenter.present_at(start));
+
+        // four frames behind, still shown and never late
+        assert!(!presenter.is_late_at(start + ms(5.0)));
+        assert!(presenter.present_at(start + ms(5.0)));
+        // and the next one is due a frame after that, not at 2ms
+        assert!(presenter.present_at(start + ms(6.0)));
+        assert!(!presenter.is_late_at(start + ms(6.5)));
+        assert_eq!((presenter.shown(), presenter.dropped()), (3, 0));
+    }
+}
@@ -0,0 +1,340 @@
+// Smoothing filters that run on the grey image before the gradient, so noise in the footage
+// doesn't turn into a mess of tiny edges.
+//
+// Each filter works on a copy of its strip padded by the filter radius according to the border
+// mode, so the row kernels (my_scalar / my_arm_neon) never have to deal with the image edge.
+use crate::args;
+use crate::border::BorderMode;
+use crate::image::{Image, ImageView};
+use crate::my_arm_neon::{self, FrameOptions};
+use crate::strips;
+
+pub const MAX_SIGMA: f32 = 10.0;
+pub const MAX_BOX_RADIUS: usize = 15;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum PreFilter {
+    #[default]
+    None,
+    // separable gaussian, the kernel reaches out to 3 sigma
+    Gaussian {
+        sigma: f32,
+    },
+    // mean of the (2 * radius + 1)^2 window, from an integral image so big radii cost the same
+    Box {
+        radius: usize,
+    },
+    // median of the 3x3 / 5x5 window, good against salt and pepper noise
+    Median3,
+    Median5,
+}
+
+impl PreFilter {
+    pub fn name(self) -> &'static str {
+        match self {
+            PreFilter::None => "none",
+            PreFilter::Gaussian { .. } => "gaussian",
+            PreFilter::Box { .. } => "box",
+            PreFilter::Median3 => "median3",
+            PreFilter::Median5 => "median5",
+        }
+    }
+
+    // `none`, `gaussian[:SIGMA]` (1.0 by default), `box[:RADIUS]` (1 by default), `median3`
+    // or `median5`
+    pub fn from_name(name: &str) -> Option<PreFilter> {
+        let name = name.to_ascii_lowercase();
+        let (kind, parameter) = match name.split_once(':') {
+            Some((kind, parameter)) => (kind, Some(parameter.trim())),
+            None => (name.as_str(), None),
+        };
+
+        match (kind, parameter) {
+            ("none", None) => Some(PreFilter::None),
+            ("gaussian" | "gauss", _) => {
+                let sigma = parameter.map_or(Some(1.0), |p| p.parse().ok())?;
+                (sigma > 0.0 && sigma <= MAX_SIGMA).then_some(PreFilter::Gaussian { sigma })
+            }
+            ("box" | "mean", _) => {
+                let radius = parameter.map_or(Some(1), |p| p.parse().ok())?;
+                (1..=MAX_BOX_RADIUS)
+                    .contains(&radius)
+                    .then_some(PreFilter::Box { radius })
+            }
+            ("median3", None) => Some(PreFilter::Median3),
+            ("median5", None) => Some(PreFilter::Median5),
+            _ => None,
+        }
+    }
+
+    // `--prefilter NAME`, none if not given
+    pub fn from_args(args: &[String]) -> Result<PreFilter, String> {
+        match args::option_value(args, "prefilter") {
+            None => Ok(PreFilter::default()),
+            Some(name) => PreFilter::from_name(name).ok_or_else(|| {
+                format!(
+                    "unknown prefilter '{}', expected none, gaussian:SIGMA (up to {}), box:RADIUS (1 to {}), median3 or median5",
+                    name, MAX_SIGMA, MAX_BOX_RADIUS
+                )
+            }),
+        }
+    }
+
+    // rows of context the filter needs on each side, added to the strip halo
+    pub fn halo(self) -> usize {
+        match self {
+            PreFilter::None => 0,
+            PreFilter::Gaussian { sigma } => gaussian_radius(sigma),
+            PreFilter::Box { radius } => radius,
+            PreFilter::Median3 => 1,
+            PreFilter::Median5 => 2,
+        }
+    }
+}
+
+// the pre-filter from `options` over a whole grey image, strip parallel like do_frame
+pub fn filter_frame(grey: &ImageView<u8>, options: &FrameOptions) -> Image<u8> {
+    strips::run_strips(
+        grey,
+        options.strips,
+        options.prefilter.halo(),
+        options.border,
+        |strip| apply(strip, options.border, options.prefilter),
+    )
+}
+
+// one grey strip (or image) through `filter`, same size out
+pub fn apply(grey: &ImageView<u8>, border: BorderMode, filter: PreFilter) -> Image<u8> {
+    assert_eq!(
+        grey.channels(),
+        1,
+        "pre-filters expect a single channel image"
+    );
+    match filter {
+        PreFilter::None => grey.to_image(),
+        PreFilter::Gaussian { sigma } => gaussian(grey, border, &gaussian_weights(sigma)),
+        PreFilter::Box { radius } => box_filter(grey, border, radius),
+        PreFilter::Median3 => median(grey, border, 3),
+        PreFilter::Median5 => median(grey, border, 5),
+    }
+}
+
+fn gaussian_radius(sigma: f32) -> usize {
+    ((3.0 * sigma).ceil() as usize).max(1)
+}
+
+// Sampled gaussian in 8 bit fixed point, summing to exactly 256 like the luma weights (the
+// leftover from rounding down goes to the taps that lost the most)
+fn gaussian_weights(sigma: f32) -> Vec<u16> {
+    let radius = gaussian_radius(sigma) as isize;
+    let raw: Vec<f32> = (-radius..=radius)
+        .map(|x| (-((x * x) as f32) / (2.0 * sigma * sigma)).exp())
+        .collect();
+    let total: f32 = raw.iter().sum();
+
+    let scaled: Vec<f32> = raw.iter().map(|w| w / total * 256.0).collect();
+    let mut weights: Vec<u16> = scaled.iter().map(|w| w.floor() as u16).collect();
+    let mut order: Vec<usize> = (0..weights.len()).collect();
+    order.sort_by(|&a, &b| {
+        let fraction = |i: usize| scaled[i] - scaled[i].floor();
+        fraction(b).total_cmp(&fraction(a))
+    });
+    let leftover = 256 - weights.iter().sum::<u16>();
+    for &i in order.iter().take(leftover as usize) {
+        weights[i] += 1;
+    }
+    weights
+}
+
+// copy of `grey` with `radius` extra pixels on every side, filled in according to `border`
+fn pad(grey: &ImageView<u8>, radius: usize, border: BorderMode) -> Image<u8> {
+    let (rows, cols) = (grey.height(), grey.width());
+    let mut padded = Image::new(cols + 2 * radius, rows + 2 * radius, 1);
+    for (py, out_row) in padded.rows_mut().enumerate() {
+        // rows outside the image with BorderMode::Zero stay zero
+        let Some(src_y) = border.resolve(py as isize - radius as isize, rows) else {
+            continue;
+        };
+        let row = grey.row(src_y);
+        out_row[radius..radius + cols].copy_from_slice(row);
+        for px in (0..radius).chain(radius + cols..cols + 2 * radius) {
+            if let Some(src_x) = border.resolve(px as isize - radius as isize, cols) {
+                out_row[px] = row[src_x];
+            }
+        }
+    }
+    padded
+}
+
+// horizontal pass into u16 (every padded row, the vertical pass needs the padding too), then
+// the vertical pass back down to u8
+fn gaussian(grey: &ImageView<u8>, border: BorderMode, weights: &[u16]) -> Image<u8> {
+    let padded = pad(grey, weights.len() / 2, border);
+
+    let mut horizontal: Image<u16> = Image::new(grey.width(), padded.height(), 1);
+    for (row, out_row) in padded.view().rows().zip(horizontal.rows_mut()) {
+        my_arm_neon::convolve_row(row, weights, out_row);
+    }
+
+    let mut output = Image::new(grey.width(), grey.height(), 1);
+    for (y, out_row) in output.rows_mut().enumerate() {
+        let window: Vec<&[u16]> = (y..y + weights.len())
+            .map(|py| horizontal.row(py))
+            .collect();
+        my_arm_neon::convolve_column(&window, weights, out_row);
+    }
+    output
+}
+
+fn box_filter(grey: &ImageView<u8>, border: BorderMode, radius: usize) -> Image<u8> {
+    let size = 2 * radius + 1;
+    let padded = pad(grey, radius, border);
+
+    // integral[y][x] is the sum of padded[..y][..x], so it has an extra zero row and column
+    // (wrapping, see my_scalar::box_row)
+    let mut integral = Image::<u32>::new(padded.width() + 1, padded.height() + 1, 1);
+    for (y, row) in padded.view().rows().enumerate() {
+        let mut row_sum = 0u32;
+        for (x, &pixel) in row.iter().enumerate() {
+            row_sum = row_sum.wrapping_add(pixel as u32);
+            let above = integral.row(y)[x + 1];
+            integral.row_mut(y + 1)[x + 1] = above.wrapping_add(row_sum);
+        }
+    }
+
+    let mut output = Image::new(grey.width(), grey.height(), 1);
+    for (y, out_row) in output.rows_mut().enumerate() {
+        my_arm_neon::box_row(integral.row(y), integral.row(y + size), size, out_row);
+    }
+    output
+}
+
+fn median(grey: &ImageView<u8>, border: BorderMode, size: usize) -> Image<u8> {
+    let padded = pad(grey, size / 2, border);
+
+    let mut output = Image::new(grey.width(), grey.height(), 1);
+    for (y, out_row) in output.rows_mut().enumerate() {
+        let window: Vec<&[u8]> = (y..y + size).map(|py| padded.row(py)).collect();
+        my_arm_neon::median_row(&window, out_row);
+    }
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FILTERS: [PreFilter; 6] = [
+        PreFilter::Gaussian { sigma: 0.5 },
+        PreFilter::Gaussian { sigma: 2.5 },
+        PreFilter::Box { radius: 1 },
+        PreFilter::Box { radius: 4 },
+        PreFilter::Median3,
+        PreFilter::Median5,
+    ];
+
+    // gradients plus a speckle every few pixels, so every filter actually changes something
+    fn frame(width: usize, height: usize) -> Image<u8> {
+        let grey = (0..width * height)
+            .map(|i| {
+                let (x, y) = (i % width, i / width);
+                match i * 7919 % 13 {
+                    0 => 255,
+                    1 => 0,
+                    _ => (x * 5 + y * 3) as u8,
+                }
+            })
+            .collect();
+        Image::from_vec(width, height, 1, grey)
+    }
+
+    #[test]
+    fn strips_match_the_whole_frame() {
+        let grey = frame(53, 41);
+        for filter in FILTERS {
+            for border in BorderMode::ALL {
+                let options = FrameOptions {
+                    prefilter: filter,
+                    border,
+                    strips: 1,
+                    ..Default::default()
+                };
+                let whole = filter_frame(&grey.view(), &options);
+                assert_eq!(whole, apply(&grey.view(), border, filter));
+                assert_ne!(whole, grey, "{:?} did nothing", filter);
+                for strips in [2, 3, 7, 41] {
+                    let options = FrameOptions { strips, ..options };
+                    assert_eq!(
+                        filter_frame(&grey.view(), &options),
+                        whole,
+                        "{:?} with {} in {} strips",
+                        filter,
+                        border.name(),
+                        strips
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn gaussian_weights_sum_to_256() {
+        for sigma in [0.1, 0.5, 1.0, 1.3, 2.0, 3.7, MAX_SIGMA] {
+            let weights = gaussian_weights(sigma);
+            assert_eq!(weights.len(), 2 * gaussian_radius(sigma) + 1);
+            assert_eq!(weights.iter().sum::<u16>(), 256, "sigma {}", sigma);
+            // symmetric, biggest in the middle
+            let middle = weights.len() / 2;
+            assert!(weights.iter().all(|&w| w <= weights[middle]));
+        }
+    }
+
+    #[test]
+    fn flat_frames_stay_flat() {
+        let flat = Image::from_vec(9, 7, 1, vec![77; 63]);
+        for filter in FILTERS {
+            for border in [BorderMode::Replicate, BorderMode::Reflect, BorderMode::Wrap] {
+                assert_eq!(apply(&flat.view(), border, filter), flat, "{:?}", filter);
+            }
+        }
+    }
+
+    #[test]
+    fn names() {
+        assert_eq!(PreFilter::from_name("none"), Some(PreFilter::None));
+        assert_eq!(
+            PreFilter::from_name("gaussian"),
+            Some(PreFilter::Gaussian { sigma: 1.0 })
+        );
+        assert_eq!(
+            PreFilter::from_name("Gauss:2.5"),
+            Some(PreFilter::Gaussian { sigma: 2.5 })
+        );
+        assert_eq!(
+            PreFilter::from_name("box"),
+            Some(PreFilter::Box { radius: 1 })
+        );
+        assert_eq!(
+            PreFilter::from_name("mean:15"),
+            Some(PreFilter::Box { radius: 15 })
+        );
+        assert_eq!(PreFilter::from_name("median5"), Some(PreFilter::Median5));
+
+        for name in [
+            "gaussian:0",
+            "gaussian:-1",
+            "gaussian:10.5",
+            "gaussian:wide",
+            "box:0",
+            "box:16",
+            "median7",
+            "none:1",
+        ] {
+            assert_eq!(PreFilter::from_name(name), None, "{}", name);
+        }
+        assert_eq!(
+            PreFilter::from_name(&format!("gaussian:{}", MAX_SIGMA)),
+            Some(PreFilter::Gaussian { sigma: MAX_SIGMA })
+        );
+    }
+}
@@ -0,0 +1,271 @@
+// Splitting a frame into horizontal strips so each rayon worker gets its own chunk of rows.
+//
+// Neighbourhood kernels (sobel etc) need `halo` extra rows above and below a strip to compute its
+// edge rows, so every strip reads a few rows more than it produces and the results are trimmed
+// back down when they're stitched together. Rows that don't divide evenly are spread over the
+// first strips, so the output always has exactly the input's dimensions.
+use rayon::prelude::*;
+
+use crate::border::BorderMode;
+use crate::image::{Image, ImageView};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Strip {
+    pub out_start: usize, // first frame row this strip produces
+    pub out_rows: usize,  // number of rows it produces
+    pub in_start: usize,  // first frame row it reads (out_start minus the halo, clamped at 0)
+    pub in_rows: usize,   // number of rows it reads, halo included
+}
+
+impl Strip {
+    // how many halo rows sit above the strip's own rows in its input
+    pub fn top_halo(&self) -> usize {
+        self.out_start - self.in_start
+    }
+
+    // and how many below
+    pub fn bottom_halo(&self) -> usize {
+        (self.in_start + self.in_rows) - (self.out_start + self.out_rows)
+    }
+}
+
+// one strip per rayon worker
+pub fn default_strip_count() -> usize {
+    rayon::current_num_threads()
+}
+
+// Split `rows` rows into (at most) `strip_count` strips with `halo` rows of overlap on each side.
+// Never returns empty strips, so asking for more strips than rows gives one row per strip.
+pub fn plan_strips(rows: usize, strip_count: usize, halo: usize) -> Vec<Strip> {
+    let strip_count = strip_count.clamp(1, rows.max(1));
+    let base = rows / strip_count;
+    let remainder = rows % strip_count;
+
+    let mut strips = Vec::with_capacity(strip_count);
+    let mut out_start: usize = 0;
+    for index in 0..strip_count {
+        let out_rows = base + usize::from(index < remainder);
+        if out_rows == 0 {
+            break;
+        }
+        let in_start = out_start.saturating_sub(halo);
+        let in_end = (out_start + out_rows + halo).min(rows);
+        strips.push(Strip {
+            out_start,
+            out_rows,
+            in_start,
+            in_rows: in_end - in_start,
+        });
+        out_start += out_rows;
+    }
+
+    strips
+}
+
+// Run `kernel` over the strips of `frame` in parallel and stitch the results back together.
+// The kernel gets each strip with its halo and must return an image with the same number of rows.
+//
+// The kernel handles the frame edges itself with `border`, except for Wrap: the rows past the top
+// of the frame are the bottom rows, which the first strip doesn't have, so edge strips get a
+// padded copy with the wrapped rows attached.
+pub fn run_strips<T, U, F>(
+    frame: &ImageView<T>,
+    strip_count: usize,
+    halo: usize,
+    border: BorderMode,
+    kernel: F,
+) -> Image<U>
+where
+    T: Copy + Sync,
+    U: Copy + Default + Send,
+    F: Fn(&ImageView<T>) -> Image<U> + Sync,
+{
+    let (plan, results) = map_strips(frame, strip_count, halo, border, kernel);
+    stitch(frame, &plan, &results)
+}
+
+// run_strips without the stitching, for callers that time the two halves separately. Each result
+// comes with the number of padding rows above the strip's own input (Wrap only), for stitch.
+pub fn map_strips<T, U, F>(
+    frame: &ImageView<T>,
+    strip_count: usize,
+    halo: usize,
+    border: BorderMode,
+    kernel: F,
+) -> (Vec<Strip>, Vec<(Image<U>, usize)>)
+where
+    T: Copy + Sync,
+    U: Copy + Default + Send,
+    F: Fn(&ImageView<T>) -> Image<U> + Sync,
+{
+    let plan = plan_strips(frame.height(), strip_count, halo);
+
+    //move these to parallel
+    let results: Vec<(Image<U>, usize)> = plan
+        .par_iter()
+        .map(|strip| {
+            let input = frame.sub_view(0, strip.in_start, frame.width(), strip.in_rows);
+            match border {
+                BorderMode::Wrap => {
+                    let extra_top = halo - strip.top_halo();
+                    let extra_bottom = halo - strip.bottom_halo();
+                    if extra_top == 0 && extra_bottom == 0 {
+                        (kernel(&input), 0)
+                    } else {
+                        let padded = wrap_pad(frame, strip, extra_top, extra_bottom);
+                        (kernel(&padded.view()), extra_top)
+                    }
+                }
+                _ => (kernel(&input), 0),
+            }
+        })
+        .collect();
+    //end parallel
+
+    (plan, results)
+}
+
+// copy of a strip's input rows with `top`/`bottom` rows from the other end of the frame added
+fn wrap_pad<T: Copy>(frame: &ImageView<T>, strip: &Strip, top: usize, bottom: usize) -> Image<T> {
+    let first = strip.in_start as isize - top as isize;
+    let last = (strip.in_start + strip.in_rows + bottom) as isize;
+
+    let mut data = Vec::with_capacity((last - first) as usize * frame.width() * frame.channels());
+    for y in first..last {
+        let src_y = BorderMode::Wrap
+            .resolve(y, frame.height())
+            .expect("wrap always resolves");
+        data.extend_from_slice(frame.row(src_y));
+    }
+    Image::from_vec(
+        frame.width(),
+        (last - first) as usize,
+        frame.channels(),
+        data,
+    )
+}
+
+// copy the rows each strip owns (halo and any wrap padding trimmed off) into one frame
+pub fn stitch<T, U: Copy + Default>(
+    frame: &ImageView<T>,
+    plan: &[Strip],
+    results: &[(Image<U>, usize)],
+) -> Image<U> {
+    let channels = results
+        .first()
+        .map_or(frame.channels(), |(result, _)| result.channels());
+    let mut combined_frame = Image::new(frame.width(), frame.height(), channels);
+
+    for (strip, (result, padding)) in plan.iter().zip(results) {
+        assert!(
+            result.width() == frame.width() && result.height() >= padding + strip.in_rows,
+            "strip kernel changed the strip size"
+        );
+        for row in 0..strip.out_rows {
+            combined_frame
+                .row_mut(strip.out_start + row)
+                .copy_from_slice(result.row(padding + strip.top_halo() + row));
+        }
+    }
+
+    combined_frame
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // every row of 0..rows produced by exactly one strip, in order, and every strip's input
+    // covering its own rows plus as much halo as the frame has
+    fn check_plan(rows: usize, strip_count: usize, halo: usize) {
+        let plan = plan_strips(rows, strip_count, halo);
+        assert!(!plan.is_empty() && plan.len() <= strip_count.max(1));
+
+        let mut next = 0;
+        for strip in &plan {
+            assert_eq!(strip.out_start, next, "gap or overlap at row {}", next);
+            assert!(strip.out_rows > 0);
+            next += strip.out_rows;
+
+            assert_eq!(strip.top_halo(), halo.min(strip.out_start));
+            let below = rows - (strip.out_start + strip.out_rows);
+            assert_eq!(strip.bottom_halo(), halo.min(below));
+        }
+        assert_eq!(next, rows);
+    }
+
+    #[test]
+    fn plan_covers_every_row_once() {
+        // doesn't divide evenly
+        check_plan(10, 3, 1);
+        check_plan(101, 4, 2);
+        // more strips than rows
+        check_plan(5, 8, 1);
+        // a single row
+        check_plan(1, 1, 1);
+        check_plan(1, 4, 2);
+        // halo taller than a strip
+        check_plan(12, 6, 5);
+        check_plan(7, 7, 3);
+    }
+
+    #[test]
+    fn extra_rows_go_to_the_first_strips() {
+        let rows: Vec<usize> = plan_strips(10, 4, 1).iter().map(|s| s.out_rows).collect();
+        assert_eq!(rows, [3, 3, 2, 2]);
+    }
+
+    const HALO: usize = 2;
+
+    // weighted sum of the 5 rows around each pixel, edges through `border`, like the real
+    // neighbourhood kernels do on a strip
+    fn vertical_blur(strip: &ImageView<u8>, border: BorderMode) -> Image<u32> {
+        let mut out = Image::new(strip.width(), strip.height(), 1);
+        for y in 0..strip.height() {
+            for x in 0..strip.width() {
+                let mut sum = 0;
+                for (dy, weight) in [(-2, 1), (-1, 4), (0, 6), (1, 4), (2, 1)] {
+                    if let Some(src) = border.resolve(y as isize + dy, strip.height()) {
+                        sum += weight * strip.row(src)[x] as u32;
+                    }
+                }
+                out.row_mut(y)[x] = sum;
+            }
+        }
+        out
+    }
+
+    #[test]
+    fn strips_match_one_strip_for_every_border() {
+        let borders = [
+            BorderMode::Zero,
+            BorderMode::Replicate,
+            BorderMode::Reflect,
+            BorderMode::Wrap,
+        ];
+        for border in borders {
+            for rows in [1, 2, 3, 7, 16, 23] {
+                let (width, height) = (5, rows);
+                let data: Vec<u8> = (0..width * height).map(|i| (i * 37 % 251) as u8).collect();
+                let frame = Image::from_vec(width, height, 1, data);
+                let frame = frame.view();
+
+                let expected = vertical_blur(&frame, border);
+                for strip_count in [2, 3, 4, 8, 30] {
+                    let (plan, results) = map_strips(&frame, strip_count, HALO, border, |strip| {
+                        vertical_blur(strip, border)
+                    });
+                    let stitched = stitch(&frame, &plan, &results);
+                    assert_eq!(
+                        stitched,
+                        expected,
+                        "{} rows, {} strips, {} border",
+                        rows,
+                        strip_count,
+                        border.name()
+                    );
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,631 @@
+// Per stage timing for --report and --trace. Every stage of every frame (decode, each pipeline
+// stage, stitch, display, the host's network send/receive) gets timed, and at exit the times are
+// boiled down to count/mean/p50/p95/p99/max per stage, so lab3 vs lab4 vs lab5 vs the cluster
+// can be compared with numbers instead of the every-50-frames printouts, and/or written out as a
+// Chrome trace to see them on a timeline (strip imbalance, network stalls).
+//
+// Each thread keeps its own log so the rayon workers never wait on each other to record a strip.
+// The report only needs a per frame total for each stage, and frames that are long done get
+// folded into bounded per stage stats, so --report is fine on a --loop 0 soak run. The spans
+// themselves are only kept for --trace, which does grow with the run.
+//
+// Nothing gets recorded until enable() is called, a span is just an Option check otherwise.
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+
+use crate::cpu_dispatch;
+
+// one stage of one frame, on one thread
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Span {
+    pub stage: &'static str,
+    pub frame: u64,
+    // small number per thread, in the order threads first recorded something
+    pub thread: u64,
+    // since enable()
+    pub start: Duration,
+    pub duration: Duration,
+}
+
+// frames further than this behind the newest one are taken to be finished, so their stage totals
+// can't get any more strips added (the host has at most a few frames out at a time)
+const OPEN_FRAMES: u64 = 256;
+// a thread's log gets compacted once it holds this many unfinished (stage, frame) totals
+const COMPACT_AT: usize = 1024;
+// frames per stage kept for the percentiles, after that it's a random sample of them
+const MAX_SAMPLES: usize = 100_000;
+
+struct Recorder {
+    epoch: Instant,
+    // --trace, keep every span and not just the totals
+    keep_spans: bool,
+    threads: Mutex<Vec<Arc<Mutex<ThreadLog>>>>,
+    finished: Mutex<Stages>,
+    // highest frame number recorded so far
+    newest: AtomicU64,
+}
+
+// what one thread recorded, only ever locked by someone else while compacting
+struct ThreadLog {
+    thread: u64,
+    // the thread's track name in the trace
+    name: String,
+    open: FrameTotals,
+    compact_at: usize,
+    spans: Vec<Span>,
+}
+
+static RECORDER: OnceLock<Recorder> = OnceLock::new();
+static NEXT_THREAD: AtomicU64 = AtomicU64::new(0);
+
+thread_local! {
+    // registered with the recorder the first time the thread records something
+    static LOG: Arc<Mutex<ThreadLog>> = {
+        let thread = NEXT_THREAD.fetch_add(1, Ordering::Relaxed);
+        let name = match (rayon::current_thread_index(), std::thread::current().name()) {
+            (Some(index), _) => format!("rayon {}", index),
+            (None, Some(name)) => name.to_string(),
+            (None, None) => format!("thread {}", thread),
+        };
+        let log = Arc::new(Mutex::new(ThreadLog {
+            thread,
+            name,
+            open: HashMap::new(),
+            compact_at: COMPACT_AT,
+            spans: Vec::new(),
+        }));
+        if let Some(recorder) = RECORDER.get() {
+            recorder.threads.lock().unwrap().push(Arc::clone(&log));
+        }
+        log
+    };
+}
+
+// Start recording, spans before this are thrown away. `keep_spans` for --trace, the report only
+// needs the totals.
+pub fn enable(keep_spans: bool) {
+    RECORDER.get_or_init(|| Recorder {
+        epoch: Instant::now(),
+        keep_spans,
+        threads: Mutex::new(Vec::new()),
+        finished: Mutex::new(Stages::default()),
+        newest: AtomicU64::new(0),
+    });
+}
+
+pub fn is_enabled() -> bool {
+    RECORDER.get().is_some()
+}
+
+// Times `stage` of frame `frame` until it's dropped:
+//
+//     let _span = timing::span("decode", number);
+#[must_use = "the span ends as soon as it's dropped"]
+pub struct SpanGuard {
+    stage: &'static str,
+    frame: u64,
+    start: Option<Instant>,
+}
+
+pub fn span(stage: &'static str, frame: u64) -> SpanGuard {
+    SpanGuard {
+        stage,
+        frame,
+        start: RECORDER.get().map(|_| Instant::now()),
+    }
+}
+
+impl Drop for SpanGuard {
+    fn drop(&mut self) {
+        if let Some(start) = self.start {
+            record(self.stage, self.frame, start);
+        }
+    }
+}
+
+// a span from `start` until now, for when the frame number isn't known until the end (a
+// message that still has to be received)
+pub fn record(stage: &'static str, frame: u64, start: Instant) {
+    let Some(recorder) = RECORDER.get() else {
+        return;
+    };
+    let duration = start.elapsed();
+    recorder.newest.fetch_max(frame, Ordering::Relaxed);
+
+    let compact = LOG.with(|log| {
+        let mut log = log.lock().unwrap();
+        let span = Span {
+            stage,
+            frame,
+            thread: log.thread,
+            start: start.saturating_duration_since(recorder.epoch),
+            duration,
+        };
+        add_span(&mut log.open, &span);
+        if recorder.keep_spans {
+            log.spans.push(span);
+        }
+        log.open.len() >= log.compact_at
+    });
+    if compact {
+        recorder.compact(false);
+    }
+}
+
+impl Recorder {
+    // Move the totals of finished frames (all of them with `everything`) out of the thread logs
+    // and into the per stage stats. Every log is drained in one go, so a frame's strips from
+    // different threads get added up before the frame counts.
+    fn compact(&self, everything: bool) {
+        let newest = self.newest.load(Ordering::Relaxed);
+        let is_finished = |frame: u64| everything || frame + OPEN_FRAMES < newest;
+
+        let mut finished = FrameTotals::new();
+        for log in self.threads.lock().unwrap().iter() {
+            let mut log = log.lock().unwrap();
+            log.open.retain(|&key, total| {
+                if is_finished(key.1) {
+                    add_total(&mut finished, key, *total);
+                }
+                !is_finished(key.1)
+            });
+            // don't come straight back if most of what's open is still in flight
+            log.compact_at = COMPACT_AT.max(2 * log.open.len());
+        }
+
+        let mut stages = self.finished.lock().unwrap();
+        for ((stage, _), total) in finished {
+            stages.add(stage, total);
+        }
+    }
+}
+
+// every span kept so far (only with enable(true)), by start time
+pub fn spans() -> Vec<Span> {
+    let Some(recorder) = RECORDER.get() else {
+        return Vec::new();
+    };
+    let mut spans: Vec<Span> = recorder
+        .threads
+        .lock()
+        .unwrap()
+        .iter()
+        .flat_map(|log| log.lock().unwrap().spans.clone())
+        .collect();
+    spans.sort_by_key(|span| span.start);
+    spans
+}
+
+// the summaries for everything recorded so far, at the end of a run
+pub fn stage_summaries() -> Vec<StageSummary> {
+    match RECORDER.get() {
+        None => Vec::new(),
+        Some(recorder) => {
+            recorder.compact(true);
+            recorder.finished.lock().unwrap().summaries()
+        }
+    }
+}
+
+// one stage's time in one frame so far, and when it first started in that frame
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct FrameTotal {
+    time: Duration,
+    first: Duration,
+}
+
+type FrameTotals = HashMap<(&'static str, u64), FrameTotal>;
+
+fn add_span(totals: &mut FrameTotals, span: &Span) {
+    let total = FrameTotal {
+        time: span.duration,
+        first: span.start,
+    };
+    add_total(totals, (span.stage, span.frame), total);
+}
+
+fn add_total(totals: &mut FrameTotals, key: (&'static str, u64), total: FrameTotal) {
+    totals
+        .entry(key)
+        .and_modify(|sum| {
+            sum.time += total.time;
+            sum.first = sum.first.min(total.first);
+        })
+        .or_insert(total);
+}
+
+// One stage's finished frames in bounded memory: count, mean and max are exact, the percentiles
+// come from every frame up to MAX_SAMPLES of them and from a uniform random sample of
+// MAX_SAMPLES after that (reservoir sampling).
+struct StageStats {
+    // when the stage first ran, to list the stages in order
+    first: Duration,
+    count: usize,
+    total: Duration,
+    max: Duration,
+    samples: Vec<Duration>,
+    random: u64,
+}
+
+impl StageStats {
+    fn new(first: Duration) -> StageStats {
+        StageStats {
+            first,
+            count: 0,
+            total: Duration::ZERO,
+            max: Duration::ZERO,
+            samples: Vec::new(),
+            random: 0x9e37_79b9_7f4a_7c15,
+        }
+    }
+
+    fn add(&mut self, time: Duration) {
+        self.count += 1;
+        self.total += time;
+        self.max = self.max.max(time);
+        if self.samples.len() < MAX_SAMPLES {
+            self.samples.push(time);
+        } else {
+            // the new frame replaces a random one with probability MAX_SAMPLES / count
+            let slot = (self.next_random() % self.count as u64) as usize;
+            if slot < MAX_SAMPLES {
+                self.samples[slot] = time;
+            }
+        }
+    }
+
+    // xorshift, the sampling doesn't need anything better
+    fn next_random(&mut self) -> u64 {
+        self.random ^= self.random << 13;
+        self.random ^= self.random >> 7;
+        self.random ^= self.random << 17;
+        self.random
+    }
+
+    fn summary(&self, stage: &str) -> StageSummary {
+        let mut sorted = self.samples.clone();
+        sorted.sort();
+        StageSummary {
+            stage: stage.to_string(),
+            count: self.count,
+            mean_us: micros(self.total) / self.count as f64,
+            p50_us: micros(percentile(&sorted, 50.0)),
+            p95_us: micros(percentile(&sorted, 95.0)),
+            p99_us: micros(percentile(&sorted, 99.0)),
+            max_us: micros(self.max),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Stages {
+    stats: HashMap<&'static str, StageStats>,
+}
+
+impl Stages {
+    fn add(&mut self, stage: &'static str, total: FrameTotal) {
+        let stats = self
+            .stats
+            .entry(stage)
+            .or_insert_with(|| StageStats::new(total.first));
+        stats.first = stats.first.min(total.first);
+        stats.add(total.time);
+    }
+
+    fn summaries(&self) -> Vec<StageSummary> {
+        let mut stages: Vec<(&&'static str, &StageStats)> = self.stats.iter().collect();
+        stages.sort_by_key(|(stage, stats)| (stats.first, **stage));
+        stages
+            .into_iter()
+            .map(|(stage, stats)| stats.summary(stage))
+            .collect()
+    }
+}
+
+// the numbers for one stage, in microseconds
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct StageSummary {
+    pub stage: String,
+    // frames the stage ran on
+    pub count: usize,
+    pub mean_us: f64,
+    pub p50_us: f64,
+    pub p95_us: f64,
+    pub p99_us: f64,
+    pub max_us: f64,
+}
+
+// One summary per stage, in the order the stages first ran. A stage that runs several times per
+// frame (once per strip) counts as the sum for that frame, so with strips it's CPU time rather
+// than wall time.
+pub fn summarize(spans: &[Span]) -> Vec<StageSummary> {
+    let mut totals = FrameTotals::new();
+    for span in spans {
+        add_span(&mut totals, span);
+    }
+    let mut stages = Stages::default();
+    for ((stage, _), total) in totals {
+        stages.add(stage, total);
+    }
+    stages.summaries()
+}
+
+// nearest rank, `sorted` can't be empty
+fn percentile(sorted: &[Duration], percent: f64) -> Duration {
+    let rank = (percent / 100.0 * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+fn micros(time: Duration) -> f64 {
+    time.as_secs_f64() * 1e6
+}
+
+// what the numbers were measured on
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Machine {
+    pub hostname: String,
+    pub os: String,
+    pub arch: String,
+    pub cpus: usize,
+    // kernel set cpu_dispatch picked
+    pub simd: String,
+}
+
+impl Machine {
+    pub fn detect() -> Machine {
+        Machine {
+            hostname: std::fs::read_to_string("/proc/sys/kernel/hostname")
+                .or_else(|_| std::env::var("HOSTNAME"))
+                .map(|name| name.trim().to_string())
+                .unwrap_or_else(|_| "unknown".to_string()),
+            os: std::env::consts::OS.to_string(),
+            arch: std::env::consts::ARCH.to_string(),
+            cpus: std::thread::available_parallelism().map_or(1, |cpus| cpus.get()),
+            simd: cpu_dispatch::detect().name().to_string(),
+        }
+    }
+}
+
+// how the run was set up, filled in by whoever ran it
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct RunConfig {
+    pub command: String,
+    pub backend: String,
+    pub input: String,
+    pub strips: usize,
+    // stage names in order, empty for the host (the workers run the chain)
+    pub pipeline: Vec<String>,
+    pub pacing: String,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Report {
+    pub config: RunConfig,
+    pub machine: Machine,
+    pub frames: u32,
+    pub dropped: u32,
+    pub elapsed_s: f64,
+    pub fps: f64,
+    pub stages: Vec<StageSummary>,
+}
+
+impl Report {
+    // the report for everything recorded so far
+    pub fn new(config: RunConfig, frames: u32, dropped: u32, elapsed: Duration) -> Report {
+        Report {
+            config,
+            machine: Machine::detect(),
+            frames,
+            dropped,
+            elapsed_s: elapsed.as_secs_f64(),
+            fps: frames as f64 / elapsed.as_secs_f64().max(f64::EPSILON),
+            stages: stage_summaries(),
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).expect("a report always serializes")
+    }
+
+    // One row per stage with the setup repeated on every row, so reports from different runs can
+    // just be concatenated (minus the header) and compared in a spreadsheet
+    pub fn to_csv(&self) -> String {
+        let mut csv = String::from(
+            "stage,count,mean_us,p50_us,p95_us,p99_us,max_us,\
+             command,backend,strips,pacing,frames,dropped,fps,hostname,arch,cpus,simd\n",
+        );
+        for stage in &self.stages {
+            csv += &format!(
+                "{},{},{:.1},{:.1},{:.1},{:.1},{:.1},{},{},{},{},{},{},{:.2},{},{},{},{}\n",
+                stage.stage,
+                stage.count,
+                stage.mean_us,
+                stage.p50_us,
+                stage.p95_us,
+                stage.p99_us,
+                stage.max_us,
+                self.config.command,
+                self.config.backend,
+                self.config.strips,
+                self.config.pacing,
+                self.frames,
+                self.dropped,
+                self.fps,
+                self.machine.hostname,
+                self.machine.arch,
+                self.machine.cpus,
+                self.machine.simd
+            );
+        }
+        csv
+    }
+
+    // CSV if `path` ends in .csv, JSON otherwise
+    pub fn write(&self, path: &str) -> Result<(), String> {
+        let contents = match path.to_ascii_lowercase().ends_with(".csv") {
+            true => self.to_csv(),
+            false => self.to_json(),
+        };
+        std::fs::write(path, contents).map_err(|err| format!("couldn't write '{}': {}", path, err))
+    }
+}
+
+// Everything recorded so far as Chrome Trace Event JSON, which chrome://tracing and
+// ui.perfetto.dev both open. One track per thread, one slice per span with the frame number in
+// its args, and an arrow from each frame's send to its receive on the host.
+pub fn trace_json(process: &str) -> String {
+    let micros = |time: Duration| time.as_secs_f64() * 1e6;
+    let mut events = vec![serde_json::json!({
+        "name": "process_name", "ph": "M", "pid": 1, "tid": 0,
+        "args": { "name": process },
+    })];
+    if let Some(recorder) = RECORDER.get() {
+        for log in recorder.threads.lock().unwrap().iter() {
+            let log = log.lock().unwrap();
+            events.push(serde_json::json!({
+                "name": "thread_name", "ph": "M", "pid": 1, "tid": log.thread,
+                "args": { "name": log.name },
+            }));
+        }
+    }
+
+    for span in spans() {
+        events.push(serde_json::json!({
+            "name": span.stage, "cat": "frame", "ph": "X", "pid": 1, "tid": span.thread,
+            "ts": micros(span.start), "dur": micros(span.duration),
+            "args": { "frame": span.frame },
+        }));
+        // flow events bind to the slice they land in, so start at the end of the send and
+        // finish at the end of the receive (when the frame actually got there)
+        let flow = match span.stage {
+            "send" => Some("s"),
+            "receive" => Some("f"),
+            _ => None,
+        };
+        if let Some(phase) = flow {
+            events.push(serde_json::json!({
+                "name": "frame", "cat": "network", "ph": phase, "bp": "e", "id": span.frame,
+                "pid": 1, "tid": span.thread, "ts": micros(span.start + span.duration),
+            }));
+        }
+    }
+
+    serde_json::json!({ "traceEvents": events, "displayTimeUnit": "ms" }).to_string()
+}
+
+pub fn write_trace(path: &str, process: &str) -> Result<(), String> {
+    std::fs::write(path, trace_json(process))
+        .map_err(|err| format!("couldn't write '{}': {}", path, err))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(millis: u64) -> Duration {
+        Duration::from_millis(millis)
+    }
+
+    fn span(stage: &'static str, frame: u64, start: u64, duration: u64) -> Span {
+        Span {
+            stage,
+            frame,
+            thread: 0,
+            start: ms(start),
+            duration: ms(duration),
+        }
+    }
+
+    #[test]
+    fn percentile_is_nearest_rank() {
+        assert_eq!(percentile(&[ms(7)], 50.0), ms(7));
+        assert_eq!(percentile(&[ms(7)], 99.0), ms(7));
+
+        let two = [ms(1), ms(2)];
+        assert_eq!(percentile(&two, 50.0), ms(1));
+        assert_eq!(percentile(&two, 51.0), ms(2));
+        assert_eq!(percentile(&two, 95.0), ms(2));
+
+        let hundred: Vec<Duration> = (1..=100).map(ms).collect();
+        assert_eq!(percentile(&hundred, 50.0), ms(50));
+        assert_eq!(percentile(&hundred, 95.0), ms(95));
+        assert_eq!(percentile(&hundred, 99.0), ms(99));
+        assert_eq!(percentile(&hundred, 100.0), ms(100));
+        assert_eq!(percentile(&hundred, 0.0), ms(1));
+    }
+
+    #[test]
+    fn strips_are_summed_per_frame() {
+        // two frames of 3 strips each on different threads, plus a stage that runs once
+        let spans = [
+            span("decode", 0, 0, 4),
+            span("sobel", 0, 5, 2),
+            span("sobel", 0, 5, 3),
+            span("sobel", 0, 6, 5),
+            span("decode", 1, 20, 6),
+            span("sobel", 1, 27, 1),
+            span("sobel", 1, 27, 1),
+            span("sobel", 1, 27, 2),
+        ];
+        let summaries = summarize(&spans);
+
+        let stages: Vec<&str> = summaries.iter().map(|s| s.stage.as_str()).collect();
+        assert_eq!(stages, ["decode", "sobel"]);
+
+        let sobel = &summaries[1];
+        assert_eq!(sobel.count, 2);
+        assert_eq!(sobel.mean_us, 7000.0);
+        assert_eq!(sobel.p50_us, 4000.0);
+        assert_eq!(sobel.max_us, 10000.0);
+
+        let decode = &summaries[0];
+        assert_eq!(
+            (decode.count, decode.mean_us, decode.max_us),
+            (2, 5000.0, 6000.0)
+        );
+    }
+
+    #[test]
+    fn stage_stats_stay_bounded() {
+        let mut stats = StageStats::new(Duration::ZERO);
+        let frames = MAX_SAMPLES + 5000;
+        for frame in 0..frames {
+            stats.add(Duration::from_micros(frame as u64 % 1000));
+        }
+        assert_eq!(stats.samples.len(), MAX_SAMPLES);
+
+        // count, mean and max stay exact, the median comes from the sample
+        let summary = stats.summary("sobel");
+        assert_eq!(summary.count, frames);
+        assert!((summary.max_us - 999.0).abs() < 1e-6, "{}", summary.max_us);
+        assert!(
+            (summary.mean_us - 499.5).abs() < 0.01,
+            "{}",
+            summary.mean_us
+        );
+        assert!((summary.p50_us - 500.0).abs() < 20.0, "{}", summary.p50_us);
+    }
+
+    #[test]
+    fn frame_totals_merge_across_threads() {
+        let mut open = FrameTotals::new();
+        add_span(&mut open, &span("sobel", 3, 10, 2));
+        let mut other = FrameTotals::new();
+        add_span(&mut other, &span("sobel", 3, 8, 5));
+
+        for (key, total) in other {
+            add_total(&mut open, key, total);
+        }
+        assert_eq!(
+            open[&("sobel", 3)],
+            FrameTotal {
+                time: ms(7),
+                first: ms(8)
+            }
+        );
+    }
+}
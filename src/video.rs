@@ -0,0 +1,364 @@
+// The read, process, show loop every lab binary used to carry its own copy of. The cpe442
+// subcommands (and the old lab binaries, through them) all run this one.
+use std::time::Instant;
+
+#[cfg(feature = "gui")]
+use opencv::highgui::{self, WINDOW_AUTOSIZE};
+use opencv::{
+    core::{self, Mat, Size, ToInputArray},
+    prelude::*,
+    videoio, Result,
+};
+
+use crate::image::{Image, ImageView};
+use crate::pipeline::{Pipeline, RunStats};
+use crate::playback::{FrameRange, Pacing, Presenter};
+use crate::timing;
+
+// what to do with the frames besides processing them
+#[derive(Clone, Debug, PartialEq)]
+pub struct VideoSettings {
+    // highgui windows (needs the gui feature)
+    pub display: bool,
+    // write the edge frames to this video file
+    pub output: Option<String>,
+    // four character code of the output codec
+    pub codec: String,
+    // output frame rate, None for the source's
+    pub fps: Option<f64>,
+    // which frames of the input to play
+    pub range: FrameRange,
+    // real time playback, and what to do with late frames
+    pub pacing: Pacing,
+}
+
+impl Default for VideoSettings {
+    fn default() -> Self {
+        VideoSettings {
+            display: false,
+            output: None,
+            codec: crate::cli::DEFAULT_CODEC.to_string(),
+            fps: None,
+            range: FrameRange::default(),
+            pacing: Pacing::default(),
+        }
+    }
+}
+
+// open a video file, with an error instead of the old panic if opencv can't read it
+pub fn open(path: &str) -> Result<videoio::VideoCapture> {
+    let video = videoio::VideoCapture::from_file(path, videoio::CAP_ANY)?;
+    if !video.is_opened()? {
+        return Err(opencv::Error::new(
+            core::StsError,
+            format!("Couldn't open video file '{}'", path),
+        ));
+    }
+    Ok(video)
+}
+
+// the source's frame rate, 30 if the container doesn't say
+pub fn source_fps(video: &videoio::VideoCapture) -> Result<f64> {
+    let fps = video.get(videoio::CAP_PROP_FPS)?;
+    Ok(if fps > 0.0 { fps } else { 30.0 })
+}
+
+// gaps up to this many frames get read through rather than seeked over, seeking lands on the
+// nearest keyframe first (and is slow) with a lot of codecs
+const MAX_GRAB: u64 = 16;
+
+// A video file played according to a FrameRange: seeks to --start, skips frames for --every,
+// stops at --end and rewinds for --loop. Reads like a VideoCapture otherwise.
+pub struct VideoSource {
+    video: videoio::VideoCapture,
+    range: FrameRange,
+    // the frame the next read gives, None when that's unknown (after the end of the file)
+    position: Option<u64>,
+    // last frame handed out in this pass
+    last: Option<u64>,
+    passes: u32,
+}
+
+impl VideoSource {
+    pub fn open(path: &str, range: FrameRange) -> Result<VideoSource> {
+        Ok(VideoSource {
+            video: open(path)?,
+            range,
+            position: Some(0),
+            last: None,
+            passes: 0,
+        })
+    }
+
+    // rate the picked frames play at, see FrameRange::output_fps
+    pub fn fps(&self) -> Result<f64> {
+        Ok(self.range.output_fps(source_fps(&self.video)?))
+    }
+
+    // the next frame into `frame`, false once the range (every loop of it) is done
+    pub fn read(&mut self, frame: &mut Mat) -> Result<bool> {
+        loop {
+            if let Some(index) = self.range.next_frame(self.last) {
+                if self.seek(index)? && self.video.read(frame)? && !frame.empty() {
+                    self.position = Some(index + 1);
+                    self.last = Some(index);
+                    return Ok(true);
+                }
+                self.position = None;
+            }
+
+            // end of this pass, from --end or the end of the file
+            self.passes += 1;
+            // a pass that didn't get a single frame (--start past the end) would spin forever
+            if self.last.is_none() || !self.range.another_pass(self.passes) {
+                return Ok(false);
+            }
+            self.last = None;
+        }
+    }
+
+    // get the capture to where the next read gives frame `index`, false if the file ends first
+    fn seek(&mut self, index: u64) -> Result<bool> {
+        match self.position {
+            Some(position) if position == index => Ok(true),
+            Some(position) if position < index && index - position <= MAX_GRAB => {
+                for _ in position..index {
+                    if !self.video.grab()? {
+                        return Ok(false);
+                    }
+                }
+                Ok(true)
+            }
+            _ => {
+                if !self.video.set(videoio::CAP_PROP_POS_FRAMES, index as f64)? {
+                    return Err(opencv::Error::new(
+                        core::StsError,
+                        format!("Couldn't seek to frame {} of the video", index),
+                    ));
+                }
+                Ok(true)
+            }
+        }
+    }
+}
+
+// highgui windows, or nothing at all when running headless
+pub struct Display {
+    windows: Vec<&'static str>,
+}
+
+impl Display {
+    pub fn new(enabled: bool, windows: &[&'static str]) -> Result<Display> {
+        if !enabled {
+            return Ok(Display {
+                windows: Vec::new(),
+            });
+        }
+
+        open_windows(windows)?;
+        Ok(Display {
+            windows: windows.to_vec(),
+        })
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.windows.is_empty()
+    }
+
+    // show `image` in the `index`th window, does nothing when headless
+    pub fn show(&self, index: usize, image: &impl ToInputArray) -> Result<()> {
+        #[cfg(feature = "gui")]
+        {
+            if let Some(window) = self.windows.get(index) {
+                highgui::imshow(window, image)?;
+            }
+        }
+        #[cfg(not(feature = "gui"))]
+        {
+            let _ = (index, image);
+        }
+        Ok(())
+    }
+
+    // give highgui a chance to draw, false once ESC has been pressed
+    pub fn poll(&self) -> Result<bool> {
+        #[cfg(feature = "gui")]
+        {
+            if self.is_enabled() && highgui::wait_key(1)? == 27 {
+                println!("ESC key pressed. Exiting...");
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+}
+
+#[cfg(feature = "gui")]
+fn open_windows(windows: &[&str]) -> Result<()> {
+    for window in windows {
+        highgui::named_window(window, WINDOW_AUTOSIZE)?;
+    }
+    Ok(())
+}
+
+#[cfg(not(feature = "gui"))]
+fn open_windows(_windows: &[&str]) -> Result<()> {
+    Err(opencv::Error::new(
+        core::StsNotImplemented,
+        "built without the gui feature, run with --display off".to_string(),
+    ))
+}
+
+// Edge frames written out to a video file. The writer needs the frame size, so the file only
+// gets opened with the first frame.
+pub struct VideoOutput {
+    path: String,
+    fourcc: i32,
+    fps: f64,
+    writer: Option<videoio::VideoWriter>,
+    frames: usize,
+}
+
+impl VideoOutput {
+    // None if `settings` has no output, `source_fps` is used unless it asks for another rate
+    pub fn from_settings(settings: &VideoSettings, source_fps: f64) -> Result<Option<VideoOutput>> {
+        let Some(path) = &settings.output else {
+            return Ok(None);
+        };
+        let codec: Vec<char> = settings.codec.chars().collect();
+        let [c1, c2, c3, c4] = codec[..] else {
+            return Err(opencv::Error::new(
+                core::StsBadArg,
+                format!("codec '{}' isn't a four character code", settings.codec),
+            ));
+        };
+        Ok(Some(VideoOutput {
+            path: path.clone(),
+            fourcc: videoio::VideoWriter::fourcc(c1, c2, c3, c4)?,
+            fps: settings.fps.unwrap_or(source_fps),
+            writer: None,
+            frames: 0,
+        }))
+    }
+
+    // grey frames go out as BGR, not every codec takes single channel video
+    pub fn write(&mut self, frame: &ImageView<u8>) -> Result<()> {
+        if self.writer.is_none() {
+            let size = Size::new(frame.width() as i32, frame.height() as i32);
+            let writer = videoio::VideoWriter::new(&self.path, self.fourcc, self.fps, size, true)?;
+            if !writer.is_opened()? {
+                return Err(opencv::Error::new(
+                    core::StsError,
+                    format!("Couldn't open '{}' for writing", self.path),
+                ));
+            }
+            self.writer = Some(writer);
+        }
+        let writer = self.writer.as_mut().unwrap();
+        match frame.channels() {
+            1 => writer.write(&grey_to_bgr(frame).as_mat()?)?,
+            _ => writer.write(&frame.as_mat()?)?,
+        }
+        self.frames += 1;
+        Ok(())
+    }
+
+    pub fn frames(&self) -> usize {
+        self.frames
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+}
+
+fn grey_to_bgr(grey: &ImageView<u8>) -> Image<u8> {
+    let mut bgr = Image::new(grey.width(), grey.height(), 3);
+    for (in_row, out_row) in grey.rows().zip(bgr.rows_mut()) {
+        for (&value, pixel) in in_row.iter().zip(out_row.chunks_exact_mut(3)) {
+            pixel.fill(value);
+        }
+    }
+    bgr
+}
+
+// Every frame of `input` (in settings.range) through `pipeline`, shown next to the original
+// (paced with settings.pacing) and/or written out according to `settings`. Stops at the end of
+// the video or on ESC.
+pub fn run(input: &str, pipeline: &Pipeline, settings: &VideoSettings) -> Result<RunStats> {
+    let mut video = VideoSource::open(input, settings.range)?;
+    let fps = video.fps()?;
+    let display = Display::new(settings.display, &["Video Frame", "Video Frame2"])?;
+    let mut output = VideoOutput::from_settings(settings, fps)?;
+    let mut presenter = Presenter::new(settings.pacing, fps);
+
+    let mut stats = RunStats::default();
+    let start_time = Instant::now();
+    // frames read so far, what the timing spans get filed under
+    let mut number = 0;
+    loop {
+        // Read the next frame
+        let mut frame = Mat::default();
+        let decode_start = Instant::now();
+        if !video.read(&mut frame)? {
+            println!("Video processing finished.");
+            break;
+        } else if frame.empty() {
+            println!("Empty frame detected. Video might have ended.");
+            break;
+        }
+        let index = number;
+        number += 1;
+        timing::record("decode", index, decode_start);
+
+        // a frame that's already too late to show only gets skipped completely when there's no
+        // file that needs every frame (present() still counts the drop)
+        if output.is_none() && presenter.is_late() {
+            presenter.present();
+            continue;
+        }
+
+        // Do the actual frame stuff
+        let start_sobel = Instant::now();
+        let edges = pipeline.run_frame(&ImageView::from_mat(&frame)?, index);
+        stats.processing += start_sobel.elapsed();
+        stats.frames += 1;
+
+        if let Some(output) = &mut output {
+            let _span = timing::span("write", index);
+            output.write(&edges.view())?;
+        }
+
+        // the wait for this frame's slot comes after the processing, so that counts too
+        let show = presenter.present();
+        let keep_going = {
+            let _span = timing::span("display", index);
+            if show {
+                display.show(0, &edges.as_mat()?)?;
+                display.show(1, &frame)?;
+            }
+            display.poll()?
+        };
+        if !keep_going {
+            break;
+        }
+
+        // Every 50 frames, calculate and print averages
+        if stats.frames % 50 == 0 {
+            println!(
+                "Averages after {} frames: \nSobel time: {:?}\n frame time {:?}",
+                stats.frames,
+                stats.average(),
+                start_time.elapsed() / stats.frames,
+            );
+        }
+    }
+
+    stats.elapsed = start_time.elapsed();
+    stats.dropped = presenter.dropped();
+    presenter.print_dropped();
+    if let Some(output) = &output {
+        println!("Wrote {} frames to {}", output.frames(), output.path());
+    }
+    Ok(stats)
+}
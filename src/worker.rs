@@ -0,0 +1,112 @@
+// The lab 6 compute node: pull frames from the host, run them through a pipeline and push the
+// results back. No opencv in here, so the Pis can run it without the painful opencv install.
+use zmq::Context;
+
+use crate::image::Image;
+use crate::luma::Luma;
+use crate::mat_packet::{self, MatMessage, PacketError};
+use crate::my_arm_neon;
+use crate::pipeline::Pipeline;
+use crate::pipeline_config::PipelineConfig;
+
+// Connect to the host at `host` and process frames until killed. `local` is what runs unless the
+// host sends its own chain, `luma` converts 16 bit frames to grey for it. The packets carry that
+// chain, so hosts and workers from before it was added can't talk to these ones. A frame that
+// can't be processed goes back as MatMessage::failed so the host moves past it, a packet that
+// doesn't even decode has no frame number to answer for and the host gives up on it after
+// mat_packet::RESULT_TIMEOUT.
+pub fn run_worker(host: &str, local: Pipeline, luma: Luma) {
+    let context = Context::new();
+
+    // Task receiver (PULL)
+    let tx = context
+        .socket(zmq::PULL)
+        .expect("Failed to create task receiver");
+    tx.connect(&format!("tcp://{}:{}", host, mat_packet::TASK_PORT))
+        .expect("Failed to connect to task receiver");
+    tx.set_sndhwm(1) // Set send high water mark (max messages to buffer)
+        .expect("Failed to set receive HWM for result receiver");
+
+    // Result sender (PUSH)
+    let rx = context
+        .socket(zmq::PUSH)
+        .expect("Failed to create result sender");
+    rx.connect(&format!("tcp://{}:{}", host, mat_packet::RESULT_PORT))
+        .expect("Failed to connect to result sender");
+    rx.set_rcvhwm(1) // Set receive high water mark (max messages to buffer)
+        .expect("Failed to set receive HWM for result receiver");
+
+    // the host's chain comes along with every frame, only rebuilt when it
+    // changes
+    let mut pipeline_json: Option<String> = None;
+    let mut remote: Option<Pipeline> = None;
+
+    println!("Compute node is ready for tasks.");
+
+    loop {
+        // Receive task
+        let message = match tx.recv_msg(0) {
+            Ok(message) => message,
+            Err(err) => {
+                eprintln!("Failed to receive a task: {}", err);
+                continue;
+            }
+        };
+        let msg: MatMessage = match bincode::deserialize(&message) {
+            Ok(msg) => msg,
+            Err(err) => {
+                eprintln!(
+                    "Skipping a task that isn't a frame packet ({}), is the host the same version?",
+                    err
+                );
+                continue;
+            }
+        };
+        let frame_num = msg.number;
+
+        if msg.pipeline != pipeline_json {
+            remote = msg.pipeline.as_deref().and_then(|json| {
+                match PipelineConfig::from_json(json).and_then(|config| config.build()) {
+                    Ok(pipeline) => Some(pipeline),
+                    Err(message) => {
+                        eprintln!("{}, using this worker's options instead", message);
+                        None
+                    }
+                }
+            });
+            pipeline_json = msg.pipeline.clone();
+        }
+
+        let mat_message = match process(&msg, &local, remote.as_ref(), luma) {
+            Ok(sobel_frame) => mat_packet::from_image(&sobel_frame.view(), frame_num, 0),
+            Err(err) => {
+                eprintln!("Skipping frame {}: {}", frame_num, err);
+                MatMessage::failed(frame_num)
+            }
+        };
+
+        let serialized: Vec<u8> = bincode::serialize(&mat_message).expect("Serialization failed");
+
+        rx.send(serialized, 0).expect("Failed to send result");
+    }
+}
+
+fn process(
+    msg: &MatMessage,
+    local: &Pipeline,
+    remote: Option<&Pipeline>,
+    luma: Luma,
+) -> Result<Image<u8>, PacketError> {
+    Ok(match (msg.is_16bit(), remote) {
+        (false, remote) => remote.unwrap_or(local).run(&msg.view()?),
+        (true, None) => {
+            let frame = msg.image16()?;
+            local.run(&my_arm_neon::to442_grayscale16(&frame.view(), luma).view())
+        }
+        // the host's chain does its own grayscale, so just scale down to 8 bits
+        (true, Some(remote)) => {
+            let frame = my_arm_neon::to442_narrow16(&msg.image16()?.view());
+            remote.run(&frame.view())
+        }
+    })
+}
@@ -0,0 +1,743 @@
+// YUV4MPEG2 (.y4m) reading and writing, no opencv involved. This is what `ffmpeg -f yuv4mpegpipe`
+// produces and takes, so with `-` as the input and output the processing commands work as a
+// filter between two ffmpegs (and the Pis don't need opencv's codecs to do it):
+//
+//     ffmpeg -i in.mp4 -f yuv4mpegpipe - | cpe442 process - --output - | ffmpeg -i - out.mp4
+//
+// A stream is one text header line, then every frame is a "FRAME" line followed by the Y, U and
+// V planes (just Y for mono). Only 8 bit streams are handled.
+use std::io::{self, BufRead, Read, Write};
+use std::time::Instant;
+
+use crate::image::{Image, ImageView};
+use crate::pipeline::{Pipeline, RunStats};
+use crate::timing;
+
+const MAGIC: &str = "YUV4MPEG2";
+const FRAME: &str = "FRAME";
+// no real header or frame line comes anywhere close, this just stops a garbage stream from
+// being read into memory forever
+const MAX_LINE: usize = 4096;
+// same for the frame buffer a header asks for, 8K 4:4:4 is a bit under 100MB
+const MAX_FRAME_LEN: usize = 1 << 27;
+
+// the C parameter, how the chroma planes are subsampled
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Colorspace {
+    // 4:2:0 with jpeg chroma siting, what the spec assumes without a C
+    #[default]
+    C420Jpeg,
+    C420Paldv,
+    C420Mpeg2,
+    C422,
+    C444,
+    Mono,
+}
+
+impl Colorspace {
+    pub fn name(self) -> &'static str {
+        match self {
+            Colorspace::C420Jpeg => "420jpeg",
+            Colorspace::C420Paldv => "420paldv",
+            Colorspace::C420Mpeg2 => "420mpeg2",
+            Colorspace::C422 => "422",
+            Colorspace::C444 => "444",
+            Colorspace::Mono => "mono",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Colorspace> {
+        match name {
+            "420jpeg" => Some(Colorspace::C420Jpeg),
+            "420paldv" => Some(Colorspace::C420Paldv),
+            // plain 420 is mpeg2 siting, but nothing here cares about siting
+            "420mpeg2" | "420" => Some(Colorspace::C420Mpeg2),
+            "422" => Some(Colorspace::C422),
+            "444" => Some(Colorspace::C444),
+            "mono" => Some(Colorspace::Mono),
+            _ => None,
+        }
+    }
+
+    // size of each chroma plane for a width x height frame, None for mono
+    pub fn chroma_size(self, width: usize, height: usize) -> Option<(usize, usize)> {
+        match self {
+            Colorspace::C420Jpeg | Colorspace::C420Paldv | Colorspace::C420Mpeg2 => {
+                Some((width.div_ceil(2), height.div_ceil(2)))
+            }
+            Colorspace::C422 => Some((width.div_ceil(2), height)),
+            Colorspace::C444 => Some((width, height)),
+            Colorspace::Mono => None,
+        }
+    }
+
+    // bytes in one frame's planes, None if that doesn't fit in a usize
+    pub fn frame_len(self, width: usize, height: usize) -> Option<usize> {
+        let chroma = match self.chroma_size(width, height) {
+            Some((chroma_width, chroma_height)) => {
+                chroma_width.checked_mul(chroma_height)?.checked_mul(2)?
+            }
+            None => 0,
+        };
+        width.checked_mul(height)?.checked_add(chroma)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Y4mHeader {
+    pub width: usize,
+    pub height: usize,
+    // frame rate as a fraction, 30000:1001 for NTSC
+    pub fps: (u32, u32),
+    // pixel aspect ratio, 0:0 for unknown
+    pub aspect: (u32, u32),
+    // p, t, b, m or ? (progressive, top/bottom field first, mixed, unknown), just passed along
+    pub interlace: char,
+    pub colorspace: Colorspace,
+    // XCOLORRANGE=FULL, otherwise Y is 16-235 and U/V 16-240 like most video
+    pub full_range: bool,
+}
+
+impl Default for Y4mHeader {
+    fn default() -> Self {
+        Y4mHeader {
+            width: 0,
+            height: 0,
+            fps: (30, 1),
+            aspect: (0, 0),
+            interlace: '?',
+            colorspace: Colorspace::default(),
+            full_range: false,
+        }
+    }
+}
+
+impl Y4mHeader {
+    // the header line without the newline
+    pub fn parse(line: &str) -> io::Result<Y4mHeader> {
+        let mut tokens = line.split_ascii_whitespace();
+        if tokens.next() != Some(MAGIC) {
+            return Err(invalid(
+                "not a y4m stream (no YUV4MPEG2 header)".to_string(),
+            ));
+        }
+
+        let mut header = Y4mHeader::default();
+        let (mut width, mut height) = (None, None);
+        for token in tokens {
+            let mut chars = token.chars();
+            let key = chars.next();
+            let value = chars.as_str();
+            match key {
+                Some('W') => width = Some(parse_number(token, value)?),
+                Some('H') => height = Some(parse_number(token, value)?),
+                Some('F') => header.fps = parse_ratio(token, value)?,
+                Some('A') => header.aspect = parse_ratio(token, value)?,
+                Some('I') => header.interlace = value.chars().next().unwrap_or('?'),
+                Some('C') => {
+                    header.colorspace = Colorspace::from_name(value).ok_or_else(|| {
+                        invalid(format!(
+                            "unsupported y4m colorspace '{}', only 8 bit 420/422/444/mono work",
+                            value
+                        ))
+                    })?
+                }
+                Some('X') if value.eq_ignore_ascii_case("COLORRANGE=FULL") => {
+                    header.full_range = true
+                }
+                // the spec says to ignore anything it doesn't know about
+                _ => {}
+            }
+        }
+
+        match (width, height) {
+            (Some(width), Some(height)) if width > 0 && height > 0 => {
+                header.width = width;
+                header.height = height;
+                Ok(header)
+            }
+            _ => Err(invalid("y4m header is missing the frame size".to_string())),
+        }
+    }
+
+    pub fn frame_len(&self) -> Option<usize> {
+        self.colorspace.frame_len(self.width, self.height)
+    }
+}
+
+impl std::fmt::Display for Y4mHeader {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} W{} H{} F{}:{} I{} A{}:{} C{}",
+            MAGIC,
+            self.width,
+            self.height,
+            self.fps.0,
+            self.fps.1,
+            self.interlace,
+            self.aspect.0,
+            self.aspect.1,
+            self.colorspace.name()
+        )?;
+        if self.full_range {
+            write!(f, " XCOLORRANGE=FULL")?;
+        }
+        Ok(())
+    }
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn parse_number(token: &str, value: &str) -> io::Result<usize> {
+    value
+        .parse()
+        .map_err(|_| invalid(format!("bad y4m header field '{}'", token)))
+}
+
+fn parse_ratio(token: &str, value: &str) -> io::Result<(u32, u32)> {
+    value
+        .split_once(':')
+        .and_then(|(num, den)| Some((num.parse().ok()?, den.parse().ok()?)))
+        .ok_or_else(|| invalid(format!("bad y4m header field '{}'", token)))
+}
+
+// One frame's planes, chroma at whatever size the colorspace says
+#[derive(Clone, Debug, PartialEq)]
+pub struct YuvFrame {
+    pub y: Image<u8>,
+    // None for mono
+    pub uv: Option<(Image<u8>, Image<u8>)>,
+}
+
+impl YuvFrame {
+    // planes straight out of a y4m frame, `data` has to be header.frame_len() long
+    pub fn from_planes(header: &Y4mHeader, data: &[u8]) -> YuvFrame {
+        assert_eq!(Some(data.len()), header.frame_len(), "wrong y4m frame size");
+        let (width, height) = (header.width, header.height);
+        let (luma, chroma) = data.split_at(width * height);
+        let y = Image::from_vec(width, height, 1, luma.to_vec());
+        let uv =
+            header
+                .colorspace
+                .chroma_size(width, height)
+                .map(|(chroma_width, chroma_height)| {
+                    let (u, v) = chroma.split_at(chroma_width * chroma_height);
+                    (
+                        Image::from_vec(chroma_width, chroma_height, 1, u.to_vec()),
+                        Image::from_vec(chroma_width, chroma_height, 1, v.to_vec()),
+                    )
+                });
+        YuvFrame { y, uv }
+    }
+
+    // Full range BT.601 from a grey or BGR(A) frame, chroma averaged down to `colorspace`. Grey
+    // frames just become Y with neutral chroma.
+    pub fn from_image(frame: &ImageView<u8>, colorspace: Colorspace) -> YuvFrame {
+        let (width, height) = (frame.width(), frame.height());
+        let chroma_size = colorspace.chroma_size(width, height);
+        if frame.channels() == 1 {
+            let neutral = |(chroma_width, chroma_height)| {
+                Image::from_vec(
+                    chroma_width,
+                    chroma_height,
+                    1,
+                    vec![128; chroma_width * chroma_height],
+                )
+            };
+            return YuvFrame {
+                y: frame.to_image(),
+                uv: chroma_size.map(|size| (neutral(size), neutral(size))),
+            };
+        }
+
+        // full resolution U and V first, then averaged over each chroma sample's block
+        let mut y = Image::new(width, height, 1);
+        let mut u_full = Image::new(width, height, 1);
+        let mut v_full = Image::new(width, height, 1);
+        for row in 0..height {
+            let pixels = frame.row(row).chunks_exact(frame.channels());
+            for (x, pixel) in pixels.enumerate() {
+                let (luma, cb, cr) = bgr_to_yuv(pixel);
+                y.row_mut(row)[x] = luma;
+                u_full.row_mut(row)[x] = cb;
+                v_full.row_mut(row)[x] = cr;
+            }
+        }
+        let uv = chroma_size.map(|(chroma_width, chroma_height)| {
+            let mut u = Image::new(chroma_width, chroma_height, 1);
+            let mut v = Image::new(chroma_width, chroma_height, 1);
+            downsample(&u_full.view(), &mut u);
+            downsample(&v_full.view(), &mut v);
+            (u, v)
+        });
+        YuvFrame { y, uv }
+    }
+
+    // BGR for the pipeline, BT.601 in whichever range the stream says
+    pub fn to_bgr(&self, full_range: bool) -> Image<u8> {
+        let (width, height) = (self.y.width(), self.y.height());
+        let mut bgr = Image::new(width, height, 3);
+        for row in 0..height {
+            for (x, pixel) in bgr.row_mut(row).chunks_exact_mut(3).enumerate() {
+                let luma = self.y.row(row)[x];
+                let (cb, cr) = match &self.uv {
+                    None => (128, 128),
+                    Some((u, v)) => {
+                        // nearest chroma sample, which is what the subsampling ratio works out to
+                        let cx = x * u.width() / width;
+                        let cy = row * u.height() / height;
+                        (u.row(cy)[cx], v.row(cy)[cx])
+                    }
+                };
+                pixel.copy_from_slice(&yuv_to_bgr(luma, cb, cr, full_range));
+            }
+        }
+        bgr
+    }
+
+    fn write_to(&self, out: &mut impl Write) -> io::Result<()> {
+        write_plane(out, &self.y)?;
+        if let Some((u, v)) = &self.uv {
+            write_plane(out, u)?;
+            write_plane(out, v)?;
+        }
+        Ok(())
+    }
+}
+
+fn write_plane(out: &mut impl Write, plane: &Image<u8>) -> io::Result<()> {
+    for row in plane.view().rows() {
+        out.write_all(row)?;
+    }
+    Ok(())
+}
+
+// average of the full resolution block each output sample covers
+fn downsample(full: &ImageView<u8>, out: &mut Image<u8>) {
+    let step_x = full.width().div_ceil(out.width());
+    let step_y = full.height().div_ceil(out.height());
+    for cy in 0..out.height() {
+        for cx in 0..out.width() {
+            let (mut sum, mut count) = (0u32, 0u32);
+            for row in (cy * step_y..(cy + 1) * step_y).take_while(|&row| row < full.height()) {
+                for x in (cx * step_x..(cx + 1) * step_x).take_while(|&x| x < full.width()) {
+                    sum += full.row(row)[x] as u32;
+                    count += 1;
+                }
+            }
+            out.row_mut(cy)[cx] = ((sum + count / 2) / count) as u8;
+        }
+    }
+}
+
+// full range BT.601, 8 bit fixed point
+fn bgr_to_yuv(pixel: &[u8]) -> (u8, u8, u8) {
+    let (b, g, r) = (pixel[0] as i32, pixel[1] as i32, pixel[2] as i32);
+    let y = (77 * r + 150 * g + 29 * b + 128) >> 8;
+    let u = ((-43 * r - 85 * g + 128 * b + 128) >> 8) + 128;
+    let v = ((128 * r - 107 * g - 21 * b + 128) >> 8) + 128;
+    (clamp(y), clamp(u), clamp(v))
+}
+
+fn yuv_to_bgr(y: u8, u: u8, v: u8, full_range: bool) -> [u8; 3] {
+    let (d, e) = (u as i32 - 128, v as i32 - 128);
+    let (r, g, b) = if full_range {
+        let c = (y as i32) << 8;
+        (c + 359 * e, c - 88 * d - 183 * e, c + 454 * d)
+    } else {
+        let c = 298 * (y as i32 - 16);
+        (c + 409 * e, c - 100 * d - 208 * e, c + 516 * d)
+    };
+    [
+        clamp((b + 128) >> 8),
+        clamp((g + 128) >> 8),
+        clamp((r + 128) >> 8),
+    ]
+}
+
+fn clamp(value: i32) -> u8 {
+    value.clamp(0, 255) as u8
+}
+
+// Frames out of a y4m stream, the header gets read straight away
+pub struct Y4mReader<R> {
+    reader: R,
+    header: Y4mHeader,
+    buffer: Vec<u8>,
+}
+
+impl<R: BufRead> Y4mReader<R> {
+    pub fn new(mut reader: R) -> io::Result<Y4mReader<R>> {
+        let line =
+            read_line(&mut reader)?.ok_or_else(|| invalid("empty y4m stream".to_string()))?;
+        let header = Y4mHeader::parse(&line)?;
+        let frame_len = header
+            .frame_len()
+            .filter(|&len| len <= MAX_FRAME_LEN)
+            .ok_or_else(|| {
+                invalid(format!(
+                    "y4m frame size {}x{} is too big",
+                    header.width, header.height
+                ))
+            })?;
+        Ok(Y4mReader {
+            reader,
+            buffer: vec![0; frame_len],
+            header,
+        })
+    }
+
+    pub fn header(&self) -> &Y4mHeader {
+        &self.header
+    }
+
+    // the next frame, None at the end of the stream
+    pub fn read_frame(&mut self) -> io::Result<Option<YuvFrame>> {
+        let Some(line) = read_line(&mut self.reader)? else {
+            return Ok(None);
+        };
+        // FRAME can have parameters of its own, nothing uses them
+        if line.split_ascii_whitespace().next() != Some(FRAME) {
+            return Err(invalid(format!("expected a y4m FRAME, got '{}'", line)));
+        }
+        self.reader.read_exact(&mut self.buffer)?;
+        Ok(Some(YuvFrame::from_planes(&self.header, &self.buffer)))
+    }
+}
+
+// one line without the newline, None at a clean end of stream
+fn read_line(reader: &mut impl BufRead) -> io::Result<Option<String>> {
+    let mut line = Vec::new();
+    Read::take(reader, MAX_LINE as u64).read_until(b'\n', &mut line)?;
+    if line.is_empty() {
+        return Ok(None);
+    }
+    if line.pop() != Some(b'\n') {
+        return Err(invalid("y4m header or frame line is cut off".to_string()));
+    }
+    String::from_utf8(line)
+        .map(Some)
+        .map_err(|_| invalid("y4m header isn't text".to_string()))
+}
+
+// Frames into a y4m stream with `header`'s size, rate and colorspace
+pub struct Y4mWriter<W> {
+    writer: W,
+    header: Y4mHeader,
+    wrote_header: bool,
+}
+
+impl<W: Write> Y4mWriter<W> {
+    pub fn new(writer: W, header: Y4mHeader) -> Y4mWriter<W> {
+        Y4mWriter {
+            writer,
+            header,
+            wrote_header: false,
+        }
+    }
+
+    pub fn header(&self) -> &Y4mHeader {
+        &self.header
+    }
+
+    pub fn write_frame(&mut self, frame: &YuvFrame) -> io::Result<()> {
+        if (frame.y.width(), frame.y.height()) != (self.header.width, self.header.height)
+            || frame.uv.as_ref().map(|(u, _)| (u.width(), u.height()))
+                != self
+                    .header
+                    .colorspace
+                    .chroma_size(self.header.width, self.header.height)
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "frame doesn't match the y4m header",
+            ));
+        }
+        if !self.wrote_header {
+            writeln!(self.writer, "{}", self.header)?;
+            self.wrote_header = true;
+        }
+        writeln!(self.writer, "{}", FRAME)?;
+        frame.write_to(&mut self.writer)
+    }
+
+    // grey or BGR, converted to the header's colorspace
+    pub fn write_image(&mut self, frame: &ImageView<u8>) -> io::Result<()> {
+        self.write_frame(&YuvFrame::from_image(frame, self.header.colorspace))
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+// Every frame of a y4m stream through `pipeline`, written to `output` if there is one. The
+// output keeps the input's size, rate and chroma layout but is always full range, since the
+// edge values go 0-255. Progress goes to stderr, stdout may well be the video.
+pub fn run<R: BufRead, W: Write>(
+    input: R,
+    output: Option<W>,
+    pipeline: &Pipeline,
+) -> io::Result<RunStats> {
+    let mut reader = Y4mReader::new(input)?;
+    let header = reader.header().clone();
+    let mut writer = output.map(|output| {
+        Y4mWriter::new(
+            output,
+            Y4mHeader {
+                full_range: true,
+                ..header.clone()
+            },
+        )
+    });
+
+    let mut writer_closed = false;
+    let mut stats = RunStats::default();
+    let start_time = Instant::now();
+    loop {
+        let decode_start = Instant::now();
+        let Some(frame) = reader.read_frame()? else {
+            break;
+        };
+        let bgr = frame.to_bgr(header.full_range);
+        timing::record("decode", stats.frames as u64, decode_start);
+
+        let start_sobel = Instant::now();
+        let edges = pipeline.run_frame(&bgr.view(), stats.frames as u64);
+        stats.processing += start_sobel.elapsed();
+        stats.frames += 1;
+
+        // whatever reads stdout quit (ffmpeg -t, head, ...), nothing left to do
+        if let Some(writer) = &mut writer {
+            let _span = timing::span("write", stats.frames as u64 - 1);
+            match writer.write_image(&edges.view()) {
+                Err(err) if err.kind() == io::ErrorKind::BrokenPipe => {
+                    eprintln!("Output closed, stopping.");
+                    writer_closed = true;
+                    break;
+                }
+                result => result?,
+            }
+        }
+
+        if stats.frames % 50 == 0 {
+            eprintln!(
+                "Averages after {} frames: \nSobel time: {:?}\n frame time {:?}",
+                stats.frames,
+                stats.average(),
+                start_time.elapsed() / stats.frames,
+            );
+        }
+    }
+    if let (Some(writer), false) = (&mut writer, writer_closed) {
+        writer.flush()?;
+    }
+
+    stats.elapsed = start_time.elapsed();
+    eprintln!("Video processing finished, {} frames.", stats.frames);
+    Ok(stats)
+}
+
+// `-` (stdin/stdout) or a .y4m file
+pub fn is_y4m_path(path: &str) -> bool {
+    path == "-"
+        || std::path::Path::new(path)
+            .extension()
+            .is_some_and(|extension| extension.eq_ignore_ascii_case("y4m"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn header_variants() {
+        let header =
+            Y4mHeader::parse("YUV4MPEG2 W640 H360 F30000:1001 Ip A1:1 C420jpeg XYSCSS=420JPEG")
+                .unwrap();
+        assert_eq!(
+            header,
+            Y4mHeader {
+                width: 640,
+                height: 360,
+                fps: (30000, 1001),
+                aspect: (1, 1),
+                interlace: 'p',
+                colorspace: Colorspace::C420Jpeg,
+                full_range: false,
+            }
+        );
+
+        let colorspaces = [
+            ("C420jpeg", Colorspace::C420Jpeg),
+            ("C420mpeg2", Colorspace::C420Mpeg2),
+            ("C420", Colorspace::C420Mpeg2),
+            ("C420paldv", Colorspace::C420Paldv),
+            ("C422", Colorspace::C422),
+            ("C444", Colorspace::C444),
+            ("Cmono", Colorspace::Mono),
+        ];
+        for (token, colorspace) in colorspaces {
+            let line = format!("YUV4MPEG2 W4 H2 F25:1 {}", token);
+            assert_eq!(Y4mHeader::parse(&line).unwrap().colorspace, colorspace);
+        }
+
+        // no C means 420jpeg, interlace and aspect get passed through
+        let header = Y4mHeader::parse("YUV4MPEG2 H2 W4 It A128:117 XCOLORRANGE=FULL").unwrap();
+        assert_eq!(header.colorspace, Colorspace::C420Jpeg);
+        assert_eq!((header.interlace, header.aspect), ('t', (128, 117)));
+        assert!(header.full_range);
+        assert_eq!(header.fps, (30, 1));
+    }
+
+    #[test]
+    fn bad_headers_are_errors() {
+        for line in [
+            "",
+            "YUV4MPEG W4 H2",
+            "YUV4MPEG2 W4",
+            "YUV4MPEG2 W0 H2",
+            "YUV4MPEG2 Wfour H2",
+            "YUV4MPEG2 W4 H2 F30",
+            "YUV4MPEG2 W4 H2 C420p10",
+        ] {
+            assert!(Y4mHeader::parse(line).is_err(), "{}", line);
+        }
+    }
+
+    #[test]
+    fn header_round_trips() {
+        let header = Y4mHeader {
+            width: 7,
+            height: 5,
+            fps: (24000, 1001),
+            aspect: (4, 3),
+            interlace: 'b',
+            colorspace: Colorspace::C422,
+            full_range: true,
+        };
+        assert_eq!(Y4mHeader::parse(&header.to_string()).unwrap(), header);
+    }
+
+    #[test]
+    fn odd_sizes_round_chroma_up() {
+        assert_eq!(Colorspace::C420Jpeg.chroma_size(7, 5), Some((4, 3)));
+        assert_eq!(Colorspace::C422.chroma_size(7, 5), Some((4, 5)));
+        assert_eq!(Colorspace::C444.chroma_size(7, 5), Some((7, 5)));
+        assert_eq!(Colorspace::Mono.chroma_size(7, 5), None);
+        assert_eq!(Colorspace::C420Mpeg2.frame_len(7, 5), Some(35 + 2 * 12));
+        assert_eq!(Colorspace::Mono.frame_len(7, 5), Some(35));
+        assert_eq!(Colorspace::C444.frame_len(usize::MAX / 2, 3), None);
+    }
+
+    fn pattern(header: &Y4mHeader) -> YuvFrame {
+        let data: Vec<u8> = (0..header.frame_len().unwrap())
+            .map(|i| (i * 31 % 251) as u8)
+            .collect();
+        YuvFrame::from_planes(header, &data)
+    }
+
+    #[test]
+    fn frames_round_trip() {
+        for colorspace in [
+            Colorspace::C420Jpeg,
+            Colorspace::C420Mpeg2,
+            Colorspace::C422,
+            Colorspace::C444,
+            Colorspace::Mono,
+        ] {
+            for (width, height) in [(8, 6), (7, 5), (1, 1)] {
+                let header = Y4mHeader {
+                    width,
+                    height,
+                    colorspace,
+                    ..Default::default()
+                };
+                let frames = [pattern(&header), pattern(&header)];
+
+                let mut stream = Vec::new();
+                let mut writer = Y4mWriter::new(&mut stream, header.clone());
+                for frame in &frames {
+                    writer.write_frame(frame).unwrap();
+                }
+                writer.flush().unwrap();
+
+                let mut reader = Y4mReader::new(&stream[..]).unwrap();
+                assert_eq!(reader.header(), &header);
+                for frame in &frames {
+                    assert_eq!(reader.read_frame().unwrap().as_ref(), Some(frame));
+                }
+                assert_eq!(reader.read_frame().unwrap(), None);
+            }
+        }
+    }
+
+    #[test]
+    fn bgr_survives_the_trip_through_yuv() {
+        // a flat colour, so chroma subsampling doesn't lose anything
+        let bgr = Image::from_vec(5, 3, 3, [40u8, 160, 220].repeat(15));
+        let frame = YuvFrame::from_image(&bgr.view(), Colorspace::C420Jpeg);
+        let back = frame.to_bgr(true);
+        for (a, b) in back.data().iter().zip(bgr.data()) {
+            assert!(a.abs_diff(*b) <= 1, "{} vs {}", a, b);
+        }
+    }
+
+    #[test]
+    fn truncated_frames_are_errors() {
+        let header = Y4mHeader {
+            width: 4,
+            height: 4,
+            ..Default::default()
+        };
+        let mut stream = Vec::new();
+        Y4mWriter::new(&mut stream, header.clone())
+            .write_frame(&pattern(&header))
+            .unwrap();
+
+        // cut off in the middle of the planes, and in the middle of the FRAME line
+        let header_len = header.to_string().len() + 1;
+        for cut in [stream.len() - 1, header_len + 10, header_len + 3] {
+            let mut reader = Y4mReader::new(&stream[..cut]).unwrap();
+            assert!(reader.read_frame().is_err(), "cut at {}", cut);
+        }
+
+        // and something that isn't a frame at all
+        let mut garbage = header.to_string().into_bytes();
+        garbage.extend(b"\nFRAMES\n");
+        assert!(Y4mReader::new(&garbage[..]).unwrap().read_frame().is_err());
+    }
+
+    #[test]
+    fn huge_frame_sizes_are_errors() {
+        // overflows usize, and fits but would be a multi-gigabyte buffer
+        for line in [
+            format!("YUV4MPEG2 W{} H{} C444", usize::MAX, usize::MAX),
+            "YUV4MPEG2 W100000 H100000 C420jpeg".to_string(),
+        ] {
+            let stream = format!("{}\nFRAME\n", line);
+            let err = Y4mReader::new(stream.as_bytes()).err().expect(&line);
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData, "{}", line);
+        }
+        // the biggest sizes anyone uses still work
+        assert!(Y4mReader::new(&b"YUV4MPEG2 W7680 H4320 C444\n"[..]).is_ok());
+    }
+
+    #[test]
+    fn frame_size_must_match_the_header() {
+        let header = Y4mHeader {
+            width: 4,
+            height: 4,
+            ..Default::default()
+        };
+        let other = Y4mHeader {
+            width: 6,
+            ..header.clone()
+        };
+        let mut writer = Y4mWriter::new(Vec::new(), header);
+        assert!(writer.write_frame(&pattern(&other)).is_err());
+    }
+}